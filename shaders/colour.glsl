@@ -83,11 +83,67 @@ vec3 dither(uvec2 pix_coord, float colour_depth, vec3 colour) {
 
 // required descriptors:
 /*
-uniform sampler samp;
-uniform texture2D textures[];
+uniform sampler2D textures[];
 */
-vec3 sample_texture(Material mat, vec2 uv) {
-    return texture(sampler2D(textures[mat.tex_id], tex_samp),
-                   uv * mat.tex_scale)
-        .xyz;
+// A texture at uv, with footprint the width of the area the ray sees in uv
+// space picking the mip level. There are no derivatives in compute shaders, so
+// the level is always given
+vec3 sample_footprint(uint tex_id, vec2 uv, float footprint) {
+    vec2 size = vec2(textureSize(textures[tex_id], 0));
+    float lod = log2(max(footprint * max(size.x, size.y), 1e-8));
+    return textureLod(textures[tex_id], uv, lod).xyz;
+}
+
+// One of the material's textures at uv, repeated by its tex_scale
+vec3 sample_map(uint tex_id, Material mat, vec2 uv, float footprint) {
+    return sample_footprint(tex_id, uv * mat.tex_scale,
+                            footprint * max(mat.tex_scale.x, mat.tex_scale.y));
+}
+
+// The albedo of the material at uv, its texture tinted by the base colour
+vec3 sample_texture(Material mat, vec2 uv, float footprint) {
+    return sample_map(mat.tex_id, mat, uv, footprint) * mat.base_colour;
+}
+
+// The material with its roughness/metallic and emissive maps applied at uv
+Material sample_material(Material mat, vec2 uv, float footprint) {
+    if (mat.metallic_roughness_tex_id != NO_TEXTURE) {
+        vec3 metallic_roughness =
+            sample_map(mat.metallic_roughness_tex_id, mat, uv, footprint);
+        mat.roughness *= metallic_roughness.g;
+        mat.metallic *= metallic_roughness.b;
+    }
+    if (mat.emissive_tex_id != NO_TEXTURE) {
+        mat.emissive *= sample_map(mat.emissive_tex_id, mat, uv, footprint);
+    }
+    return mat;
+}
+
+// How much of the light bounced from other surfaces reaches uv
+float sample_occlusion(Material mat, vec2 uv, float footprint) {
+    if (mat.occlusion_tex_id == NO_TEXTURE) {
+        return 1.0;
+    }
+    return sample_map(mat.occlusion_tex_id, mat, uv, footprint).r;
+}
+
+// The normal bent by the material's normal map. tangent points along +u, and
+// its w is the sign of the bitangent
+vec3 sample_normal_map(Material mat, vec2 uv, float footprint, vec3 normal,
+                       vec4 tangent) {
+    if (mat.normal_tex_id == NO_TEXTURE) {
+        return normal;
+    }
+
+    // tangents can be zero where uvs meet at a point, like a sphere's poles
+    vec3 t = tangent.xyz - (normal * dot(normal, tangent.xyz));
+    if (dot(t, t) < 1e-8) {
+        return normal;
+    }
+    t = normalize(t);
+    vec3 b = cross(normal, t) * tangent.w;
+
+    vec3 n = sample_map(mat.normal_tex_id, mat, uv, footprint) * 2.0 - 1.0;
+    // +y is up the image, and v goes down it
+    return normalize((t * n.x) - (b * n.y) + (normal * n.z));
 }
@@ -4,5 +4,35 @@
 #define DBL_MIN 2.2250738585072014e-308
 #define EPSILON 0.001
 #define UINT_MAX 4294967295
+#define NO_MATERIAL UINT_MAX
+#define NO_TEXTURE UINT_MAX
 #define TAU 6.283185307
 #define PI 3.1415926535
+
+// Object types
+#define OBJ_SPHERE 0
+#define OBJ_PLANE 1
+#define OBJ_MESH 2
+// Fills the top level BVH of an empty scene, and is never hit
+#define OBJ_NONE 3
+
+// Light types
+#define LIGHT_POINT 0
+#define LIGHT_DIRECTIONAL 1
+#define LIGHT_SPOT 2
+#define LIGHT_RECT 3
+#define LIGHT_SPHERE 4
+
+// Sky types
+#define SKY_COLOUR 0
+#define SKY_EQUIRECT 1
+#define SKY_CUBEMAP 2
+#define SKY_PROCEDURAL 3
+
+// The size of the grid skies are importance sampled on, the same as sky.rs
+#define SKY_CDF_WIDTH 128
+#define SKY_CDF_HEIGHT 64
+
+// Render modes
+#define RENDER_LIGHTMAP 0
+#define RENDER_PATH_TRACED 1
@@ -1,17 +1,71 @@
 // readonly buffer SphereData { Sphere data[]; } spheres;
 // readonly buffer PlaneData { Plane data[]; } planes;
-// uniform texture2D textures[];
+// readonly buffer MaterialData { Material data[]; } materials;
+// uniform sampler2D textures[];
+
+// Moves a ray into an object's space. The direction isn't renormalised, so
+// distances along the ray are the same in both spaces
+Ray to_object_space(Ray ray, mat4 inv_model) {
+    return Ray((inv_model * vec4(ray.origin, 1.0)).xyz,
+               mat3(inv_model) * ray.direction);
+}
+
+// Normals are transformed by the inverse transpose of the model matrix
+vec3 normal_to_world(vec3 normal, mat4 inv_model) {
+    return normalize(transpose(mat3(inv_model)) * normal);
+}
+
+// The instance's material if it has one, otherwise the triangle's from the
+// mesh file, or the default material if neither do
+Material triangle_material(MeshInstance m, uint triangle_idx) {
+    uint mat_id = m.mat_id;
+    if (mat_id == NO_MATERIAL) mat_id = triangles[triangle_idx].mat_id;
+    return materials[mat_id == NO_MATERIAL ? 0 : mat_id];
+}
+
+// Whether the material's texture is transparent enough at uv for rays to go
+// through. Always tested on the full size texture, so cutouts don't shrink or
+// grow with distance
+bool is_cut_out(Material mat, vec2 uv) {
+    return mat.alpha_cutoff > 0.0 &&
+           textureLod(textures[mat.tex_id], uv * mat.tex_scale, 0.0).a <
+               mat.alpha_cutoff;
+}
+
+// Whether a triangle is cut out by its material where it's hit, at
+// barycentric coordinates bary
+bool is_cut_out_triangle(MeshInstance m, uint triangle_idx, vec2 bary) {
+    Material mat = triangle_material(m, triangle_idx);
+    if (mat.alpha_cutoff <= 0.0) return false;
+
+    Triangle triangle = triangles[triangle_idx];
+    vec2 uv =
+        ((1.0 - bary.x - bary.y) *
+         vertices[m.start_vertex_idx + triangle.v1_idx].uv) +
+        (bary.x * vertices[m.start_vertex_idx + triangle.v2_idx].uv) +
+        (bary.y * vertices[m.start_vertex_idx + triangle.v3_idx].uv);
+    return is_cut_out(mat, uv);
+}
+
+// The uv of a point on the plane, 0..1 across its width and height
+vec2 plane_uv(Plane plane, vec3 position) {
+    vec3 bitangent = cross(plane.normal, plane.tangent);
+    vec3 delta = position - plane.position;
+    return vec2(0.5) + vec2(dot(plane.tangent, delta) / plane.width,
+                            dot(bitangent, delta) / plane.height);
+}
 
-float ray_sphere_intersect(Ray ray, Sphere sphere) {
+// The ray must be in the sphere's object space
+float ray_sphere_intersect(Ray ray, float radius) {
     // TODO: reduce the amount of "if" statements in here
     //  vector from sphere center to ray origin.
-    vec3 c_to_o = ray.origin - sphere.position;
+    vec3 c_to_o = ray.origin;
 
     // quadratic formula constants for line-sphere intersection
     // TODO: precompute r^2?
     float a = dot(ray.direction, ray.direction);
     float b = 2.0 * dot(ray.direction, c_to_o);
-    float c = dot(c_to_o, c_to_o) - (sphere.radius * sphere.radius);
+    float c = dot(c_to_o, c_to_o) - (radius * radius);
 
     float d0 = FLT_MAX, d1 = FLT_MAX;
 
@@ -93,6 +147,17 @@ float ray_plane_intersect(Ray ray, Plane plane) {
     }
 }
 
+// Like ray_plane_intersect, but goes through the parts its material cuts out
+float intersect_plane(Ray ray, Plane plane) {
+    float dist = ray_plane_intersect(ray, plane);
+    if (dist < FLT_MAX &&
+        is_cut_out(materials[plane.mat_id],
+                   plane_uv(plane, ray.origin + (dist * ray.direction)))) {
+        return FLT_MAX;
+    }
+    return dist;
+}
+
 float ray_triangle_intersect(Ray ray, vec3 p1, vec3 p2, vec3 p3, inout float u,
                              inout float v) {
     vec3 edge1 = p2 - p1;
@@ -123,76 +188,175 @@ float ray_triangle_intersect(Ray ray, vec3 p1, vec3 p2, vec3 p3, inout float u,
         return t;
 }
 
-HitInfo cast_ray(Ray ray) {
-    // Expects SphereData, PlaneData in scope
-    float least_dist = FLT_MAX;
-    uint hit_idx = UINT_MAX;
-    uint hit_obj = UINT_MAX;
-    uint triangle_idx = UINT_MAX;
-    vec2 triangle_uv = vec2(FLT_MAX);
+// Distance along the ray to where it enters the box, or FLT_MAX if it misses
+float ray_aabb_intersect(vec3 origin, vec3 inv_dir, vec3 aabb_min,
+                         vec3 aabb_max) {
+    vec3 t0 = (aabb_min - origin) * inv_dir;
+    vec3 t1 = (aabb_max - origin) * inv_dir;
+    vec3 t_near = min(t0, t1);
+    vec3 t_far = max(t0, t1);
 
-    for (int i = 0; i < spheres.length(); i++) {
-        float obj_dist = ray_sphere_intersect(ray, spheres[i]);
-        if (obj_dist < least_dist) {
-            least_dist = obj_dist;
-            hit_idx = i;
-            hit_obj = 0;  // TODO: replace with var
-        }
+    float near = max(max(t_near.x, t_near.y), t_near.z);
+    float far = min(min(t_far.x, t_far.y), t_far.z);
+
+    if (far >= near && far >= 0.0) {
+        return max(near, 0.0);
+    } else {
+        return FLT_MAX;
     }
+}
+
+// Enough for the deepest tree bvh.rs builds, which is the same as STACK_SIZE
+// there
+const uint BVH_STACK_SIZE = 32;
+
+// Gets the children of an inner node in the order to visit them, nearest
+// first, along with their distances along the ray
+void order_children(BvhNode left, BvhNode right, uint left_idx, vec3 origin,
+                    vec3 inv_dir, out uvec2 order, out vec2 dists) {
+    float left_dist =
+        ray_aabb_intersect(origin, inv_dir, left.aabb_min, left.aabb_max);
+    float right_dist =
+        ray_aabb_intersect(origin, inv_dir, right.aabb_min, right.aabb_max);
+
+    if (left_dist <= right_dist) {
+        order = uvec2(left_idx, left_idx + 1);
+        dists = vec2(left_dist, right_dist);
+    } else {
+        order = uvec2(left_idx + 1, left_idx);
+        dists = vec2(right_dist, left_dist);
+    }
+}
 
-    for (int i = 0; i < planes.length(); i++) {
-        float obj_dist = ray_plane_intersect(ray, planes[i]);
-        if (obj_dist < least_dist) {
-            least_dist = obj_dist;
-            hit_idx = i;
-            hit_obj = 1;  // TODO: replace with var
+// Walks the mesh's BVH to find the closest triangle hit nearer than
+// least_dist. Returns its index (UINT_MAX if none), and updates least_dist
+// and triangle_uv
+uint intersect_mesh(Ray ray, MeshInstance m, inout float least_dist,
+                    inout vec2 triangle_uv) {
+    // the BVH is in object space, so move the ray there instead of moving
+    // every vertex
+    Ray local_ray = to_object_space(ray, m.inv_model);
+    vec3 inv_dir = 1.0 / local_ray.direction;
+    uint hit_triangle = UINT_MAX;
+
+    uint stack[BVH_STACK_SIZE];
+    uint stack_ptr = 0;
+    stack[stack_ptr++] = 0;
+
+    while (stack_ptr > 0) {
+        BvhNode node = bvh_nodes[m.start_node_idx + stack[--stack_ptr]];
+
+        if (ray_aabb_intersect(local_ray.origin, inv_dir, node.aabb_min,
+                               node.aabb_max) >= least_dist)
+            continue;
+
+        if (node.count > 0) {
+            for (uint t = m.start_triangle_idx + node.left_first;
+                 t < m.start_triangle_idx + node.left_first + node.count;
+                 t++) {
+                Triangle triangle = triangles[t];
+
+                vec3 p1 = vertices[m.start_vertex_idx + triangle.v1_idx].position;
+                vec3 p2 = vertices[m.start_vertex_idx + triangle.v2_idx].position;
+                vec3 p3 = vertices[m.start_vertex_idx + triangle.v3_idx].position;
+
+                float tu;
+                float tv;
+                float obj_dist =
+                    ray_triangle_intersect(local_ray, p1, p2, p3, tu, tv);
+
+                if (obj_dist < least_dist &&
+                    !is_cut_out_triangle(m, t, vec2(tu, tv))) {
+                    least_dist = obj_dist;
+                    hit_triangle = t;
+                    triangle_uv = vec2(tu, tv);
+                }
+            }
+        } else {
+            // visit the nearer child first, so further ones can be culled
+            uvec2 order;
+            vec2 dists;
+            order_children(bvh_nodes[m.start_node_idx + node.left_first],
+                           bvh_nodes[m.start_node_idx + node.left_first + 1],
+                           node.left_first, local_ray.origin, inv_dir, order,
+                           dists);
+
+            if (dists.y < least_dist) stack[stack_ptr++] = order.y;
+            if (dists.x < least_dist) stack[stack_ptr++] = order.x;
         }
     }
 
-    // Go through mesh instances
-    for (int i = 0; i < mesh_instances.length(); i++) {
-        MeshInstance m = mesh_instances[i];
+    return hit_triangle;
+}
 
-        // Go through all triangles of that mesh
-        for (uint t = m.start_triangle_idx;
-             t < (m.start_triangle_idx + m.num_triangles); t++) {
-            Triangle triangle = triangles[t];
+// The cone distance along the ray, where a reflected or refracted ray carries
+// on from. The curvature of what's hit is ignored
+RayCone cone_at(RayCone cone, float dist) {
+    return RayCone(cone.width + (cone.spread * dist), cone.spread);
+}
 
-            Vertex v1 = vertices[m.start_vertex_idx + triangle.v1_idx];
-            Vertex v2 = vertices[m.start_vertex_idx + triangle.v2_idx];
-            Vertex v3 = vertices[m.start_vertex_idx + triangle.v3_idx];
+HitInfo cast_ray(Ray ray, RayCone cone) {
+    // Expects SphereData, PlaneData in scope
+    float least_dist = FLT_MAX;
+    uint hit_idx = UINT_MAX;
+    uint hit_obj = UINT_MAX;
+    uint triangle_idx = UINT_MAX;
+    vec2 triangle_uv = vec2(FLT_MAX);
 
-            // translate vertices
-            vec3 p1 = v1.position + m.position;
-            vec3 p2 = v2.position + m.position;
-            vec3 p3 = v3.position + m.position;
-
-            float tu;
-            float tv;
-            float obj_dist = ray_triangle_intersect(ray, p1, p2, p3, tu, tv);
-
-            // Show vertices
-            //  Sphere sphere =
-            //     Sphere(v1.position, 0.15,
-            //            Material(0, vec2(1.0), 1.0, 1.0, 0.0, 0.0, 0.0,
-            // 0.0)); obj_dist = min(obj_dist, ray_sphere_intersect(ray,
-            // sphere));
-
-            // sphere =
-            //     Sphere(v2.position, 0.15,
-            //            Material(0, vec2(1.0), 1.0, 1.0, 0.0, 0.0, 0.0,
-            // 0.0)); obj_dist = min(obj_dist, ray_sphere_intersect(ray,
-            // sphere)); sphere = Sphere(v3.position, 0.15, Material(0,
-            // vec2(1.0), 1.0, 1.0, 0.0, 0.0, 0.0, 0.0)); obj_dist =
-            // min(obj_dist, ray_sphere_intersect(ray, sphere));
-
-            if (obj_dist < least_dist) {
-                least_dist = obj_dist;
-                hit_idx = i;
-                triangle_idx = t;
-                hit_obj = 2;  // TODO: replace with var
-                triangle_uv = vec2(tu, tv);
+    // Walk the top level BVH over all objects, nearest first
+    vec3 inv_dir = 1.0 / ray.direction;
+
+    uint stack[BVH_STACK_SIZE];
+    uint stack_ptr = 0;
+    stack[stack_ptr++] = 0;
+
+    while (stack_ptr > 0) {
+        BvhNode node = tlas_nodes[stack[--stack_ptr]];
+
+        if (ray_aabb_intersect(ray.origin, inv_dir, node.aabb_min,
+                               node.aabb_max) >= least_dist)
+            continue;
+
+        if (node.count > 0) {
+            for (uint o = node.left_first; o < node.left_first + node.count;
+                 o++) {
+                ObjectRef obj = objects[o];
+
+                if (obj.obj_type == OBJ_SPHERE) {
+                    Sphere sphere = spheres[obj.obj_idx];
+                    float obj_dist = ray_sphere_intersect(
+                        to_object_space(ray, sphere.inv_model), sphere.radius);
+                    if (obj_dist < least_dist) {
+                        least_dist = obj_dist;
+                        hit_idx = obj.obj_idx;
+                        hit_obj = OBJ_SPHERE;
+                    }
+                } else if (obj.obj_type == OBJ_PLANE) {
+                    float obj_dist = intersect_plane(ray, planes[obj.obj_idx]);
+                    if (obj_dist < least_dist) {
+                        least_dist = obj_dist;
+                        hit_idx = obj.obj_idx;
+                        hit_obj = OBJ_PLANE;
+                    }
+                } else if (obj.obj_type == OBJ_MESH) {
+                    uint t = intersect_mesh(ray, mesh_instances[obj.obj_idx],
+                                            least_dist, triangle_uv);
+                    if (t != UINT_MAX) {
+                        hit_idx = obj.obj_idx;
+                        triangle_idx = t;
+                        hit_obj = OBJ_MESH;
+                    }
+                }
             }
+        } else {
+            uvec2 order;
+            vec2 dists;
+            order_children(tlas_nodes[node.left_first],
+                           tlas_nodes[node.left_first + 1], node.left_first,
+                           ray.origin, inv_dir, order, dists);
+
+            if (dists.y < least_dist) stack[stack_ptr++] = order.y;
+            if (dists.x < least_dist) stack[stack_ptr++] = order.x;
         }
     }
 
@@ -201,27 +365,41 @@ HitInfo cast_ray(Ray ray) {
         vec3 normal;
         Material mat;
         vec2 uv;
+        vec3 vertex_colour = vec3(1.0);
+        vec4 surface_tangent;
+        // how far uvs go per unit of distance across the surface, on average
+        float uv_density;
 
-        if (hit_obj == 0) {
+        if (hit_obj == OBJ_SPHERE) {
             Sphere sphere = spheres[hit_idx];
-            normal = normalize(position - sphere.position);
-            mat = sphere.mat;
-
-            uv = vec2(0.5 + (atan(normal.x, -normal.z) / TAU),
-                      0.5 + (asin(normal.y) / PI));
-
-        } else if (hit_obj == 1) {
+            Ray local_ray = to_object_space(ray, sphere.inv_model);
+            vec3 local_normal = normalize(local_ray.origin +
+                                          (least_dist * local_ray.direction));
+            normal = normal_to_world(local_normal, sphere.inv_model);
+            mat = materials[sphere.mat_id];
+
+            // uvs stay fixed to the sphere as it rotates
+            uv = vec2(0.5 + (atan(local_normal.x, -local_normal.z) / TAU),
+                      0.5 + (asin(local_normal.y) / PI));
+            // around the y axis, the way u increases. v goes up, the other way
+            // to cross(normal, tangent)
+            surface_tangent =
+                vec4(mat3(sphere.model) *
+                         vec3(-local_normal.z, 0.0, local_normal.x),
+                     -1.0);
+
+            // u goes once around the sphere, and v halfway
+            float radius = sphere.radius * length(sphere.model[0].xyz);
+            uv_density = 1.0 / (PI * sqrt(2.0) * radius);
+        } else if (hit_obj == OBJ_PLANE) {
             Plane plane = planes[hit_idx];
-            mat = plane.mat;
+            mat = materials[plane.mat_id];
             normal = plane.normal;
 
-            vec3 tangent = plane.tangent;
-            vec3 bitangent = cross(normal, tangent);
-
-            vec3 delta = position - plane.position;
-            uv = vec2(0.5) + vec2(dot(tangent, delta) / plane.width,
-                                  dot(bitangent, delta) / plane.height);
-        } else if (hit_obj == 2) {
+            uv = plane_uv(plane, position);
+            surface_tangent = vec4(plane.tangent, 1.0);
+            uv_density = 1.0 / sqrt(plane.width * plane.height);
+        } else if (hit_obj == OBJ_MESH) {
             MeshInstance m = mesh_instances[hit_idx];
             Triangle triangle = triangles[triangle_idx];
 
@@ -229,34 +407,60 @@ HitInfo cast_ray(Ray ray) {
             Vertex v2 = vertices[m.start_vertex_idx + triangle.v2_idx];
             Vertex v3 = vertices[m.start_vertex_idx + triangle.v3_idx];
 
-            // translate vertices
-            v1.position += m.position;
-            v2.position += m.position;
-            v3.position += m.position;
-
             float r = triangle_uv.x;
             float s = triangle_uv.y;
             float w = 1.0 - r - s;
 
-            normal =
-                normalize((w * v1.normal) + (r * v2.normal) + (s * v3.normal));
+            normal = normal_to_world(
+                (w * v1.normal) + (r * v2.normal) + (s * v3.normal),
+                m.inv_model);
             // normal = normalize(v1.normal + v2.normal + v3.normal);
-            mat = m.mat;
+            mat = triangle_material(m, triangle_idx);
 
             uv = (w * v1.uv) + (r * v2.uv) + (s * v3.uv);
             // uv = vec2(0.5);
+            vertex_colour =
+                (w * v1.colour) + (r * v2.colour) + (s * v3.colour);
+            vec3 local_tangent = (w * v1.tangent.xyz) + (r * v2.tangent.xyz) +
+                                 (s * v3.tangent.xyz);
+            surface_tangent = vec4(mat3(m.model) * local_tangent, v1.tangent.w);
+
+            // from the triangle's area in uv space and in the world, both
+            // doubled
+            vec3 edge1 = mat3(m.model) * (v2.position - v1.position);
+            vec3 edge2 = mat3(m.model) * (v3.position - v1.position);
+            float world_area = length(cross(edge1, edge2));
+            vec2 uv_edge1 = v2.uv - v1.uv;
+            vec2 uv_edge2 = v3.uv - v1.uv;
+            float uv_area =
+                abs((uv_edge1.x * uv_edge2.y) - (uv_edge1.y * uv_edge2.x));
+            uv_density =
+                world_area > 0.0 ? sqrt(uv_area / world_area) : 0.0;
         }
 
+        // lightmaps are ordered spheres, then planes, then mesh instances
         uint lm_idx = hit_idx;
-        if (hit_obj > 0) {
-            lm_idx += spheres.length();
+        if (hit_obj > OBJ_SPHERE) {
+            lm_idx += constants.counts.spheres;
         }
-        if (hit_obj > 1) {
-            lm_idx += planes.length();
+        if (hit_obj > OBJ_PLANE) {
+            lm_idx += constants.counts.planes;
         }
 
-        vec3 colour = sample_texture(mat, uv);
-        vec3 radiosity = sample_lightmap(lm_idx, uv);
+        // the width of the ray's cone in uv space, stretched where it meets the
+        // surface at an angle. Kept from getting too wide at grazing angles,
+        // as the filtering doesn't stretch along with it
+        float cos_incidence = max(abs(dot(normal, ray.direction)), 0.1);
+        float footprint =
+            cone_at(cone, least_dist).width * uv_density / cos_incidence;
+
+        mat = sample_material(mat, uv, footprint);
+        normal =
+            sample_normal_map(mat, uv, footprint, normal, surface_tangent);
+
+        vec3 colour = sample_texture(mat, uv, footprint) * vertex_colour;
+        vec3 radiosity = sample_lightmap(lm_idx, uv) *
+                         sample_occlusion(mat, uv, footprint);
 
         return HitInfo(position, normal, mat, colour, radiosity);
 
@@ -266,128 +470,84 @@ HitInfo cast_ray(Ray ray) {
     }
 }
 
-// TODO: This technically causes a "penumbra" cast on objects by themselves.
-// Not sure if thats correct? Should that happen *on top* of lambertian
-// attenuation? mostly noticeable in radiosity and needs paying attention to
-float cast_shadow_ray(Ray ray, vec3 vec_to_light) {
-    // Expects SphereData, PlaneData in scope
-    // ray must be normalized
-    // ignores planes as they dont cast shadows
-    // uses distance between a point and a line to find the closest approach
-    // between the ray and a sphere
-    float light_dist = length(vec_to_light);
-    // float least_dist = FLT_MAX;
-
-    const float SHADING_K = 16.0;  // TODO: make actual light size
-    float shade = 1.0;
-
-    for (int i = 0; i < spheres.length(); i++) {
-        Sphere sphere = spheres[i];
-        float obj_dist = ray_sphere_intersect(ray, sphere);
-
-        vec3 p = sphere.position;
-        vec3 a = ray.origin;
-        vec3 d = p - a;
-
-        float projected_length = dot(d, ray.direction);
-
-        if (projected_length > light_dist || projected_length < 0.0) continue;
-
-        float closest_approach =
-            length(d - (projected_length * ray.direction)) - sphere.radius;
-        closest_approach = max(closest_approach, 0.0);
-
-        shade = min(
-            shade, smoothstep(0.0, 1.0,
-                              SHADING_K * closest_approach / projected_length));
-
-        // least_dist = min(least_dist, obj_dist);
-    }
-
-    for (int i = 0; i < mesh_instances.length(); i++) {
-        MeshInstance m = mesh_instances[i];
-
-        // Go through all triangles of that mesh
-        for (uint t = m.start_triangle_idx;
-             t < (m.start_triangle_idx + m.num_triangles); t++) {
-            Triangle triangle = triangles[t];
-
-            Vertex v1 = vertices[m.start_vertex_idx + triangle.v1_idx];
-            Vertex v2 = vertices[m.start_vertex_idx + triangle.v2_idx];
-            Vertex v3 = vertices[m.start_vertex_idx + triangle.v3_idx];
-
-            // translate vertices
-            v1.position += m.position;
-            v2.position += m.position;
-            v3.position += m.position;
-
-            float tu;
-            float tv;
-            float projected_length = ray_triangle_intersect(
-                ray, v1.position, v2.position, v3.position, tu, tv);
+// The fraction of light, per channel, that gets through an object on its way
+// to a light after travelling thickness through it. Opaque objects let none
+// through, while transmissive ones let some through, tinted by their colour
+// and absorbed along the way (Beer-Lambert). Refraction isn't followed, so
+// there are no caustics
+vec3 shadow_transmittance(Material mat, float thickness) {
+    float transmissive = mat.transmission * (1.0 - mat.metallic);
+    return transmissive * mat.base_colour * exp(-mat.absorption * thickness);
+}
 
-            // we directly hit the triangle and it's blocking light
-            if (projected_length < light_dist && projected_length > 0.0) {
-                shade = min(shade, 0.0);
-            } else {
-                vec3 vp1 = v1.position;
-                vec3 vp2 = v2.position;
-                vec3 vp3 = v3.position;
-
-                vec3 edges[3][2] = {{vp1, vp2}, {vp1, vp3}, {vp2, vp3}};
-
-                for (int e = 0; e < 3; e++) {
-                    vec3 vertices[2] = edges[e];
-                    vec3 seg_dir = vertices[1] - vertices[0];
-
-                    vec3 p1 = ray.origin;
-                    vec3 p2 = (ray.origin + ray.direction);
-                    vec3 p3 = vertices[0];
-                    vec3 p4 = vertices[1];
-
-                    vec3 V1 = p2 - p1;
-                    vec3 V2 = p4 - p3;
-                    vec3 V21 = p3 - p1;
-
-                    float v11 = dot(V1, V1);
-                    float v21 = dot(V2, V1);
-                    float v22 = dot(V2, V2);
-                    float v21_2 = dot(V21, V2);
-                    float v21_1 = dot(V21, V1);
-                    float denom = v21 * v21 - v22 * v11;
-
-                    float s;
-                    float t;
-
-                    /* if (abs(denom) < EPSILON) {
-                        s = 0;
-                        t = (v11 * s - v21_1) / v21;
-                    } else { */
-                    s = (v21_2 * v21 - v22 * v21_1) / denom;
-                    t = (-v21_1 * v21 + v11 * v21_2) / denom;
-                    /* } */
-
-                    if (s < 0.0 || s > light_dist) continue;
-                    t = clamp(t, 0.0, 1.0);
-
-                    vec3 closest_point_ray = p1 + s * V1;
-                    vec3 closest_point_edge = p3 + t * V2;
-
-                    float projected_length =
-                        abs(length(closest_point_ray - ray.origin));
-
-                    float closest_approach =
-                        abs(length(closest_point_ray - closest_point_edge));
-
-                    shade = min(shade, smoothstep(0.0, 1.0,
-                                                  SHADING_K * closest_approach /
-                                                      projected_length));
+// How much light gets through to a light, black if anything opaque is in the
+// way
+vec3 cast_shadow_ray(Ray ray, float light_dist) {
+    // ray must be normalized
+    vec3 inv_dir = 1.0 / ray.direction;
+    vec3 shade = vec3(1.0);
+
+    uint stack[BVH_STACK_SIZE];
+    uint stack_ptr = 0;
+    stack[stack_ptr++] = 0;
+
+    while (stack_ptr > 0) {
+        BvhNode node = tlas_nodes[stack[--stack_ptr]];
+
+        if (ray_aabb_intersect(ray.origin, inv_dir, node.aabb_min,
+                               node.aabb_max) >= light_dist)
+            continue;
+
+        if (node.count > 0) {
+            for (uint o = node.left_first; o < node.left_first + node.count;
+                 o++) {
+                ObjectRef obj = objects[o];
+
+                // find where the ray goes in and out of the object
+                if (obj.obj_type == OBJ_SPHERE) {
+                    Sphere sphere = spheres[obj.obj_idx];
+                    Ray local_ray = to_object_space(ray, sphere.inv_model);
+                    float entry = ray_sphere_intersect(local_ray, sphere.radius);
+
+                    if (entry < light_dist) {
+                        local_ray.origin +=
+                            local_ray.direction * (entry + EPSILON);
+                        float exit = min(
+                            ray_sphere_intersect(local_ray, sphere.radius),
+                            light_dist - entry);
+                        shade *= shadow_transmittance(
+                            materials[sphere.mat_id], exit);
+                    }
+                } else if (obj.obj_type == OBJ_PLANE) {
+                    Plane plane = planes[obj.obj_idx];
+                    if (intersect_plane(ray, plane) < light_dist)
+                        shade *= shadow_transmittance(
+                            materials[plane.mat_id], 0.0);
+                } else if (obj.obj_type == OBJ_MESH) {
+                    MeshInstance m = mesh_instances[obj.obj_idx];
+                    float entry = light_dist;
+                    vec2 triangle_uv;
+
+                    uint t = intersect_mesh(ray, m, entry, triangle_uv);
+                    if (t != UINT_MAX) {
+                        Ray exit_ray = Ray(
+                            ray.origin + (ray.direction * (entry + EPSILON)),
+                            ray.direction);
+                        float exit = max(light_dist - entry - EPSILON, 0.0);
+                        intersect_mesh(exit_ray, m, exit, triangle_uv);
+                        shade *= shadow_transmittance(
+                            triangle_material(m, t), exit);
+                    }
                 }
-            }
 
-            // least_dist = min(least_dist, obj_dist);
+                // no need to look any further
+                if (shade == vec3(0.0)) return shade;
+            }
+        } else {
+            stack[stack_ptr++] = node.left_first + 1;
+            stack[stack_ptr++] = node.left_first;
         }
     }
 
     return shade;
-};
\ No newline at end of file
+}
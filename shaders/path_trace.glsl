@@ -0,0 +1,211 @@
+// Monte Carlo path tracing, one sample per call. The results are accumulated
+// over frames by the caller
+
+// required descriptors:
+/*
+readonly buffer LightData { Light data[]; } lights;
+readonly buffer SkyData { Sky sky; };
+readonly buffer SkyCdfData { float sky_cdf[]; };
+*/
+
+const uint MAX_PATH_BOUNCES = 8;
+// paths are only cut short by russian roulette after this many bounces
+const uint MIN_PATH_BOUNCES = 3;
+
+// Weights between two ways of picking the same direction, from their pdfs
+float power_heuristic(float pdf, float other_pdf) {
+    float a = pdf * pdf;
+    float b = other_pdf * other_pdf;
+    return a + b > 0.0 ? a / (a + b) : 0.0;
+}
+
+// The pdf over solid angle of scatter picking light_dir from a material that
+// isn't transmissive. light_dir must be normalized, view_direction points at
+// the surface
+float scatter_pdf(vec3 normal, vec3 light_dir, vec3 view_direction,
+                  Material mat, vec3 albedo) {
+    float n_dot_l = dot(normal, light_dir);
+    if (n_dot_l <= 0.0) return 0.0;
+
+    vec3 v = -view_direction;
+    vec3 h = normalize(light_dir + v);
+    float n_dot_h = max(dot(normal, h), 1e-4);
+    float v_dot_h = max(dot(v, h), 1e-4);
+
+    float roughness = max(mat.roughness, MIN_ROUGHNESS);
+    float alpha = roughness * roughness;
+
+    vec3 f = fresnel_schlick(max(dot(normal, v), 0.0),
+                             base_reflectance(mat, albedo));
+    float p_specular =
+        clamp(mix((f.x + f.y + f.z) / 3.0, 1.0, mat.metallic), 0.1, 1.0);
+
+    // the microfacet normal's pdf, moved over to the reflected direction
+    float specular_pdf =
+        distribution_ggx(n_dot_h, alpha) * n_dot_h / (4.0 * v_dot_h);
+    float diffuse_pdf = n_dot_l / PI;
+
+    return mix(diffuse_pdf, specular_pdf, p_specular);
+}
+
+// The light from one direction in the sky, chosen with xi, reflected at a
+// hit, with shadows. Weighted against scatter finding the sky itself
+vec3 sky_sample(HitInfo hit, vec3 view_direction, vec2 xi) {
+    float pdf;
+    vec3 direction = sample_sky_direction(xi, pdf);
+    if (pdf <= 0.0) return vec3(0.0);
+
+    vec3 f = brdf(hit.normal, direction, view_direction, hit.mat, hit.colour);
+    if (f == vec3(0.0)) return vec3(0.0);
+
+    // far enough away that it's never in front of anything
+    Ray shadow_ray =
+        Ray(hit.position + (hit.normal * EPSILON * 5.0), direction);
+    vec3 shade = cast_shadow_ray(shadow_ray, 1e6);
+    if (shade == vec3(0.0)) return vec3(0.0);
+
+    float weight = power_heuristic(
+        pdf, scatter_pdf(hit.normal, direction, view_direction, hit.mat,
+                         hit.colour));
+
+    // brdf is scaled by pi to match the lights, the sky is in radiance
+    return shade * f * sample_sky(direction) * weight / (PI * pdf);
+}
+
+// Picks the direction a path carries on in after a hit, and multiplies
+// throughput by how much light comes back along it. Returns false if the
+// path ends. normal must face the incoming ray. bsdf_pdf is the pdf of the
+// direction for weighting against sky_sample, 0.0 when sky_sample doesn't
+// cover it
+bool scatter(inout Ray ray, HitInfo hit, bool entering, inout vec3 throughput,
+             inout vec3 absorption, inout uint rng, out float bsdf_pdf) {
+    Material mat = hit.mat;
+    vec3 albedo = hit.colour;
+    vec3 normal = hit.normal;
+    vec3 v = -ray.direction;
+
+    float roughness = max(mat.roughness, MIN_ROUGHNESS);
+    float alpha = roughness * roughness;
+    vec3 h = sample_ggx(normal, alpha, random2(rng));
+    float v_dot_h = max(dot(v, h), 0.0);
+
+    vec3 direction;
+    float transmissive = mat.transmission * (1.0 - mat.metallic);
+
+    if (random(rng) < transmissive) {
+        // through a dielectric, split between reflection and refraction by
+        // the fresnel term. The masking term is ignored here
+        float eta = entering ? 1.0 / mat.ior : mat.ior;
+
+        if (random(rng) < fresnel_dielectric(v_dot_h, eta)) {
+            direction = reflect(ray.direction, h);
+        } else {
+            direction = refract(ray.direction, h, eta);
+            throughput *= albedo;
+            absorption = entering ? mat.absorption : vec3(0.0);
+        }
+    } else {
+        // the rest is a specular lobe over a diffuse one, pick one of them
+        // by roughly how much they reflect
+        vec3 f0 = base_reflectance(mat, albedo);
+        vec3 f = fresnel_schlick(max(dot(normal, v), 0.0), f0);
+        float p_specular = clamp(mix((f.x + f.y + f.z) / 3.0, 1.0, mat.metallic),
+                                 0.1, 1.0);
+
+        if (random(rng) < p_specular) {
+            direction = reflect(ray.direction, h);
+
+            float n_dot_l = dot(normal, direction);
+            float n_dot_v = max(dot(normal, v), 1e-4);
+            float n_dot_h = max(dot(normal, h), 1e-4);
+            if (n_dot_l <= 0.0) return false;
+
+            // the brdf times the cosine term, over the pdf of sampling
+            // the microfacet normal
+            throughput *= fresnel_schlick(v_dot_h, f0) *
+                          geometry_smith(n_dot_v, n_dot_l, alpha) * v_dot_h /
+                          (n_dot_v * n_dot_h * p_specular);
+        } else {
+            direction = sample_cosine_hemisphere(normal, random2(rng));
+
+            // only the part of the material that isn't transmissive is
+            // left to be diffuse
+            throughput *= (1.0 - f) * albedo * diffuse_weight(mat) /
+                          ((1.0 - transmissive) * (1.0 - p_specular));
+        }
+    }
+
+    if (direction == vec3(0.0)) return false;
+
+    // brdf, which sky_sample uses, doesn't cover what transmissive materials
+    // reflect and refract, so the sky is only found by scattering from them
+    bsdf_pdf = transmissive > 0.0
+                   ? 0.0
+                   : scatter_pdf(normal, direction, ray.direction, mat, albedo);
+
+    // nudge the ray off the surface
+    ray = Ray(hit.position + (direction * EPSILON * 3.0), direction);
+    return true;
+}
+
+// cone starts as the camera ray's, and follows the path as it bounces
+vec3 path_trace_pixel(Ray ray, RayCone cone, inout uint rng) {
+    vec3 colour = vec3(0.0);
+    vec3 throughput = vec3(1.0);
+    vec3 absorption = vec3(0.0);
+    // camera rays can't be picked by sky_sample
+    float bsdf_pdf = 0.0;
+
+    for (uint bounce = 0; bounce < MAX_PATH_BOUNCES; bounce++) {
+        HitInfo hit = cast_ray(ray, cone);
+
+        if (hit.normal.x >= FLT_MAX) {
+            float weight =
+                bsdf_pdf > 0.0
+                    ? power_heuristic(bsdf_pdf, sky_pdf(ray.direction))
+                    : 1.0;
+            colour += throughput * weight * sample_sky(ray.direction);
+            break;
+        }
+
+        // Beer-Lambert, light is absorbed on its way through a medium
+        float dist = distance(ray.origin, hit.position);
+        throughput *= exp(-absorption * dist);
+        cone = cone_at(cone, dist);
+
+        bool entering = dot(ray.direction, hit.normal) < 0.0;
+        if (!entering) hit.normal = -hit.normal;
+
+        // lights aren't part of the scene, so they can't be hit by a path and
+        // are sampled directly at every hit instead. Emissive objects can be
+        // hit, so their light is only added here
+        colour += throughput * hit.mat.emissive;
+        for (uint i = 0; i < constants.counts.lights; i++) {
+            colour += throughput * light_sample(lights[i], hit.position,
+                                                hit.normal, ray.direction,
+                                                hit.mat, hit.colour,
+                                                random2(rng), false);
+        }
+
+        // the sky can be hit, but small bright parts of it are rarely found,
+        // so it's sampled directly too
+        if (hit.mat.transmission * (1.0 - hit.mat.metallic) <= 0.0) {
+            colour += throughput * sky_sample(hit, ray.direction, random2(rng));
+        }
+
+        if (!scatter(ray, hit, entering, throughput, absorption, rng,
+                     bsdf_pdf))
+            break;
+
+        // russian roulette, end paths that won't add much, boosting the rest
+        // so it stays unbiased
+        if (bounce >= MIN_PATH_BOUNCES) {
+            float p = clamp(max(throughput.x, max(throughput.y, throughput.z)),
+                            0.05, 1.0);
+            if (random(rng) > p) break;
+            throughput /= p;
+        }
+    }
+
+    return colour;
+}
@@ -0,0 +1,26 @@
+// Hashing for noise, there is no state so the same inputs give the same
+// numbers every frame
+
+// PCG hash, from "Hash Functions for GPU Rendering" (Jarzynski and Olano)
+uint pcg_hash(uint v) {
+    uint state = v * 747796405u + 2891336453u;
+    uint word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+uint hash_position(vec3 p) {
+    return pcg_hash(floatBitsToUint(p.x) ^
+                    pcg_hash(floatBitsToUint(p.y) ^
+                             pcg_hash(floatBitsToUint(p.z))));
+}
+
+// 0.0..1.0
+float hash_to_float(uint h) { return float(h) / 4294967296.0; }
+
+// 0.0..1.0, advancing the state so the next call gives a new number
+float random(inout uint state) {
+    state = pcg_hash(state);
+    return hash_to_float(state);
+}
+
+vec2 random2(inout uint state) { return vec2(random(state), random(state)); }
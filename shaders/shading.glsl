@@ -1,63 +1,363 @@
 // required descriptors:
 /*
-readonly buffer LightData { PointLight data[]; } lights;
+readonly buffer LightData { Light data[]; } lights;
+readonly buffer SkyData { Sky sky; };
 */
 
-// TODO: Blinn Phong?
-float phong(vec3 normal, vec3 vec_to_light, vec3 view_direction,
-            float light_intensity, Material mat) {
-    float dist_sqd = dot(vec_to_light, vec_to_light);
-    float light_radiance = light_intensity / dist_sqd;
-    vec3 vec_to_light_norm = vec_to_light * inversesqrt(dist_sqd);
+// area lights are sampled on a jittered grid of this size squared
+const uint AREA_LIGHT_GRID = 4;
+
+// Perceptually linear, squared to get the alpha used by GGX. Kept above zero
+// so highlights from point lights don't vanish on smooth surfaces
+const float MIN_ROUGHNESS = 0.045;
+
+// Surfaces rougher than this don't reflect their surroundings, their specular
+// only comes from lights
+const float MAX_REFLECTION_ROUGHNESS = 0.5;
+
+// the sky's light is averaged over jittered grids of these sizes squared,
+// picked from the sky for diffuse light and from the GGX lobe for glossy
+// reflections
+const uint SKY_IRRADIANCE_GRID = 8;
+const uint SKY_SPECULAR_GRID = 4;
+
+// Schlick's approximation of how much light is reflected rather than
+// refracted
+vec3 fresnel_schlick(float cos_theta, vec3 f0) {
+    return f0 + ((1.0 - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0));
+}
+
+// Fraction of light reflected at the boundary between two dielectrics, eta is
+// the ratio of their iors (from / to) and cos_i is for the incoming ray.
+// 1.0 when there is total internal reflection
+float fresnel_dielectric(float cos_i, float eta) {
+    float sin_t2 = eta * eta * (1.0 - (cos_i * cos_i));
+    if (sin_t2 >= 1.0) return 1.0;
+
+    // Schlick's approximation, using the angle on the less dense side
+    float cos_t = sqrt(1.0 - sin_t2);
+    float r = (eta - 1.0) / (eta + 1.0);
+    float f0 = r * r;
+    return f0 + ((1.0 - f0) * pow(1.0 - (eta > 1.0 ? cos_t : cos_i), 5.0));
+}
+
+// How much of the light hitting a surface is scattered diffusely, metals and
+// transmissive materials have none
+float diffuse_weight(Material mat) {
+    return (1.0 - mat.metallic) * (1.0 - mat.transmission);
+}
 
-    vec3 light_reflection = reflect(vec_to_light_norm, normal);
+// Reflectance at normal incidence, dielectrics get it from their ior and
+// metals from their colour
+vec3 base_reflectance(Material mat, vec3 albedo) {
+    float r = (mat.ior - 1.0) / (mat.ior + 1.0);
+    return mix(vec3(r * r), albedo, mat.metallic);
+}
+
+// GGX/Trowbridge-Reitz normal distribution
+float distribution_ggx(float n_dot_h, float alpha) {
+    float a2 = alpha * alpha;
+    float d = (n_dot_h * n_dot_h * (a2 - 1.0)) + 1.0;
+    return a2 / (PI * d * d);
+}
+
+// Smith masking-shadowing, with the Schlick-GGX approximation
+float geometry_smith(float n_dot_v, float n_dot_l, float alpha) {
+    float k = alpha / 2.0;
+    float g_v = n_dot_v / ((n_dot_v * (1.0 - k)) + k);
+    float g_l = n_dot_l / ((n_dot_l * (1.0 - k)) + k);
+    return g_v * g_l;
+}
+
+// Cook-Torrance specular with a lambertian diffuse lobe, times the cosine
+// term. Lights are in units where a white lambertian surface facing one
+// reflects all of its intensity, so this is scaled by pi to match.
+// light_dir must be normalized, view_direction points at the surface
+vec3 brdf(vec3 normal, vec3 light_dir, vec3 view_direction, Material mat,
+          vec3 albedo) {
+    float n_dot_l = dot(normal, light_dir);
+    if (n_dot_l <= 0.0) return vec3(0.0);
+
+    vec3 v = -view_direction;
+    vec3 h = normalize(light_dir + v);
+    float n_dot_v = max(dot(normal, v), 1e-4);
+    float n_dot_h = max(dot(normal, h), 0.0);
+    float v_dot_h = max(dot(v, h), 0.0);
+
+    float roughness = max(mat.roughness, MIN_ROUGHNESS);
+    float alpha = roughness * roughness;
+
+    vec3 f = fresnel_schlick(v_dot_h, base_reflectance(mat, albedo));
+    vec3 specular = f * distribution_ggx(n_dot_h, alpha) *
+                    geometry_smith(n_dot_v, n_dot_l, alpha) /
+                    (4.0 * n_dot_v * n_dot_l);
+    vec3 diffuse = (1.0 - f) * diffuse_weight(mat) * albedo / PI;
+
+    return PI * (diffuse + specular) * n_dot_l;
+}
 
-    float diffuse = max(dot(vec_to_light_norm, normal), 0.0);
-    float specular = 0.0;
+// How much of its surroundings a surface reflects, fading out as it gets
+// rougher. Also returns how much is refracted through it, and the direction.
+// normal must face the incoming ray, entering is whether it's going into
+// the object
+vec3 reflection_weight(vec3 normal, vec3 view_direction, Material mat,
+                       vec3 albedo, bool entering, out vec3 refract_weight,
+                       out vec3 refracted) {
+    float smoothness =
+        1.0 - clamp(mat.roughness / MAX_REFLECTION_ROUGHNESS, 0.0, 1.0);
+    float cos_i = max(dot(normal, -view_direction), 0.0);
 
-    if (diffuse > 0.0) {
-        specular =
-            pow(max(dot(light_reflection, view_direction), 0.0), mat.shininess);
+    vec3 f = fresnel_schlick(cos_i, base_reflectance(mat, albedo));
+    vec3 reflect_weight = f * smoothness * smoothness;
+
+    // TODO: blur refraction through rough surfaces
+    float transmissive = mat.transmission * (1.0 - mat.metallic);
+    refract_weight = vec3(0.0);
+    refracted = vec3(0.0);
+
+    if (transmissive > 0.0) {
+        float eta = entering ? 1.0 / mat.ior : mat.ior;
+        float f_dielectric = fresnel_dielectric(cos_i, eta);
+
+        if (f_dielectric < 1.0) {
+            refracted = refract(view_direction, normal, eta);
+            refract_weight = (1.0 - f_dielectric) * transmissive * albedo;
+        }
+
+        // the light that isn't refracted is all reflected
+        reflect_weight = mix(reflect_weight, vec3(f_dielectric), transmissive);
     }
-    // TODO: Tint specular based on metallic parameter
-    return light_radiance * (mat.diffuse * diffuse + mat.specular * specular);
+
+    return reflect_weight;
 }
 
-vec3 shade_object(vec3 direction, HitInfo info, inout vec3 transmission) {
-    PointLight light = lights[0];  // TODO: multiple lights
+bool is_area_light(Light light) {
+    return light.light_type == LIGHT_RECT || light.light_type == LIGHT_SPHERE;
+}
+
+// Picks a point on the light to shade position from, using xi (0.0..1.0) to
+// choose it on area lights. Returns the light arriving from that point,
+// ignoring shadows, and the vector to it
+vec3 sample_light(Light light, vec3 position, vec2 xi,
+                  out vec3 vec_to_light) {
+    vec3 radiance = light.colour * light.intensity;
+
+    if (light.light_type == LIGHT_DIRECTIONAL) {
+        // far enough away that it's never in front of anything
+        vec_to_light = -light.direction * 1e6;
+        return radiance;
+    }
+
     vec3 light_pos = light.position;
-    float light_intensity = light.intensity;
+    if (light.light_type == LIGHT_RECT) {
+        light_pos += ((xi.x - 0.5) * light.tangent) +
+                     ((xi.y - 0.5) * light.bitangent);
+    } else if (light.light_type == LIGHT_SPHERE) {
+        // a point on the sphere, flipped onto the side facing us
+        float z = 1.0 - (2.0 * xi.x);
+        float r = sqrt(max(1.0 - (z * z), 0.0));
+        float phi = TAU * xi.y;
+        vec3 dir = vec3(r * cos(phi), r * sin(phi), z);
+        if (dot(dir, position - light.position) < 0.0) dir = -dir;
 
-    Material mat = info.mat;
-    vec3 obj_col = info.colour;
+        light_pos += light.radius * dir;
+    }
 
-    vec3 vec_to_light = light_pos - info.position;
-    vec3 position = info.position;
-    vec3 normal = info.normal;
+    vec_to_light = light_pos - position;
+    float dist_sqd = dot(vec_to_light, vec_to_light);
+    vec3 light_dir = vec_to_light * inversesqrt(dist_sqd);
+    radiance /= dist_sqd;
+
+    if (light.light_type == LIGHT_SPOT) {
+        float cos_angle = dot(-light_dir, light.direction);
+        radiance *= smoothstep(light.cos_outer, light.cos_inner, cos_angle);
+    } else if (light.light_type == LIGHT_RECT) {
+        // one sided, and dimmer at grazing angles
+        radiance *= max(dot(-light_dir, light.direction), 0.0);
+    }
+
+    return radiance;
+}
 
-    Ray shadow_ray =
-        Ray(position + (normal * EPSILON * 5.0), normalize(vec_to_light));
+// One shadowed sample of the light reflected at a point from a light, using
+// xi (0.0..1.0) to pick the point on area lights. With lambert_only it's just
+// the light arriving weighted by the cosine term, for radiosity
+vec3 light_sample(Light light, vec3 position, vec3 normal, vec3 view_direction,
+                  Material mat, vec3 albedo, vec2 xi, bool lambert_only) {
+    vec3 vec_to_light;
+    vec3 radiance = sample_light(light, position, xi, vec_to_light);
 
-    float shade = cast_shadow_ray(shadow_ray, vec_to_light);
+    float light_dist = length(vec_to_light);
+    vec3 light_dir = vec_to_light / light_dist;
+    float lambert = dot(normal, light_dir);
 
-    // Cheap fresnel
-    // TODO: make more physically correct
-    float fx = clamp(1.0 - dot(normal, -direction), 0.0, 1.0);
-    float fresnel = fx * fx * fx * fx * fx;
+    if (lambert <= 0.0 || dot(radiance, radiance) <= 0.0) return vec3(0.0);
 
-    // The amount the object this way was reflected off modifies the colour
-    vec3 last_transmission = transmission;
-    transmission *=
-        clamp(fresnel + mat.reflectivity, 0.0, 1.0) * obj_col;  // obj_colour
+    Ray shadow_ray = Ray(position + (normal * EPSILON * 5.0), light_dir);
+    vec3 shade = cast_shadow_ray(shadow_ray, light_dist);
 
-    /* return last_transmission * obj_col *
-           (mat.ambient + shade * phong(normal, vec_to_light, direction,
-                                        light_intensity, mat)); */
+    if (shade == vec3(0.0)) return vec3(0.0);
+
+    vec3 reflected = lambert_only
+                         ? vec3(lambert)
+                         : brdf(normal, light_dir, view_direction, mat, albedo);
+    return shade * reflected * radiance;
+}
 
-    return (last_transmission * info.radiosity * obj_col) +
-           last_transmission * obj_col *
-               (shade *
-                phong(normal, vec_to_light, direction, light_intensity, mat));
+// The light reflected at a point from one light, with shadows, averaged over
+// a grid of samples on area lights
+vec3 direct_light(Light light, vec3 position, vec3 normal,
+                  vec3 view_direction, Material mat, vec3 albedo,
+                  bool lambert_only) {
+    uint num_samples =
+        is_area_light(light) ? AREA_LIGHT_GRID * AREA_LIGHT_GRID : 1;
+
+    // rotate the grid by a different amount for each point, so the banding
+    // between samples turns into noise
+    uint h = hash_position(position);
+    vec2 offset = vec2(hash_to_float(h), hash_to_float(pcg_hash(h)));
+
+    vec3 total = vec3(0.0);
+    for (uint s = 0; s < num_samples; s++) {
+        vec2 cell = vec2(s % AREA_LIGHT_GRID, s / AREA_LIGHT_GRID);
+        vec2 xi = fract(((cell + 0.5) / AREA_LIGHT_GRID) + offset);
+
+        total += light_sample(light, position, normal, view_direction, mat,
+                              albedo, xi, lambert_only);
+    }
+
+    return total / float(num_samples);
+}
+
+// Builds a basis around n and moves v into it, from "Building an Orthonormal
+// Basis, Revisited" (Duff et al.)
+vec3 to_basis(vec3 v, vec3 n) {
+    float s = n.z >= 0.0 ? 1.0 : -1.0;
+    float a = -1.0 / (s + n.z);
+    float b = n.x * n.y * a;
+    vec3 tangent = vec3(1.0 + (s * n.x * n.x * a), s * b, -s * n.x);
+    vec3 bitangent = vec3(b, s + (n.y * n.y * a), -n.y);
+    return (v.x * tangent) + (v.y * bitangent) + (v.z * n);
+}
+
+vec3 sample_cosine_hemisphere(vec3 normal, vec2 xi) {
+    float r = sqrt(xi.x);
+    float phi = TAU * xi.y;
+    return to_basis(vec3(r * cos(phi), r * sin(phi), sqrt(1.0 - xi.x)),
+                    normal);
+}
+
+// A microfacet normal from the GGX distribution
+vec3 sample_ggx(vec3 normal, float alpha, vec2 xi) {
+    float phi = TAU * xi.x;
+    float cos_theta =
+        sqrt((1.0 - xi.y) / (1.0 + (((alpha * alpha) - 1.0) * xi.y)));
+    float sin_theta = sqrt(max(1.0 - (cos_theta * cos_theta), 0.0));
+    return to_basis(
+        vec3(sin_theta * cos(phi), sin_theta * sin(phi), cos_theta), normal);
+}
+
+// The light from the sky reaching a point, with shadows, over pi so it's in
+// the same units as the lightmaps
+vec3 sky_irradiance(vec3 position, vec3 normal) {
+    uint num_samples = SKY_IRRADIANCE_GRID * SKY_IRRADIANCE_GRID;
+
+    // rotate the grid by a different amount for each point, so the banding
+    // between samples turns into noise
+    uint h = hash_position(position);
+    vec2 offset = vec2(hash_to_float(h), hash_to_float(pcg_hash(h)));
+
+    vec3 total = vec3(0.0);
+    for (uint s = 0; s < num_samples; s++) {
+        vec2 cell = vec2(s % SKY_IRRADIANCE_GRID, s / SKY_IRRADIANCE_GRID);
+        vec2 xi = fract(((cell + 0.5) / SKY_IRRADIANCE_GRID) + offset);
+
+        float pdf;
+        vec3 direction = sample_sky_direction(xi, pdf);
+        float n_dot_l = dot(normal, direction);
+        if (n_dot_l <= 0.0 || pdf <= 0.0) continue;
+
+        // far enough away that it's never in front of anything
+        Ray shadow_ray =
+            Ray(position + (normal * EPSILON * 5.0), direction);
+        vec3 shade = cast_shadow_ray(shadow_ray, 1e6);
+
+        total += shade * sample_sky(direction) * n_dot_l / pdf;
+    }
+
+    return total / (float(num_samples) * PI);
+}
+
+// The sky reflected by a glossy surface, with shadows. Smooth surfaces trace
+// their reflections already, so this fades in as they get rougher
+vec3 sky_specular(vec3 position, vec3 normal, vec3 view_direction,
+                  Material mat, vec3 albedo) {
+    float smoothness =
+        1.0 - clamp(mat.roughness / MAX_REFLECTION_ROUGHNESS, 0.0, 1.0);
+    float fade = 1.0 - (smoothness * smoothness);
+    if (fade <= 0.0) return vec3(0.0);
+
+    vec3 v = -view_direction;
+    float roughness = max(mat.roughness, MIN_ROUGHNESS);
+    float alpha = roughness * roughness;
+    vec3 f0 = base_reflectance(mat, albedo);
+    float n_dot_v = max(dot(normal, v), 1e-4);
+
+    uint num_samples = SKY_SPECULAR_GRID * SKY_SPECULAR_GRID;
+    uint h = hash_position(position);
+    vec2 offset = vec2(hash_to_float(h), hash_to_float(pcg_hash(h)));
+
+    vec3 total = vec3(0.0);
+    for (uint s = 0; s < num_samples; s++) {
+        vec2 cell = vec2(s % SKY_SPECULAR_GRID, s / SKY_SPECULAR_GRID);
+        vec2 xi = fract(((cell + 0.5) / SKY_SPECULAR_GRID) + offset);
+
+        vec3 m = sample_ggx(normal, alpha, xi);
+        vec3 direction = reflect(view_direction, m);
+        float n_dot_l = dot(normal, direction);
+        if (n_dot_l <= 0.0) continue;
+
+        float n_dot_h = max(dot(normal, m), 1e-4);
+        float v_dot_h = max(dot(v, m), 0.0);
+
+        Ray shadow_ray =
+            Ray(position + (normal * EPSILON * 5.0), direction);
+        vec3 shade = cast_shadow_ray(shadow_ray, 1e6);
+
+        // the brdf times the cosine term, over the pdf of sampling the
+        // microfacet normal
+        total += shade * sample_sky(direction) *
+                 fresnel_schlick(v_dot_h, f0) *
+                 geometry_smith(n_dot_v, n_dot_l, alpha) * v_dot_h /
+                 (n_dot_v * n_dot_h);
+    }
+
+    return fade * total / float(num_samples);
+}
+
+// The light reflected towards the viewer at a hit, normal must face the viewer
+vec3 shade_object(vec3 direction, HitInfo info) {
+    Material mat = info.mat;
+    vec3 albedo = info.colour;
+
+    vec3 position = info.position;
+    vec3 normal = info.normal;
+
+    // Sum the direct light from every light
+    vec3 direct = vec3(0.0);
+    for (uint i = 0; i < constants.counts.lights; i++) {
+        direct += direct_light(lights[i], position, normal, direction, mat,
+                               albedo, false);
+    }
+
+    // only diffuse surfaces pick up bounced light
+    vec3 indirect = info.radiosity * albedo * diffuse_weight(mat);
+
+    // the sky's diffuse light is in the lightmaps, its glossy reflections
+    // aren't
+    if (sky.lights_scene != 0) {
+        direct += sky_specular(position, normal, direction, mat, albedo);
+    }
 
-    /* return 1.5 * info.radiosity; */
+    return indirect + direct + mat.emissive;
 }
@@ -0,0 +1,193 @@
+// What rays that miss everything see
+
+// required descriptors:
+/*
+readonly buffer SkyData { Sky sky; };
+readonly buffer SkyCdfData { float sky_cdf[]; };
+uniform sampler2D textures[];
+*/
+
+// the Preetham model gives luminance in kcd/m^2, this brings a clear sky to
+// about the brightness of the scene's lights
+const float SKY_LUMINANCE_SCALE = 0.1;
+
+// Perez et al.'s distribution of sky luminance and chromaticity (as Yxy), by
+// the angle from the zenith and the angle from the sun
+vec3 perez(float cos_theta, float gamma, float cos_gamma, vec3 a, vec3 b,
+           vec3 c, vec3 d, vec3 e) {
+    return (1.0 + (a * exp(b / cos_theta))) *
+           (1.0 + (c * exp(d * gamma)) + (e * cos_gamma * cos_gamma));
+}
+
+// The clear sky from "A Practical Analytic Model for Daylight" (Preetham et
+// al.), without the sun itself. to_sun must be normalised
+vec3 procedural_sky(vec3 direction, vec3 to_sun, float turbidity) {
+    float t = turbidity;
+
+    // below the horizon is the same as the horizon, and so is a sun that's set
+    float cos_theta = max(direction.y, 0.01);
+    float cos_gamma = clamp(dot(direction, to_sun), -1.0, 1.0);
+    float gamma = acos(cos_gamma);
+    float theta_s = acos(clamp(to_sun.y, 0.0, 1.0));
+
+    // Perez coefficients for Y, x and y
+    vec3 a = (vec3(0.1787, -0.0193, -0.0167) * t) +
+             vec3(-1.4630, -0.2592, -0.2608);
+    vec3 b = (vec3(-0.3554, -0.0665, -0.0950) * t) +
+             vec3(0.4275, 0.0008, 0.0092);
+    vec3 c = (vec3(-0.0227, -0.0004, -0.0079) * t) +
+             vec3(5.3251, 0.2125, 0.2102);
+    vec3 d = (vec3(0.1206, -0.0641, -0.0441) * t) +
+             vec3(-2.5771, -0.8989, -1.6537);
+    vec3 e = (vec3(-0.0670, -0.0033, -0.0109) * t) +
+             vec3(0.3703, 0.0452, 0.0529);
+
+    // the sky at the zenith
+    float chi = ((4.0 / 9.0) - (t / 120.0)) * (PI - (2.0 * theta_s));
+    float zenith_y = (((4.0453 * t) - 4.9710) * tan(chi)) - (0.2155 * t) +
+                     2.4192;
+    vec3 thetas = vec3(theta_s * theta_s * theta_s, theta_s * theta_s, theta_s);
+    vec2 zenith_xy = vec2(
+        (t * t * dot(vec3(0.00166, -0.00375, 0.00209), thetas)) +
+            (t * (dot(vec3(-0.02903, 0.06377, -0.03202), thetas) + 0.00394)) +
+            dot(vec3(0.11693, -0.21196, 0.06052), thetas) + 0.25886,
+        (t * t * dot(vec3(0.00275, -0.00610, 0.00317), thetas)) +
+            (t * (dot(vec3(-0.04214, 0.08970, -0.04153), thetas) + 0.00516)) +
+            dot(vec3(0.15346, -0.26756, 0.06670), thetas) + 0.26688);
+
+    vec3 yxy = vec3(zenith_y, zenith_xy) *
+               perez(cos_theta, gamma, cos_gamma, a, b, c, d, e) /
+               perez(1.0, theta_s, cos(theta_s), a, b, c, d, e);
+
+    // Yxy to XYZ, then to linear sRGB
+    float luminance = yxy.x * SKY_LUMINANCE_SCALE;
+    vec3 xyz = vec3(yxy.y, yxy.z, 1.0 - yxy.y - yxy.z) * (luminance / yxy.z);
+    mat3 xyz_to_rgb = mat3(3.2406, -0.9689, 0.0557, -1.5372, 1.8758, -0.2040,
+                           -0.4986, 0.0415, 1.0570);
+    return max(xyz_to_rgb * xyz, vec3(0.0));
+}
+
+// Which face of a cubemap a direction points at, and where on it, like
+// OpenGL cubemaps
+vec2 cubemap_uv(vec3 direction, out uint face) {
+    vec3 a = abs(direction);
+    vec2 st;
+    float major;
+
+    if (a.x >= a.y && a.x >= a.z) {
+        face = direction.x > 0.0 ? 0 : 1;
+        major = a.x;
+        st = vec2(direction.x > 0.0 ? -direction.z : direction.z, -direction.y);
+    } else if (a.y >= a.z) {
+        face = direction.y > 0.0 ? 2 : 3;
+        major = a.y;
+        st = vec2(direction.x, direction.y > 0.0 ? direction.z : -direction.z);
+    } else {
+        face = direction.z > 0.0 ? 4 : 5;
+        major = a.z;
+        st = vec2(direction.z > 0.0 ? direction.x : -direction.x,
+                  -direction.y);
+    }
+
+    return 0.5 * ((st / major) + 1.0);
+}
+
+// The light coming from the sky in a direction
+vec3 sample_sky(vec3 direction) {
+    // turning the sky one way is the same as turning the direction the other
+    float s = sin(sky.rotation);
+    float c = cos(sky.rotation);
+    vec3 d = vec3((c * direction.x) - (s * direction.z), direction.y,
+                  (s * direction.x) + (c * direction.z));
+
+    vec3 radiance;
+    if (sky.sky_type == SKY_EQUIRECT) {
+        vec2 uv = vec2(0.5 + (atan(d.x, d.z) / TAU),
+                       acos(clamp(d.y, -1.0, 1.0)) / PI);
+        radiance = textureLod(textures[sky.tex_ids[0]], uv, 0.0).xyz;
+    } else if (sky.sky_type == SKY_CUBEMAP) {
+        uint face;
+        vec2 uv = cubemap_uv(d, face);
+        uint tex_id = sky.tex_ids[face];
+
+        // the sampler may repeat, so keep away from the edges to stop the
+        // other side of the face bleeding in
+        vec2 half_texel = 0.5 / vec2(textureSize(textures[tex_id], 0));
+        uv = clamp(uv, half_texel, 1.0 - half_texel);
+        radiance = textureLod(textures[tex_id], uv, 0.0).xyz;
+    } else if (sky.sky_type == SKY_PROCEDURAL) {
+        // not rotated, so it stays lined up with the sun's light
+        radiance = procedural_sky(direction, -sky.sun_direction, sky.turbidity);
+    } else {
+        radiance = sky.colour;
+    }
+
+    return radiance * sky.intensity;
+}
+
+// Skies are importance sampled with a piecewise constant distribution on a
+// SKY_CDF_WIDTH by SKY_CDF_HEIGHT latitude/longitude grid, built on the CPU
+// by sky.rs. sky_cdf holds the cdf over the rows, then the cdf within each
+// row, each starting at 0.0 and ending at 1.0
+
+uint sky_row_start(uint row) {
+    return (SKY_CDF_HEIGHT + 1) + (row * (SKY_CDF_WIDTH + 1));
+}
+
+// Which of the count intervals of the cdf at start x falls in, and how far
+// through it
+uint sample_cdf(uint start, uint count, float x, out float t) {
+    // the last value that's no more than x, skipping over empty intervals
+    uint lo = 0;
+    uint hi = count;
+    while (lo + 1 < hi) {
+        uint mid = (lo + hi) / 2;
+        if (sky_cdf[start + mid] <= x) {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+
+    float width = sky_cdf[start + lo + 1] - sky_cdf[start + lo];
+    t = width > 0.0 ? clamp((x - sky_cdf[start + lo]) / width, 0.0, 1.0) : 0.0;
+    return lo;
+}
+
+float sky_cell_pdf(uint row, uint col, float v) {
+    float sin_theta = sin(v * PI);
+    if (sin_theta <= 0.0) return 0.0;
+
+    float p_row = sky_cdf[row + 1] - sky_cdf[row];
+    uint c = sky_row_start(row) + col;
+    float p_col = sky_cdf[c + 1] - sky_cdf[c];
+
+    // from the uv square to the sphere
+    return p_row * p_col * float(SKY_CDF_WIDTH * SKY_CDF_HEIGHT) /
+           (2.0 * PI * PI * sin_theta);
+}
+
+// Picks a direction in the sky with xi (0.0..1.0), brighter ones more often.
+// Also gives its pdf over solid angle
+vec3 sample_sky_direction(vec2 xi, out float pdf) {
+    float dv;
+    float du;
+    uint row = sample_cdf(0, SKY_CDF_HEIGHT, xi.y, dv);
+    uint col = sample_cdf(sky_row_start(row), SKY_CDF_WIDTH, xi.x, du);
+
+    vec2 uv = vec2((col + du) / SKY_CDF_WIDTH, (row + dv) / SKY_CDF_HEIGHT);
+    pdf = sky_cell_pdf(row, col, uv.y);
+
+    float phi = (uv.x - 0.5) * TAU;
+    float sin_theta = sin(uv.y * PI);
+    return vec3(sin_theta * sin(phi), cos(uv.y * PI), sin_theta * cos(phi));
+}
+
+// The pdf over solid angle of sample_sky_direction picking a direction
+float sky_pdf(vec3 direction) {
+    vec2 uv = vec2(0.5 + (atan(direction.x, direction.z) / TAU),
+                   acos(clamp(direction.y, -1.0, 1.0)) / PI);
+    uint row = min(uint(uv.y * SKY_CDF_HEIGHT), uint(SKY_CDF_HEIGHT - 1));
+    uint col = min(uint(uv.x * SKY_CDF_WIDTH), uint(SKY_CDF_WIDTH - 1));
+    return sky_cell_pdf(row, col, uv.y);
+}
@@ -4,16 +4,36 @@ struct Ray {
     vec3 direction;
 };
 
+// The cone of directions a ray stands for, which widens with distance and
+// picks the mip level of the textures it hits (Akenine-Möller et al.)
+struct RayCone {
+    float width;   // at the ray's origin
+    float spread;  // the angle it widens by
+};
+
+// Metallic/roughness material, base_colour tints the texture. Objects refer
+// to them by their index in the material buffer. The other maps are
+// NO_TEXTURE when the material doesn't have them
 struct Material {
     uint tex_id;
     vec2 tex_scale;
 
-    float ambient;
-    float diffuse;
-    float specular;
-    float shininess;
-    float reflectivity;
-    float emissive;
+    vec3 base_colour;
+    float metallic;
+    vec3 emissive;
+    float roughness;
+    float ior;
+    float transmission;
+    vec3 absorption;
+
+    uint normal_tex_id;  // +y up the image
+    uint metallic_roughness_tex_id;  // roughness in green, metallic in blue
+    uint emissive_tex_id;
+    uint occlusion_tex_id;  // in red
+
+    // rays go through where the texture's alpha is below this, 0.0 for none.
+    // Only meshes and planes are cut out
+    float alpha_cutoff;
 };
 
 struct HitInfo {
@@ -24,10 +44,12 @@ struct HitInfo {
     vec3 radiosity;
 };
 
+// Centred on the origin of its object space
 struct Sphere {
-    vec3 position;
+    mat4 model;
+    mat4 inv_model;
     float radius;
-    Material mat;
+    uint mat_id;
 };
 
 struct Plane {
@@ -36,32 +58,91 @@ struct Plane {
     vec3 tangent;
     float width;
     float height;
-    Material mat;
+    uint mat_id;
 };
 
 struct Vertex {
     vec3 position;
     vec3 normal;
     vec2 uv;
+    vec3 colour;   // tints the texture, white unless the mesh file has colours
+    vec4 tangent;  // w is the sign of the bitangent
 };
 
+// mat_id is the material from the mesh file, NO_MATERIAL if it has none
 struct Triangle {
     uint v1_idx;
     uint v2_idx;
     uint v3_idx;
+    uint mat_id;
+};
+
+// A leaf if count > 0, left_first is then the first triangle in it.
+// Otherwise left_first is the left child, and the right child is next to it
+struct BvhNode {
+    vec3 aabb_min;
+    uint left_first;
+    vec3 aabb_max;
+    uint count;
+};
+
+// An object in the top level BVH, obj_idx indexes the buffer for its type
+struct ObjectRef {
+    uint obj_type;
+    uint obj_idx;
+};
+
+// How many of each object and light there are, passed in the push constants.
+// Buffers can't be empty, so without any of a type its buffer keeps a
+// placeholder or an earlier frame's contents, and its length can't be used
+struct ObjectCounts {
+    uint spheres;
+    uint planes;
+    uint mesh_instances;
+    uint lights;
 };
 
 struct MeshInstance {
-    vec3 position;
+    mat4 model;
+    mat4 inv_model;
     uint start_triangle_idx;
     uint start_vertex_idx;
     uint num_triangles;
-    Material mat;
+    uint start_node_idx;
+    uint mat_id;  // NO_MATERIAL to use the materials from the mesh file
 };
 
-struct PointLight {
+// Every type of light, see the LIGHT_ defines.
+// direction is the way directional and spot lights shine, and the normal of
+// rect lights. tangent and bitangent are the full edges of rect lights
+struct Light {
     vec3 position;
+    uint light_type;
+    vec3 direction;
+    float intensity;
+    vec3 colour;
+    float radius;
+    vec3 tangent;
+    float cos_inner;
+    vec3 bitangent;
+    float cos_outer;
+};
+
+// What rays that miss everything see, see the SKY_ defines. tex_ids are the
+// equirect texture, or the cubemap faces in the order +x, -x, +y, -y, +z, -z.
+// sun_direction is the way the sunlight travels, normalised on upload, and
+// rotation is in radians around the y axis, which doesn't turn the procedural
+// sky. lights_scene is whether the sky lights surfaces outside of path
+// tracing, where it always does
+struct Sky {
+    vec3 colour;
+    uint sky_type;
+    vec3 sun_direction;
+    float turbidity;
     float intensity;
+    float rotation;
+    uint tex_ids[6];
+    uint lights_scene;
 };
 
 struct Camera {
@@ -71,4 +152,5 @@ struct Camera {
 };
 
 const Material NULL_MAT =
-    Material(0, vec2(0.0, 0.0), 0.0, 0.0, 0.0, 0.0, 0.0, 0.0);
\ No newline at end of file
+    Material(0, vec2(0.0, 0.0), vec3(0.0), 0.0, vec3(0.0), 0.0, 1.0, 0.0,
+             vec3(0.0), NO_TEXTURE, NO_TEXTURE, NO_TEXTURE, NO_TEXTURE, 0.0);
\ No newline at end of file
@@ -1,7 +1,10 @@
 use std::cell::RefCell;
 use std::intrinsics::variant_count;
+use std::path::Path;
 use std::sync::Arc;
 
+use image::ImageResult;
+use log::info;
 use winit::{
     event::{
         DeviceEvent, ElementState, Event, KeyboardInput, ModifiersState, MouseButton, VirtualKeyCode, WindowEvent,
@@ -10,10 +13,13 @@ use winit::{
 };
 
 use crate::{
-    renderer::{CameraComponent, GPURenderer, TransformComponent},
-    scene::Scene,
+    renderer::{
+        CameraComponent, Frame, GPURenderer, Handle, Mesh, MeshError, RenderMode, Renderer, Texture, TextureError,
+        TransformComponent,
+    },
+    scene::{load_gltf, Scene, SceneFile, SceneFileError},
     vec3,
-    vk::VkBackend,
+    vk::{DeviceError, VkBackend},
     Vec3,
 };
 
@@ -21,10 +27,10 @@ const NUM_KEYS: usize = variant_count::<VirtualKeyCode>();
 //const NUM_MOUSE_BUTTON: usize = variant_count::<MouseButton>();
 
 //TODO: rename this
-pub struct Engine {
+pub struct Engine<R: Renderer = GPURenderer> {
     event_loop: Option<EventLoop<()>>,
-    backend:    Arc<RefCell<VkBackend>>,
-    renderer:   GPURenderer,
+    backend:    Option<Arc<RefCell<VkBackend>>>, // only needed to control the window
+    renderer:   R,
 
     keymap:         [bool; NUM_KEYS],
     modifiers:      ModifiersState,
@@ -35,21 +41,58 @@ pub struct Engine {
     mouse_captured: bool,
 }
 
-impl Engine {
+impl Engine<GPURenderer> {
     pub fn new(width: u32, height: u32) -> Self {
-        let event_loop = Some(EventLoop::new());
-        let backend = VkBackend::new(event_loop.as_ref().unwrap(), "LumenRay", width, height);
+        Self::with_device(width, height, false, None).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Creates an engine with no window, for rendering offscreen. It can't be `run`.
+    pub fn new_headless(width: u32, height: u32) -> Self {
+        Self::with_device(width, height, true, None).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Creates an engine on a specific device, by its index in the list of devices that gets logged
+    pub fn with_device(
+        width: u32, height: u32, headless: bool, device_index: Option<usize>,
+    ) -> Result<Self, DeviceError> {
+        let (event_loop, backend) = if headless {
+            (None, VkBackend::new_headless(width, height, device_index)?)
+        } else {
+            let event_loop = EventLoop::new();
+            let backend = VkBackend::new(&event_loop, "LumenRay", width, height, device_index)?;
+
+            let window = backend.window().unwrap();
+            window.set_cursor_grab(true).unwrap();
+            window.set_cursor_visible(false);
 
-        let window = backend.surface.window();
-        window.set_cursor_grab(true).unwrap();
-        window.set_cursor_visible(false);
+            (Some(event_loop), backend)
+        };
 
         let backend = Arc::new(RefCell::new(backend));
         let renderer = GPURenderer::new(backend.clone());
 
-        Self {
+        Ok(Self {
             event_loop,
-            backend,
+            backend: Some(backend),
+            renderer,
+
+            keymap: [false; NUM_KEYS],
+            modifiers: ModifiersState::empty(),
+            mouse_left: false,
+            mouse_right: false,
+            mouse_dx: 0.0,
+            mouse_dy: 0.0,
+            mouse_captured: !headless,
+        })
+    }
+}
+
+impl<R: Renderer + 'static> Engine<R> {
+    /// Creates a headless engine around any renderer. It can't be `run`.
+    pub fn with_renderer(renderer: R) -> Self {
+        Self {
+            event_loop: None,
+            backend: None,
             renderer,
 
             keymap: [false; NUM_KEYS],
@@ -58,18 +101,47 @@ impl Engine {
             mouse_right: false,
             mouse_dx: 0.0,
             mouse_dy: 0.0,
-            mouse_captured: true,
+            mouse_captured: false,
         }
     }
 
-    pub fn get_texture_by_path(&mut self, path: &str) -> u32 { self.renderer.get_texture_by_path(path) }
-    pub fn get_texture_by_colour(&mut self, colour: Vec3) -> u32 { self.renderer.get_texture_by_colour(colour) }
-    pub fn get_mesh_by_path(&mut self, path: &str) -> u32 { self.renderer.get_mesh_by_path(path) }
+    pub fn renderer(&self) -> &R { &self.renderer }
+    pub fn renderer_mut(&mut self) -> &mut R { &mut self.renderer }
+
+    pub fn get_texture_by_path(&mut self, path: &str) -> Result<Handle<Texture>, TextureError> {
+        self.renderer.get_texture_by_path(path)
+    }
+    pub fn get_texture_by_colour(&mut self, colour: Vec3) -> Handle<Texture> {
+        self.renderer.get_texture_by_colour(colour)
+    }
+    pub fn get_mesh_by_path(&mut self, path: &str) -> Result<Handle<Mesh>, MeshError> {
+        self.renderer.get_mesh_by_path(path)
+    }
+    pub fn get_texture_path(&self, tex_id: u32) -> Option<&str> { self.renderer.get_texture_path(tex_id) }
+    pub fn get_mesh_path(&self, mesh_id: u32) -> Option<&str> { self.renderer.get_mesh_path(mesh_id) }
+
+    /// Frees the textures, meshes and materials no longer used, like after a scene has been dropped for another
+    pub fn unload_unused(&mut self) { self.renderer.unload_unused() }
+
+    /// Loads a scene from a RON, TOML, JSON or glTF file, along with the textures and meshes it uses
+    pub fn load_scene(&mut self, path: &str) -> Result<Scene, SceneFileError> {
+        info!("Loading scene from \"{}\"", path);
+        let extension = Path::new(path).extension().and_then(|e| e.to_str()).unwrap_or_default();
+        match extension.to_ascii_lowercase().as_str() {
+            "gltf" | "glb" => load_gltf(path, &mut self.renderer),
+            _ => SceneFile::from_path(path)?.into_scene(&mut self.renderer),
+        }
+    }
+
+    /// Saves a scene to a RON, TOML or JSON file
+    pub fn save_scene(&self, scene: &Scene, path: &str) -> Result<(), SceneFileError> {
+        SceneFile::from_scene(scene, &self.renderer).save(path)
+    }
 
     pub fn run(mut self, mut scene: Scene) {
         let mut n: u32 = 0;
 
-        let event_loop = self.event_loop.take().unwrap();
+        let event_loop = self.event_loop.take().expect("A headless engine can't be run");
 
         event_loop.run(move |ev, _, control_flow| match ev {
             Event::WindowEvent {
@@ -79,6 +151,11 @@ impl Engine {
                 *control_flow = ControlFlow::Exit;
             }
 
+            Event::WindowEvent {
+                event: WindowEvent::Resized(size),
+                ..
+            } => self.renderer.resize(size.width, size.height),
+
             // keyboard input event
             Event::DeviceEvent {
                 event:
@@ -111,7 +188,22 @@ impl Engine {
         });
     }
 
-    fn render(&mut self, scene: &mut Scene) {
+    /// Draws a single frame of the scene from its first camera, then reads it back
+    pub fn render_frame(&mut self, scene: &mut Scene) -> Frame {
+        self.render(scene);
+        self.renderer.read_frame()
+    }
+
+    /// Renders the scene from its first camera at the engine's resolution, and saves it to an image file.
+    /// See `Frame::save` for the supported formats.
+    pub fn render_to_file(&mut self, scene: &mut Scene, path: &str) -> ImageResult<()> {
+        let frame = self.render_frame(scene);
+        info!("Saving frame to \"{}\"", path);
+        frame.save(path)
+    }
+
+    /// Draws a single frame of the scene from its first camera
+    pub fn render(&mut self, scene: &mut Scene) {
         //let frame_start = std::time::Instant::now();
 
         self.renderer.draw(scene);
@@ -132,7 +224,6 @@ impl Engine {
 
         let mut offset = vec3(0.0, 0.0, 0.0);
         let delta: f32 = if self.modifiers.shift() { 0.2 } else { 0.1 };
-        let backend = self.backend.borrow();
 
         if self.keymap[VirtualKeyCode::W as usize] {
             offset.z += delta;
@@ -148,17 +239,12 @@ impl Engine {
         }
         if self.keymap[VirtualKeyCode::Escape as usize] && self.mouse_captured {
             self.mouse_captured = false;
-            let window = backend.surface.window();
-            window.set_cursor_grab(false).unwrap();
-            window.set_cursor_visible(true);
+            self.set_cursor_grab(false);
         }
 
         if self.mouse_left && !self.mouse_captured {
             self.mouse_captured = true;
-
-            let window = backend.surface.window();
-            window.set_cursor_grab(true).unwrap();
-            window.set_cursor_visible(false);
+            self.set_cursor_grab(true);
         }
 
         let mut fov_add = 0.0;
@@ -181,13 +267,52 @@ impl Engine {
         self.mouse_dy = 0.0;
     }
 
+    /// Locks and hides the cursor in the window, or releases it
+    fn set_cursor_grab(&self, grab: bool) {
+        if let Some(backend) = &self.backend {
+            if let Some(window) = backend.borrow().window() {
+                window.set_cursor_grab(grab).unwrap();
+                window.set_cursor_visible(!grab);
+            }
+        }
+    }
+
+    /// Shows the number of samples so far in the window title while path tracing
+    fn update_title(&self) {
+        if let Some(backend) = &self.backend {
+            if let Some(window) = backend.borrow().window() {
+                let title = match self.renderer.render_mode() {
+                    RenderMode::Lightmap => "LumenRay".to_owned(),
+                    RenderMode::PathTraced => format!("LumenRay - {} samples", self.renderer.sample_count()),
+                };
+                window.set_title(&title);
+            }
+        }
+    }
+
+    fn toggle_render_mode(&mut self) {
+        let mode = match self.renderer.render_mode() {
+            RenderMode::Lightmap => RenderMode::PathTraced,
+            RenderMode::PathTraced => RenderMode::Lightmap,
+        };
+        info!("Switching to {:?} rendering", mode);
+        self.renderer.set_render_mode(mode);
+    }
+
     fn on_mouse_move(&mut self, delta: (f64, f64)) {
         self.mouse_dx += delta.0 as f32;
         self.mouse_dy += delta.1 as f32;
     }
 
     fn on_key_down(&mut self, key: VirtualKeyCode, state: ElementState) {
-        self.keymap[key as usize] = state == ElementState::Pressed;
+        let pressed = state == ElementState::Pressed;
+
+        // once per press, not every frame it's held down
+        if key == VirtualKeyCode::P && pressed && !self.keymap[key as usize] {
+            self.toggle_render_mode();
+        }
+
+        self.keymap[key as usize] = pressed;
     }
 
     fn on_modifiers_changed(&mut self, modifiers: ModifiersState) { self.modifiers = modifiers; }
@@ -205,5 +330,6 @@ impl Engine {
     fn on_window_update(&mut self, scene: &mut Scene, n: &mut u32) {
         self.update(scene, n);
         self.render(scene);
+        self.update_title();
     }
 }
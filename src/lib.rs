@@ -8,11 +8,13 @@ pub mod vk;
 
 pub use glam::mat3;
 pub use glam::mat4;
+pub use glam::quat;
 pub use glam::vec2;
 pub use glam::vec3;
 pub use glam::vec4;
 pub use glam::Mat3;
 pub use glam::Mat4;
+pub use glam::Quat;
 pub use glam::Vec2;
 pub use glam::Vec3;
 pub use glam::Vec3Swizzles;
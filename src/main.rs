@@ -1,155 +1,118 @@
+use clap::Parser;
+use log::{error, info};
 use lumen_ray::{
     engine::Engine,
-    renderer::{
-        srgb_to_linear, CameraComponent, MaterialComponent, MeshRenderComponent, PlaneRenderComponent,
-        PointLightComponent, SphereRenderComponent, TransformComponent,
-    },
-    rgb,
+    renderer::{CpuRenderer, GPURenderer, RenderMode, Renderer},
     scene::Scene,
-    soft_gray, soft_green, soft_red, soft_yellow, vec2, vec3, white, Vec3,
 };
 
 #[global_allocator]
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
-const WIDTH: u32 = 800;
-const HEIGHT: u32 = 600;
-
 // TODO: write tests and add codecov
 // TODO: add bench with default scene
 // TODO: replace unwraps with result propogation (`?`)
 
+/// A real time ray tracing engine
+#[derive(Parser)]
+#[clap(version)]
+struct Args {
+    /// Scene file to render (.ron, .toml, .json, .gltf or .glb)
+    #[clap(default_value = "assets/scenes/cornell.ron")]
+    scene: String,
+
+    #[clap(long, default_value_t = 800)]
+    width: u32,
+
+    #[clap(long, default_value_t = 600)]
+    height: u32,
+
+    /// Render without a window and save the result to this image file (.png, .exr, ...), then exit
+    #[clap(short, long)]
+    output: Option<String>,
+
+    /// Number of frames to render before saving the output
+    #[clap(short, long, default_value_t = 1)]
+    frames: u32,
+
+    /// Render on the CPU instead of the GPU. Requires --output
+    #[clap(long, requires = "output")]
+    cpu: bool,
+
+    /// Path trace instead of using lightmaps, --frames sets the samples per pixel
+    #[clap(long)]
+    path_trace: bool,
+
+    /// Index of the device to render on, as listed in the log. Defaults to the best suitable device
+    #[clap(short, long)]
+    device: Option<usize>,
+
+    /// Log level (off, error, warn, info, debug or trace)
+    #[clap(long, default_value = "debug")]
+    log_level: log::LevelFilter,
+}
+
 fn main() {
+    let args = Args::parse();
+
     let mut log_builder = env_logger::Builder::new();
-    log_builder.filter(None, log::LevelFilter::Debug).init();
-
-    let mut engine = Engine::new(WIDTH, HEIGHT);
-    let mut scene = Scene::empty();
-
-    scene.create_entity((
-        TransformComponent::with_pos(0.0, -1.0, 0.0),
-        MeshRenderComponent {
-            mesh_id: engine.get_mesh_by_path("assets/models/cube.obj"),
-        },
-        MaterialComponent {
-            tex_id: engine.get_texture_by_colour(soft_gray!()),
-            //tex_id: engine.get_texture_by_path("assets/textures/Floor128.bmp"),
-            //tex_id: engine.get_texture_by_path("assets/textures/Checkerboard.png"),
-            //tex_scale: vec2(4.0, 4.0),
-            ..MaterialComponent::basic()
-        },
-    ));
-    /* scene.create_entity((
-        TransformComponent::with_pos(0.0, 0.0, 0.0),
-        MeshRenderComponent {
-            mesh_id: engine.get_mesh_by_path("assets/models/monkey.obj"),
-        },
-        MaterialComponent {
-            tex_id: engine.get_texture_by_colour(soft_gray!()),
-            ..MaterialComponent::basic()
-        },
-    )); */
-
-    scene.create_entity((
-        TransformComponent::with_pos(-1.2, -1.0, 2.1),
-        SphereRenderComponent { radius: 1.0 },
-        MaterialComponent {
-            tex_id: engine.get_texture_by_colour(white!()),
-            ambient: 0.05,
-            diffuse: 0.03,
-            specular: 0.2,
-            shininess: 16.0,
-            reflectivity: 1.0,
-            emissive: 0.0,
-            ..Default::default()
-        },
-    ));
-    /*
-    scene.create_entity((
-        TransformComponent::with_pos(1.0, -1.0, 1.7),
-        SphereRenderComponent { radius: 1.0 },
-        MaterialComponent {
-            tex_id: engine.get_texture_by_colour(soft_yellow!()),
-            ambient: 0.1,
-            diffuse: 1.0,
-            specular: 0.9,
-            shininess: 32.0,
-            reflectivity: 0.25,
-            emissive: 0.0,
-            ..Default::default()
-        },
-    )); */
-
-    scene.create_entity((
-        TransformComponent::with_pos(0.0, -2.0, -0.5),
-        PlaneRenderComponent::new(vec3(0.0, 1.0, 0.0), 6.0, 11.0),
-        MaterialComponent {
-            tex_id: engine.get_texture_by_path("assets/textures/Floor128.bmp"),
-            tex_scale: vec2(0.4, 0.4) * vec2(12.0, 12.0),
-            reflectivity: 0.01,
-            ..MaterialComponent::basic()
-        },
-    ));
-
-    scene.create_entity((
-        TransformComponent::with_pos(-3.0, 1.0, -0.5),
-        PlaneRenderComponent::new(vec3(1.0, 0.0, 0.0), 6.0, 11.0),
-        MaterialComponent {
-            tex_id: engine.get_texture_by_colour(soft_red!()),
-            ..MaterialComponent::basic()
-        },
-    ));
-
-    scene.create_entity((
-        TransformComponent::with_pos(3.0, 1.0, -0.5),
-        PlaneRenderComponent::new(vec3(-1.0, 0.0, 0.0), 6.0, 11.0),
-        MaterialComponent {
-            tex_id: engine.get_texture_by_colour(soft_green!()),
-            ..MaterialComponent::basic()
-        },
-    ));
-
-    scene.create_entity((
-        TransformComponent::with_pos(0.0, 1.0, 5.0),
-        PlaneRenderComponent::new(vec3(0.0, 0.0, -1.0), 6.0, 6.0),
-        MaterialComponent {
-            tex_id: engine.get_texture_by_colour(soft_gray!()),
-            ..MaterialComponent::basic()
-        },
-    ));
-    scene.create_entity((
-        TransformComponent::with_pos(0.0, 1.0, -6.0),
-        PlaneRenderComponent::new(vec3(0.0, 0.0, 1.0), 6.0, 6.0),
-        MaterialComponent {
-            tex_id: engine.get_texture_by_colour(soft_gray!()),
-            ..MaterialComponent::basic()
-        },
-    ));
-
-    scene.create_entity((
-        TransformComponent::with_pos(0.0, 4.0, -0.5),
-        PlaneRenderComponent::new(vec3(0.0, -1.0, 0.0), 6.0, 11.0),
-        MaterialComponent {
-            tex_id: engine.get_texture_by_colour(soft_gray!()),
-            ..MaterialComponent::basic()
-        },
-    ));
-
-    scene.create_entity((
-        //TransformComponent::with_pos(2.0, 1.0, 2.5),
-        TransformComponent::with_pos(2.0, -1.0, 3.5),
-        //TransformComponent::with_pos(0.0, 3.0, -2.5),
-        PointLightComponent { intensity: 4.0 },
-    ));
-
-    scene.create_entity((
-        TransformComponent::with_pos(0.0, 1.5, -6.5),
-        CameraComponent {
-            pitch: 0.0,
-            yaw:   0.0,
-            fov:   90.0,
-        },
-    ));
-
-    engine.run(scene);
+    log_builder.filter(None, args.log_level).init();
+
+    if args.cpu {
+        let engine = Engine::with_renderer(CpuRenderer::new(args.width, args.height));
+        render_to_file(engine, &args);
+    } else if args.output.is_some() {
+        let engine = create_engine(&args, true);
+        render_to_file(engine, &args);
+    } else {
+        let mut engine = create_engine(&args, false);
+        engine.renderer_mut().set_render_mode(render_mode(&args));
+        let scene = load_scene(&mut engine, &args.scene);
+        engine.run(scene);
+    }
+}
+
+fn create_engine(args: &Args, headless: bool) -> Engine<GPURenderer> {
+    match Engine::with_device(args.width, args.height, headless, args.device) {
+        Ok(engine) => engine,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn load_scene<R: Renderer + 'static>(engine: &mut Engine<R>, path: &str) -> Scene {
+    match engine.load_scene(path) {
+        Ok(scene) => scene,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn render_mode(args: &Args) -> RenderMode {
+    if args.path_trace {
+        RenderMode::PathTraced
+    } else {
+        RenderMode::Lightmap
+    }
+}
+
+/// Renders the requested number of frames offscreen, and saves the last one
+fn render_to_file<R: Renderer + 'static>(mut engine: Engine<R>, args: &Args) {
+    let output = args.output.as_ref().unwrap();
+    engine.renderer_mut().set_render_mode(render_mode(args));
+    let mut scene = load_scene(&mut engine, &args.scene);
+
+    for _ in 1..args.frames {
+        engine.render(&mut scene);
+    }
+    info!("Rendered {} frames", args.frames.max(1));
+
+    if let Err(e) = engine.render_to_file(&mut scene, output) {
+        error!("Couldn't save \"{}\": {}", output, e);
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,230 @@
+// Textures, meshes and materials held by a renderer, handed out as reference counted handles.
+//
+// Assets live in slots, and their ids are the slot index, which is what materials, shaders and the GPU buffers refer
+// to. A slot is only freed by `AssetStore::unload_unused` once nothing has a handle to it, and is then reused by the
+// next asset added.
+
+use std::{collections::HashMap, fmt, hash::Hash, marker::PhantomData, ops::Index, sync::Arc};
+
+use super::{Material, Texture};
+
+/// Textures are keyed by their path and whether they were loaded as data, as one file can be loaded as both
+pub(super) type TextureStore = AssetStore<Texture, (String, bool)>;
+/// Materials are keyed by their contents, so identical ones share an id
+pub(super) type MaterialStore = AssetStore<Material, MaterialKey>;
+
+/// The bits of every field of a material, as floats can't be hashed
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub(super) struct MaterialKey(Vec<u32>);
+
+impl From<&Material> for MaterialKey {
+    fn from(m: &Material) -> Self {
+        let maps = [
+            m.normal_tex_id,
+            m.metallic_roughness_tex_id,
+            m.emissive_tex_id,
+            m.occlusion_tex_id,
+        ];
+        let floats = m
+            .tex_scale
+            .to_array()
+            .into_iter()
+            .chain(m.base_colour.to_array())
+            .chain([m.metallic, m.roughness])
+            .chain(m.emissive.to_array())
+            .chain([m.ior, m.transmission])
+            .chain(m.absorption.to_array())
+            // last, so having no cutoff gives a shorter key
+            .chain(m.alpha_cutoff);
+
+        // texture ids never get near u32::MAX, so it can stand for no map
+        Self(
+            std::iter::once(m.tex_id)
+                .chain(maps.into_iter().map(|id| id.unwrap_or(u32::MAX)))
+                .chain(floats.map(f32::to_bits))
+                .collect(),
+        )
+    }
+}
+
+/// A reference to an asset held by a renderer, which keeps it loaded. Cloning it adds another reference.
+///
+/// Assets are only unloaded by `Renderer::unload_unused`, so the id of one can still be used for a moment after its
+/// handle is dropped, like when making a material from it.
+pub struct Handle<T> {
+    id:      u32,
+    refs:    Arc<()>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Handle<T> {
+    /// The id the asset is stored under, which materials and the renderers use
+    pub fn id(&self) -> u32 { self.id }
+}
+
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        Self {
+            id:      self.id,
+            refs:    self.refs.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T> PartialEq for Handle<T> {
+    fn eq(&self, other: &Self) -> bool { self.id == other.id }
+}
+
+impl<T> Eq for Handle<T> {}
+
+impl<T> Hash for Handle<T> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) { self.id.hash(state) }
+}
+
+impl<T> fmt::Debug for Handle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { f.debug_tuple("Handle").field(&self.id).finish() }
+}
+
+struct Slot<T, K> {
+    asset: T,
+    /// The path it was loaded from, or the name it was added with
+    name:  String,
+    /// What `AssetStore::find` looks it up by, `None` for assets that were made in memory
+    key:   Option<K>,
+    /// Shared with every handle, so it's only referenced here once they're all gone
+    refs:  Arc<()>,
+}
+
+/// Assets of one type, looked up by id or by a key `K`, like their path
+pub struct AssetStore<T, K = String> {
+    slots:  Vec<Option<Slot<T, K>>>,
+    /// Unloaded slots, which are filled again before the store grows
+    free:   Vec<u32>,
+    by_key: HashMap<K, u32>,
+}
+
+impl<T, K: Eq + Hash + Clone> AssetStore<T, K> {
+    pub fn new() -> Self {
+        Self {
+            slots:  vec![],
+            free:   vec![],
+            by_key: HashMap::new(),
+        }
+    }
+
+    /// A handle to the asset with this key, if one's loaded
+    pub fn find(&self, key: &K) -> Option<Handle<T>> { self.by_key.get(key).and_then(|&id| self.handle(id)) }
+
+    /// Another handle to a loaded asset
+    pub fn handle(&self, id: u32) -> Option<Handle<T>> {
+        let slot = self.slots.get(id as usize)?.as_ref()?;
+        Some(Handle {
+            id,
+            refs: slot.refs.clone(),
+            _marker: PhantomData,
+        })
+    }
+
+    /// Adds an asset, in the first free slot. Keyed assets replace any other with the same key in `find`
+    pub fn insert(&mut self, name: &str, key: Option<K>, asset: T) -> Handle<T> {
+        let id = self.free.pop().unwrap_or(self.slots.len() as u32);
+        if let Some(key) = &key {
+            self.by_key.insert(key.clone(), id);
+        }
+
+        let slot = Slot {
+            asset,
+            name: name.to_owned(),
+            key,
+            refs: Arc::new(()),
+        };
+        let handle = Handle {
+            id,
+            refs: slot.refs.clone(),
+            _marker: PhantomData,
+        };
+
+        match self.slots.get_mut(id as usize) {
+            Some(free) => *free = Some(slot),
+            None => self.slots.push(Some(slot)),
+        }
+        handle
+    }
+
+    /// Changes what `find` looks an asset up by, like after it's been changed so the old key no longer fits
+    pub fn set_key(&mut self, id: u32, key: Option<K>) {
+        let slot = match self.slots.get_mut(id as usize).and_then(Option::as_mut) {
+            Some(slot) => slot,
+            None => return,
+        };
+        if let Some(old) = std::mem::replace(&mut slot.key, key.clone()) {
+            if self.by_key.get(&old) == Some(&id) {
+                self.by_key.remove(&old);
+            }
+        }
+        if let Some(key) = key {
+            self.by_key.insert(key, id);
+        }
+    }
+
+    /// Swaps in a new asset in place of the one the handle refers to, giving back the old one
+    pub fn replace(&mut self, handle: &Handle<T>, asset: T) -> T {
+        let slot = self.slots[handle.id as usize]
+            .as_mut()
+            .expect("handles keep their assets loaded");
+        std::mem::replace(&mut slot.asset, asset)
+    }
+
+    /// Frees every asset that nothing has a handle to, giving back their ids
+    pub fn unload_unused(&mut self) -> Vec<u32> {
+        let mut unloaded = vec![];
+        for (id, slot) in self.slots.iter_mut().enumerate() {
+            if slot.as_ref().is_some_and(|s| Arc::strong_count(&s.refs) == 1) {
+                // Another asset may have been added under the same key since
+                if let Some(key) = slot.take().and_then(|s| s.key) {
+                    if self.by_key.get(&key) == Some(&(id as u32)) {
+                        self.by_key.remove(&key);
+                    }
+                }
+                unloaded.push(id as u32);
+            }
+        }
+
+        self.free.extend(unloaded.iter().rev());
+        unloaded
+    }
+
+    pub fn get(&self, id: u32) -> Option<&T> { self.slot(id).map(|s| &s.asset) }
+
+    pub fn get_mut(&mut self, id: u32) -> Option<&mut T> {
+        self.slots.get_mut(id as usize)?.as_mut().map(|s| &mut s.asset)
+    }
+
+    /// The path an asset was loaded from, or the name it was added with
+    pub fn name(&self, id: u32) -> Option<&str> { self.slot(id).map(|s| s.name.as_str()) }
+
+    /// The loaded assets and their ids, in id order
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &T)> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter_map(|(id, slot)| slot.as_ref().map(|s| (id as u32, &s.asset)))
+    }
+
+    /// One more than the highest id, loaded or not, which is how many slots arrays indexed by id need
+    pub fn id_count(&self) -> u32 { self.slots.len() as u32 }
+
+    fn slot(&self, id: u32) -> Option<&Slot<T, K>> { self.slots.get(id as usize)?.as_ref() }
+}
+
+impl<T, K: Eq + Hash + Clone> Default for AssetStore<T, K> {
+    fn default() -> Self { Self::new() }
+}
+
+impl<T, K: Eq + Hash + Clone> Index<u32> for AssetStore<T, K> {
+    type Output = T;
+
+    /// Panics if the asset has been unloaded
+    fn index(&self, id: u32) -> &T { self.get(id).expect("asset has been unloaded") }
+}
@@ -0,0 +1,486 @@
+// Bounding volume hierarchy, built on the CPU with the surface area heuristic (SAH).
+// The nodes are laid out flat so they can be uploaded to the GPU as they are,
+// and traversed with a stack in intersection.glsl.
+
+use crate::{Mat4, Vec3};
+
+/// Leaves are never split below this many primitives
+const MIN_SPLIT_SIZE: usize = 2;
+/// Leaves bigger than this are always split, even if the SAH says not to
+const MAX_LEAF_SIZE: usize = 8;
+const NUM_BINS: usize = 12;
+/// Estimated cost of traversing a node, relative to intersecting one primitive
+const TRAVERSAL_COST: f32 = 1.0;
+/// Nodes this deep are always leaves, which bounds the traversal stack
+const MAX_DEPTH: usize = 31;
+/// Each inner node on the way down leaves at most one child on the stack, plus the two it pushes.
+/// Same as `BVH_STACK_SIZE` in intersection.glsl
+const STACK_SIZE: usize = MAX_DEPTH + 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub const EMPTY: Self = Self {
+        min: Vec3::splat(f32::MAX),
+        max: Vec3::splat(f32::MIN),
+    };
+
+    pub fn from_points(points: &[Vec3]) -> Self { points.iter().fold(Self::EMPTY, |aabb, p| aabb.grow(*p)) }
+
+    pub fn from_sphere(position: Vec3, radius: f32) -> Self {
+        Self {
+            min: position - radius,
+            max: position + radius,
+        }
+    }
+
+    /// Bounds of a rectangle centred on `position`. It is given a little thickness so rays along it still hit.
+    pub fn from_rect(position: Vec3, normal: Vec3, tangent: Vec3, width: f32, height: f32) -> Self {
+        let half_width = 0.5 * width * tangent;
+        let half_height = 0.5 * height * normal.cross(tangent);
+
+        Self::from_points(&[
+            position - half_width - half_height,
+            position + half_width - half_height,
+            position - half_width + half_height,
+            position + half_width + half_height,
+        ])
+        .inflate(1e-3)
+    }
+
+    pub fn grow(self, p: Vec3) -> Self {
+        Self {
+            min: self.min.min(p),
+            max: self.max.max(p),
+        }
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        Self {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    pub fn translate(self, offset: Vec3) -> Self {
+        Self {
+            min: self.min + offset,
+            max: self.max + offset,
+        }
+    }
+
+    /// Bounds of the box after it's been transformed, found from its eight corners
+    pub fn transform(self, matrix: Mat4) -> Self {
+        if self.is_empty() {
+            return self;
+        }
+
+        let corners = (0..8)
+            .map(|i| {
+                let corner = Vec3::new(
+                    if i & 1 == 0 { self.min.x } else { self.max.x },
+                    if i & 2 == 0 { self.min.y } else { self.max.y },
+                    if i & 4 == 0 { self.min.z } else { self.max.z },
+                );
+                matrix.transform_point3(corner)
+            })
+            .collect::<Vec<_>>();
+
+        Self::from_points(&corners)
+    }
+
+    pub fn inflate(self, amount: f32) -> Self {
+        Self {
+            min: self.min - amount,
+            max: self.max + amount,
+        }
+    }
+
+    pub fn centroid(&self) -> Vec3 { (self.min + self.max) * 0.5 }
+
+    pub fn is_empty(&self) -> bool { self.min.cmpgt(self.max).any() }
+
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let e = self.max - self.min;
+        2.0 * (e.x * e.y + e.y * e.z + e.z * e.x)
+    }
+
+    /// Distance along the ray to where it enters the box, or f32::MAX if it misses.
+    /// Same as `ray_aabb_intersect` in intersection.glsl
+    pub fn ray_distance(&self, origin: Vec3, inv_dir: Vec3) -> f32 {
+        let t0 = (self.min - origin) * inv_dir;
+        let t1 = (self.max - origin) * inv_dir;
+        let near = t0.min(t1).max_element();
+        let far = t0.max(t1).min_element();
+
+        if far >= near && far >= 0.0 {
+            near.max(0.0)
+        } else {
+            f32::MAX
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BvhNode {
+    pub aabb:       Aabb,
+    /// Index of the left child for inner nodes (the right child follows it), or of the first primitive for leaves
+    pub left_first: u32,
+    /// Number of primitives in a leaf, 0 for inner nodes
+    pub count:      u32,
+}
+
+impl BvhNode {
+    pub fn is_leaf(&self) -> bool { self.count > 0 }
+}
+
+#[derive(Debug, Default)]
+pub struct Bvh {
+    pub nodes: Vec<BvhNode>,
+}
+
+impl Bvh {
+    /// Builds a BVH over primitives with the given bounds.
+    /// Also returns the order the primitives need to be rearranged into, as leaves refer to contiguous ranges.
+    /// With no primitives there are no nodes either.
+    pub fn build(bounds: &[Aabb]) -> (Self, Vec<u32>) {
+        if bounds.is_empty() {
+            return (Self::default(), vec![]);
+        }
+
+        let mut builder = BvhBuilder {
+            bounds,
+            centroids: bounds.iter().map(Aabb::centroid).collect(),
+            indices: (0..bounds.len() as u32).collect(),
+            nodes: Vec::with_capacity(bounds.len() * 2),
+        };
+
+        builder.nodes.push(BvhNode {
+            aabb:       Aabb::EMPTY,
+            left_first: 0,
+            count:      bounds.len() as u32,
+        });
+        builder.update_bounds(0);
+        builder.subdivide(0, 0);
+
+        (Self { nodes: builder.nodes }, builder.indices)
+    }
+
+    pub fn len(&self) -> u32 { self.nodes.len() as u32 }
+    pub fn is_empty(&self) -> bool { self.nodes.is_empty() }
+
+    pub fn bounds(&self) -> Aabb { self.nodes.first().map_or(Aabb::EMPTY, |n| n.aabb) }
+
+    /// Walks the leaves the ray passes through, nearest first, ignoring anything further than `max_dist`.
+    /// `visit` is given the range of primitives in each leaf and returns the new `max_dist`.
+    /// Boxes are grown by `inflate`, to also find primitives that pass close to the ray.
+    pub fn traverse(
+        &self, origin: Vec3, direction: Vec3, inflate: f32, mut max_dist: f32,
+        mut visit: impl FnMut(std::ops::Range<usize>, f32) -> f32,
+    ) {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let inv_dir = direction.recip();
+        let node_distance = |idx: usize| self.nodes[idx].aabb.inflate(inflate).ray_distance(origin, inv_dir);
+
+        let mut stack = [0usize; STACK_SIZE];
+        let mut stack_ptr = 1;
+
+        while stack_ptr > 0 {
+            stack_ptr -= 1;
+            let idx = stack[stack_ptr];
+            let node = &self.nodes[idx];
+
+            if node_distance(idx) >= max_dist {
+                continue;
+            }
+
+            if node.is_leaf() {
+                let first = node.left_first as usize;
+                max_dist = visit(first..first + node.count as usize, max_dist);
+            } else {
+                // push the far child first, so the near one is visited first
+                let (mut near, mut far) = (node.left_first as usize, node.left_first as usize + 1);
+                let (mut near_dist, mut far_dist) = (node_distance(near), node_distance(far));
+                if near_dist > far_dist {
+                    std::mem::swap(&mut near, &mut far);
+                    std::mem::swap(&mut near_dist, &mut far_dist);
+                }
+
+                debug_assert!(stack_ptr + 2 <= STACK_SIZE, "BVH is deeper than the builder allows");
+                if far_dist < max_dist {
+                    stack[stack_ptr] = far;
+                    stack_ptr += 1;
+                }
+                if near_dist < max_dist {
+                    stack[stack_ptr] = near;
+                    stack_ptr += 1;
+                }
+            }
+        }
+    }
+}
+
+struct BvhBuilder<'a> {
+    bounds:    &'a [Aabb],
+    centroids: Vec<Vec3>,
+    indices:   Vec<u32>,
+    nodes:     Vec<BvhNode>,
+}
+
+impl BvhBuilder<'_> {
+    fn primitives(&self, node_idx: usize) -> &[u32] {
+        let node = &self.nodes[node_idx];
+        let first = node.left_first as usize;
+        &self.indices[first..first + node.count as usize]
+    }
+
+    fn update_bounds(&mut self, node_idx: usize) {
+        let aabb = self
+            .primitives(node_idx)
+            .iter()
+            .fold(Aabb::EMPTY, |aabb, &i| aabb.union(self.bounds[i as usize]));
+        self.nodes[node_idx].aabb = aabb;
+    }
+
+    fn subdivide(&mut self, node_idx: usize, depth: usize) {
+        let node = self.nodes[node_idx];
+        let count = node.count as usize;
+        if count <= MIN_SPLIT_SIZE || depth == MAX_DEPTH {
+            return;
+        }
+
+        let first = node.left_first as usize;
+        let left_count = match self.find_sah_split(node_idx) {
+            Some((axis, split_pos, cost)) => {
+                // splitting has to be cheaper than intersecting everything in this node
+                let leaf_cost = count as f32 * node.aabb.surface_area();
+                if cost >= leaf_cost && count <= MAX_LEAF_SIZE {
+                    return;
+                }
+                self.partition(first, count, |c| c[axis] < split_pos)
+            }
+            None => 0,
+        };
+
+        // the SAH couldn't separate the primitives (e.g. they share a centroid), so just halve them
+        let left_count = if left_count == 0 || left_count == count {
+            if count <= MAX_LEAF_SIZE {
+                return;
+            }
+            self.split_median(first, count);
+            count / 2
+        } else {
+            left_count
+        };
+
+        let left_idx = self.nodes.len();
+        self.nodes.push(BvhNode {
+            aabb:       Aabb::EMPTY,
+            left_first: first as u32,
+            count:      left_count as u32,
+        });
+        self.nodes.push(BvhNode {
+            aabb:       Aabb::EMPTY,
+            left_first: (first + left_count) as u32,
+            count:      (count - left_count) as u32,
+        });
+        self.nodes[node_idx].left_first = left_idx as u32;
+        self.nodes[node_idx].count = 0;
+
+        self.update_bounds(left_idx);
+        self.update_bounds(left_idx + 1);
+        self.subdivide(left_idx, depth + 1);
+        self.subdivide(left_idx + 1, depth + 1);
+    }
+
+    /// Bins the centroids along each axis and finds the split with the lowest SAH cost.
+    /// Returns the axis, the position of the split along it, and the cost.
+    fn find_sah_split(&self, node_idx: usize) -> Option<(usize, f32, f32)> {
+        let primitives = self.primitives(node_idx);
+        let centroid_bounds = Aabb::from_points(
+            &primitives
+                .iter()
+                .map(|&i| self.centroids[i as usize])
+                .collect::<Vec<_>>(),
+        );
+
+        let mut best = None;
+        let mut best_cost = f32::MAX;
+
+        for axis in 0..3 {
+            let (min, max) = (centroid_bounds.min[axis], centroid_bounds.max[axis]);
+            if max <= min {
+                continue;
+            }
+
+            let mut bin_bounds = [Aabb::EMPTY; NUM_BINS];
+            let mut bin_counts = [0usize; NUM_BINS];
+            let scale = NUM_BINS as f32 / (max - min);
+
+            for &i in primitives {
+                let bin = (((self.centroids[i as usize][axis] - min) * scale) as usize).min(NUM_BINS - 1);
+                bin_bounds[bin] = bin_bounds[bin].union(self.bounds[i as usize]);
+                bin_counts[bin] += 1;
+            }
+
+            // sweep from both sides to get the area and count on either side of each split plane
+            let mut left_areas = [0.0; NUM_BINS - 1];
+            let mut left_counts = [0; NUM_BINS - 1];
+            let mut right_areas = [0.0; NUM_BINS - 1];
+            let mut right_counts = [0; NUM_BINS - 1];
+            let (mut left_box, mut right_box) = (Aabb::EMPTY, Aabb::EMPTY);
+            let (mut left_sum, mut right_sum) = (0, 0);
+
+            for i in 0..NUM_BINS - 1 {
+                left_sum += bin_counts[i];
+                left_box = left_box.union(bin_bounds[i]);
+                left_counts[i] = left_sum;
+                left_areas[i] = left_box.surface_area();
+
+                right_sum += bin_counts[NUM_BINS - 1 - i];
+                right_box = right_box.union(bin_bounds[NUM_BINS - 1 - i]);
+                right_counts[NUM_BINS - 2 - i] = right_sum;
+                right_areas[NUM_BINS - 2 - i] = right_box.surface_area();
+            }
+
+            for i in 0..NUM_BINS - 1 {
+                let cost =
+                    TRAVERSAL_COST + left_counts[i] as f32 * left_areas[i] + right_counts[i] as f32 * right_areas[i];
+                if cost < best_cost {
+                    best_cost = cost;
+                    best = Some((axis, min + (i + 1) as f32 / scale));
+                }
+            }
+        }
+
+        best.map(|(axis, split_pos)| (axis, split_pos, best_cost))
+    }
+
+    /// Moves the primitives whose centroid passes the predicate to the start of the range.
+    /// Returns how many there were.
+    fn partition(&mut self, first: usize, count: usize, is_left: impl Fn(Vec3) -> bool) -> usize {
+        let mut i = first;
+        let mut j = first + count;
+        while i < j {
+            if is_left(self.centroids[self.indices[i] as usize]) {
+                i += 1;
+            } else {
+                j -= 1;
+                self.indices.swap(i, j);
+            }
+        }
+        i - first
+    }
+
+    /// Sorts the range along the longest axis of its centroids, so it can be split in half
+    fn split_median(&mut self, first: usize, count: usize) {
+        let centroids = &self.centroids;
+        let range = &mut self.indices[first..first + count];
+        let extent = Aabb::from_points(&range.iter().map(|&i| centroids[i as usize]).collect::<Vec<_>>());
+        let size = extent.max - extent.min;
+        let axis = if size.x > size.y && size.x > size.z {
+            0
+        } else if size.y > size.z {
+            1
+        } else {
+            2
+        };
+
+        range.sort_unstable_by(|&a, &b| centroids[a as usize][axis].total_cmp(&centroids[b as usize][axis]));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit_box(centre: Vec3) -> Aabb { Aabb::from_sphere(centre, 0.5) }
+
+    /// The primitives `traverse` visits for a ray, without culling by distance
+    fn visited(bvh: &Bvh, origin: Vec3, direction: Vec3) -> Vec<usize> {
+        let mut visited = vec![];
+        bvh.traverse(origin, direction, 0.0, f32::MAX, |range, max_dist| {
+            visited.extend(range);
+            max_dist
+        });
+        visited
+    }
+
+    fn depth(bvh: &Bvh, idx: usize) -> usize {
+        let node = &bvh.nodes[idx];
+        if node.is_leaf() {
+            0
+        } else {
+            let left = node.left_first as usize;
+            1 + depth(bvh, left).max(depth(bvh, left + 1))
+        }
+    }
+
+    #[test]
+    fn empty() {
+        let (bvh, order) = Bvh::build(&[]);
+        assert!(bvh.is_empty());
+        assert!(order.is_empty());
+        assert!(bvh.bounds().is_empty());
+        assert!(visited(&bvh, Vec3::ZERO, Vec3::Z).is_empty());
+    }
+
+    #[test]
+    fn single_object() {
+        let (bvh, order) = Bvh::build(&[unit_box(Vec3::new(0.0, 0.0, 5.0))]);
+        assert_eq!(bvh.len(), 1);
+        assert!(bvh.nodes[0].is_leaf());
+        assert_eq!(order, vec![0]);
+        assert_eq!(visited(&bvh, Vec3::ZERO, Vec3::Z), vec![0]);
+        assert!(visited(&bvh, Vec3::ZERO, -Vec3::Z).is_empty());
+    }
+
+    #[test]
+    fn visits_everything_along_the_ray() {
+        let bounds = (0..100)
+            .map(|i| unit_box(Vec3::new((i % 10) as f32 * 2.0, (i / 10) as f32 * 2.0, 0.0)))
+            .collect::<Vec<_>>();
+        let (bvh, order) = Bvh::build(&bounds);
+
+        let mut sorted = order.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..100).collect::<Vec<_>>());
+
+        // a ray along the bottom row passes through the first ten boxes
+        let mut hits = visited(&bvh, Vec3::new(-5.0, 0.0, 0.0), Vec3::X)
+            .into_iter()
+            .map(|i| order[i])
+            .filter(|&i| bounds[i as usize].ray_distance(Vec3::new(-5.0, 0.0, 0.0), Vec3::X.recip()) < f32::MAX)
+            .collect::<Vec<_>>();
+        hits.sort_unstable();
+        assert_eq!(hits, (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn depth_is_limited() {
+        // boxes that shrink geometrically make the SAH peel one off at a time
+        let bounds = (0..200)
+            .map(|i| Aabb::from_sphere(Vec3::new(0.5f32.powi(i), 0.0, 0.0), 0.25 * 0.5f32.powi(i)))
+            .collect::<Vec<_>>();
+        let (bvh, order) = Bvh::build(&bounds);
+
+        assert!(depth(&bvh, 0) <= MAX_DEPTH);
+        assert_eq!(order.len(), bounds.len());
+        // they're all on the x axis, so this goes through every one without running out of stack
+        assert_eq!(visited(&bvh, Vec3::new(-1.0, 0.0, 0.0), Vec3::X).len(), bounds.len());
+        assert_eq!(
+            bvh.nodes.iter().filter(|n| n.is_leaf()).map(|n| n.count).sum::<u32>(),
+            bounds.len() as u32
+        );
+    }
+}
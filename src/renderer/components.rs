@@ -1,23 +1,54 @@
 // put in SphereRender, PlaneRender, BoxRender, MeshRender etc. components here...
 
-use crate::{vec2, vec3, Mat3, Vec2, Vec3};
+use serde::{Deserialize, Serialize};
 
+use crate::{vec2, vec3, Mat3, Mat4, Quat, Vec2, Vec3};
+
+use super::{Handle, Mesh, Texture};
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
 pub struct TransformComponent {
     pub position: Vec3,
-    // rotation
+    pub rotation: Quat,
+    pub scale:    Vec3,
 }
 
 impl TransformComponent {
     pub fn with_pos(x: f32, y: f32, z: f32) -> Self {
         Self {
             position: Vec3::new(x, y, z),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_rotation(self, rotation: Quat) -> Self { Self { rotation, ..self } }
+    pub fn with_scale(self, scale: Vec3) -> Self { Self { scale, ..self } }
+
+    /// Object space to world space
+    pub fn model_matrix(&self) -> Mat4 {
+        Mat4::from_scale_rotation_translation(self.scale, self.rotation, self.position)
+    }
+
+    /// Transforms object space normals to world space, the inverse transpose of the model matrix
+    pub fn normal_matrix(&self) -> Mat3 { Mat3::from_mat4(self.model_matrix()).inverse().transpose() }
+}
+
+impl Default for TransformComponent {
+    fn default() -> Self {
+        Self {
+            position: Vec3::ZERO,
+            rotation: Quat::IDENTITY,
+            scale:    Vec3::ONE,
         }
     }
 }
+#[derive(Clone, Copy, Serialize, Deserialize)]
 pub struct SphereRenderComponent {
     pub radius: f32,
 }
 
+#[derive(Clone, Copy)]
 pub struct PlaneRenderComponent {
     pub normal:    Vec3,
     pub tangent:   Vec3,
@@ -54,34 +85,134 @@ impl PlaneRenderComponent {
             height,
         }
     }
+
+    /// The plane in world space, with its orientation and size taken from the transform
+    pub fn to_world(&self, transform: &TransformComponent) -> Self {
+        let model = Mat3::from_mat4(transform.model_matrix());
+        let tangent = model * (self.tangent * self.width);
+        let bitangent = model * (self.bitangent * self.height);
+        let normal = (transform.normal_matrix() * self.normal).normalize();
+
+        Self {
+            normal,
+            tangent: tangent.normalize(),
+            bitangent: bitangent.normalize(),
+            width: tangent.length(),
+            height: bitangent.length(),
+        }
+    }
 }
 
+/// Keeps its mesh loaded for as long as the component exists
+#[derive(Clone)]
 pub struct MeshRenderComponent {
-    pub mesh_id: u32,
+    pub mesh: Handle<Mesh>,
 }
 
+/// Keeps its material loaded for as long as the component exists. Objects that share a material share the
+/// handle, so changing it changes all of them
+#[derive(Clone)]
 pub struct MaterialComponent {
+    pub material: Handle<Material>,
+}
+
+/// A metallic/roughness material, shaded with a GGX microfacet brdf
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Material {
     pub tex_id:    u32,
     pub tex_scale: Vec2,
 
-    pub ambient:      f32,
-    pub diffuse:      f32, // aka albedo
-    pub specular:     f32,
-    pub shininess:    f32, // aka gloss
-    pub reflectivity: f32,
-    pub emissive:     f32,
-    // TODO: specular tint?
+    pub base_colour: Vec3, // tints the texture
+    pub metallic:    f32,
+    pub roughness:   f32,
+    pub emissive:    Vec3, // light given off, not tinted by the texture
+    pub ior:         f32,  // how reflective it is, when it isn't metallic, and how much it bends light
+
+    pub transmission: f32,  // how much of the light that isn't reflected goes through it
+    pub absorption:   Vec3, // absorbed per unit travelled through it, for tinted glass or water
+
+    // optional maps, at the same uvs as the texture. All but the normal map scale the value they go with
+    pub normal_tex_id:             Option<u32>, // tangent space, +y up the image like glTF, so along -v
+    pub metallic_roughness_tex_id: Option<u32>, // roughness in green and metallic in blue, like glTF
+    pub emissive_tex_id:           Option<u32>,
+    pub occlusion_tex_id:          Option<u32>, // in red, darkens the light bounced from other surfaces
+
+    /// Where the texture's alpha is below this, rays go straight through, for cutouts like leaves and fences.
+    /// Only meshes and planes are cut out
+    pub alpha_cutoff: Option<f32>,
 }
 
-impl MaterialComponent {
+impl Material {
     pub const fn basic() -> Self {
         Self {
             tex_id:    0,
             tex_scale: vec2(1.0, 1.0),
 
-            ambient:      0.1,
+            base_colour: Vec3::ONE,
+            metallic:    0.0,
+            roughness:   0.75,
+            emissive:    Vec3::ZERO,
+            ior:         1.5,
+
+            transmission: 0.0,
+            absorption:   Vec3::ZERO,
+
+            normal_tex_id:             None,
+            metallic_roughness_tex_id: None,
+            emissive_tex_id:           None,
+            occlusion_tex_id:          None,
+
+            alpha_cutoff: None,
+        }
+    }
+
+    /// The texture and every map it has
+    pub fn texture_ids(&self) -> impl Iterator<Item = u32> {
+        let maps = [
+            self.normal_tex_id,
+            self.metallic_roughness_tex_id,
+            self.emissive_tex_id,
+            self.occlusion_tex_id,
+        ];
+        std::iter::once(self.tex_id).chain(maps.into_iter().flatten())
+    }
+}
+
+/// The Phong style parameters materials used to have, which can be converted to the metallic/roughness model
+#[derive(Clone, Copy)]
+pub struct LegacyMaterial {
+    pub diffuse:      f32,
+    pub shininess:    f32,
+    pub reflectivity: f32,
+    pub emissive:     f32,
+}
+
+impl LegacyMaterial {
+    /// Reflective materials become smooth metals, and the shininess is mapped to a roughness
+    pub fn to_pbr(&self, tex_id: u32, tex_scale: Vec2) -> Material {
+        // Phong exponent to Beckmann alpha, whose square root is close to the perceptual roughness
+        let alpha = (2.0 / (self.shininess.max(0.0) + 2.0)).sqrt();
+        let reflectivity = self.reflectivity.clamp(0.0, 1.0);
+
+        Material {
+            tex_id,
+            tex_scale,
+            base_colour: Vec3::splat(self.diffuse + ((1.0 - self.diffuse) * reflectivity)),
+            metallic: reflectivity,
+            roughness: alpha.sqrt() * (1.0 - reflectivity),
+            emissive: Vec3::splat(self.emissive),
+            ior: 1.5,
+            transmission: 0.0,
+            absorption: Vec3::ZERO,
+            ..Material::basic()
+        }
+    }
+}
+
+impl Default for LegacyMaterial {
+    fn default() -> Self {
+        Self {
             diffuse:      1.0,
-            specular:     0.0,
             shininess:    4.0,
             reflectivity: 0.0,
             emissive:     0.0,
@@ -89,10 +220,11 @@ impl MaterialComponent {
     }
 }
 
-impl Default for MaterialComponent {
+impl Default for Material {
     fn default() -> Self { Self::basic() }
 }
 
+#[derive(Clone, Copy, Serialize, Deserialize)]
 pub struct CameraComponent {
     pub pitch: f32,
     pub yaw:   f32,
@@ -111,9 +243,112 @@ impl CameraComponent {
     }
 }
 
+fn white() -> Vec3 { Vec3::ONE }
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
 pub struct PointLightComponent {
-    //TODO: Coloured light?
-    pub intensity: f32, // shadow softness?
+    #[serde(default = "white")]
+    pub colour:    Vec3,
+    pub intensity: f32,
+}
+
+/// A light infinitely far away, like the sun. Doesn't need a transform.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct DirectionalLightComponent {
+    #[serde(default = "white")]
+    pub colour:    Vec3,
+    pub intensity: f32,
+    /// The direction the light travels in
+    pub direction: Vec3,
+}
+
+/// A point light limited to a cone, which fades out between the inner and outer angles
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct SpotLightComponent {
+    #[serde(default = "white")]
+    pub colour:      Vec3,
+    pub intensity:   f32,
+    /// The direction the cone points in, rotated by the transform
+    pub direction:   Vec3,
+    /// Half angle of the fully lit part of the cone, in degrees
+    pub inner_angle: f32,
+    /// Half angle of the whole cone, in degrees
+    pub outer_angle: f32,
 }
 
-pub struct SkyBoxComponent {}
+impl SpotLightComponent {
+    /// Cosines of the inner and outer angles, which the falloff is between. The inner angle is kept inside the outer
+    /// one, and a little narrower so the falloff never divides by zero.
+    pub fn cone_cosines(&self) -> (f32, f32) {
+        let cos_outer = self.outer_angle.to_radians().cos();
+        let cos_inner = self.inner_angle.to_radians().cos().max(cos_outer);
+        (cos_inner, cos_outer.min(cos_inner - 1e-4))
+    }
+}
+
+/// A light with a size, which casts soft shadows
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct AreaLightComponent {
+    #[serde(default = "white")]
+    pub colour:    Vec3,
+    pub intensity: f32,
+    pub shape:     AreaLightShape,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AreaLightShape {
+    /// A one sided rectangle, oriented like a plane and rotated and scaled by the transform
+    Rect {
+        normal: Vec3,
+        width:  f32,
+        height: f32,
+    },
+    Sphere {
+        radius: f32,
+    },
+}
+
+/// What rays that miss everything see, and light that comes from every direction. Brighter parts of it are sampled
+/// more, so small suns in environment maps light the scene without much noise.
+/// Doesn't need a transform, and only the first one in the scene is used. Without one the sky is plain grey, and only
+/// lights the scene when path tracing.
+#[derive(Clone, PartialEq)]
+pub struct SkyBoxComponent {
+    pub sky:       Sky,
+    /// Turns the sky around the y axis, in degrees
+    pub rotation:  f32,
+    /// Multiplies the brightness of the sky
+    pub intensity: f32,
+}
+
+impl Default for SkyBoxComponent {
+    fn default() -> Self {
+        Self {
+            sky:       Sky::Colour(Vec3::splat(0.7)),
+            rotation:  0.0,
+            intensity: 1.0,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub enum Sky {
+    Colour(Vec3),
+    /// A latitude/longitude panorama, like a .hdr or .exr environment map. The middle of it faces +z
+    Equirect {
+        texture: Handle<Texture>,
+    },
+    /// One texture per face, in the order +x, -x, +y, -y, +z, -z, laid out like OpenGL cubemaps
+    Cubemap {
+        textures: [Handle<Texture>; 6],
+    },
+    /// A clear sky from the Preetham model, which doesn't include the sun itself, so pair it with a
+    /// `DirectionalLightComponent`
+    Procedural {
+        /// The direction the sunlight travels in, like `DirectionalLightComponent::direction`. Zero is straight down
+        sun_direction: Vec3,
+        /// How hazy the air is, from 2 for a very clear sky to about 10
+        turbidity:     f32,
+    },
+}
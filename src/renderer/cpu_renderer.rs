@@ -0,0 +1,1733 @@
+// A reference renderer that runs on the CPU.
+// Mirrors gpu_render.comp and the shader files it includes, function for function,
+// so that it can be used as a ground truth for the compute shader.
+
+use std::f32::consts::PI;
+
+use log::debug;
+use rayon::prelude::*;
+
+use crate::{
+    renderer::{Mesh, MeshError},
+    rgb,
+    scene::Scene,
+    soft_blue, vec2, vec3, Mat3, Mat4, Reflectable, Vec2, Vec3, Vec4,
+};
+
+use super::{
+    srgb_to_linear, Aabb, AssetStore, Bvh, CameraComponent, Frame, Handle, Material, MaterialComponent, MaterialKey,
+    MaterialStore, MeshRenderComponent, PlaneRenderComponent, RenderMode, Renderer, SkyBoxComponent,
+    SphereRenderComponent, Texture, TextureError, TextureSampler, TextureStore, TransformComponent, Triangle,
+};
+
+use super::lights::{gather_lights, Light, LightType};
+use super::sky::{sample_sky, SkyDistribution};
+
+const MAX_BOUNCES: u32 = 4;
+const RAY_STACK_SIZE: usize = 8;
+const COLOUR_DEPTH: f32 = 256.0; // 2^8
+const EPSILON: f32 = 0.001;
+// area lights are sampled on a jittered grid of this size squared
+const AREA_LIGHT_GRID: u32 = 4;
+// lights up everything a little, in place of the GPU renderer's radiosity
+const AMBIENT: f32 = 0.1;
+// perceptually linear, squared to get the alpha used by GGX
+const MIN_ROUGHNESS: f32 = 0.045;
+// surfaces rougher than this don't reflect their surroundings, their specular only comes from lights
+const MAX_REFLECTION_ROUGHNESS: f32 = 0.5;
+const MAX_PATH_BOUNCES: u32 = 8;
+// paths are only cut short by russian roulette after this many bounces
+const MIN_PATH_BOUNCES: u32 = 3;
+// the sky's light is averaged over jittered grids of these sizes squared, picked from the sky for diffuse light and
+// from the GGX lobe for glossy reflections
+const SKY_IRRADIANCE_GRID: u32 = 8;
+const SKY_SPECULAR_GRID: u32 = 4;
+
+// 8x8 Bayer ordered dithering pattern
+const BAYER: [[i32; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+pub struct CpuRenderer {
+    width:  u32,
+    height: u32,
+    frame:  Vec<f32>,
+
+    textures: TextureStore,
+
+    materials:         MaterialStore,
+    /// The textures each material uses by id, so they stay loaded for as long as the material
+    material_textures: Vec<Vec<Handle<Texture>>>,
+    /// Material 0, which objects without their own use, held so it's never unloaded
+    _basic_material:   Option<Handle<Material>>,
+
+    meshes: AssetStore<Mesh>,
+
+    /// The sky box `sky_distribution` was built for, `None` for the default sky
+    sky_box:          Option<SkyBoxComponent>,
+    sky_distribution: SkyDistribution,
+
+    render_mode:  RenderMode,
+    sample_count: u32,
+    /// The sum of every path traced sample for each pixel
+    accumulation: Vec<Vec3>,
+    /// Everything drawn in the last frame, path tracing starts again when it changes
+    frame_state:  Option<FrameState>,
+}
+
+// The scene as plain data, gathered once per frame so it can be shared between threads
+struct Ray {
+    origin:    Vec3,
+    direction: Vec3,
+}
+
+/// The cone of directions a ray stands for, which widens with distance, so textures can be blurred to match what it
+/// covers. From "Texture Level of Detail Strategies for Real-Time Ray Tracing" (Akenine-Möller et al.)
+#[derive(Clone, Copy)]
+struct RayCone {
+    /// How wide the cone is at the ray's origin
+    width:  f32,
+    /// The angle the cone widens by
+    spread: f32,
+}
+
+impl RayCone {
+    /// The cone distance along the ray, where a reflected or refracted ray carries on from. The curvature of what's
+    /// hit is ignored
+    fn at(self, distance: f32) -> Self {
+        Self {
+            width: self.width + (self.spread * distance),
+            ..self
+        }
+    }
+}
+
+/// Centred on the origin of its object space
+#[derive(Clone, PartialEq)]
+struct Sphere {
+    model:     Mat4,
+    inv_model: Mat4,
+    radius:    f32,
+    mat_id:    u32,
+}
+
+#[derive(Clone, PartialEq)]
+struct Plane {
+    position: Vec3,
+    normal:   Vec3,
+    tangent:  Vec3,
+    width:    f32,
+    height:   f32,
+    mat_id:   u32,
+}
+
+#[derive(Clone, PartialEq)]
+struct MeshInstance {
+    model:     Mat4,
+    inv_model: Mat4,
+    mesh_id:   u32,
+    /// Overrides the materials from the mesh file
+    mat_id:    Option<u32>,
+}
+
+/// A ray waiting to be traced, with how much it adds to the pixel and the absorption of whatever it's travelling through
+struct PendingRay {
+    ray:          Ray,
+    cone:         RayCone,
+    transmission: Vec3,
+    absorption:   Vec3,
+    depth:        u32,
+}
+
+struct HitInfo {
+    position:  Vec3,
+    normal:    Vec3,
+    mat:       Material,
+    colour:    Vec3,
+    radiosity: Vec3,
+}
+
+#[derive(Clone, PartialEq)]
+struct Camera {
+    position:     Vec3,
+    rotation:     Mat3,
+    zdepth:       f32,
+    /// The angle one pixel covers, which the ray cones start with
+    pixel_spread: f32,
+}
+
+/// What was drawn in a frame, to tell when the accumulated path traced samples are out of date
+#[derive(PartialEq)]
+struct FrameState {
+    camera:         Camera,
+    spheres:        Vec<Sphere>,
+    planes:         Vec<Plane>,
+    lights:         Vec<Light>,
+    sky:            SkyBoxComponent,
+    mesh_instances: Vec<MeshInstance>,
+    materials:      Vec<(u32, Material)>,
+}
+
+/// An object in the top level BVH, indexing the list for its type
+enum Object {
+    Sphere(usize),
+    Plane(usize),
+    Mesh(usize),
+}
+
+struct SceneData<'a> {
+    spheres:          Vec<Sphere>,
+    planes:           Vec<Plane>,
+    lights:           Vec<Light>,
+    sky:              SkyBoxComponent,
+    /// Whether the sky lights surfaces outside of path tracing, which only a sky that's been added does
+    sky_lights_scene: bool,
+    sky_distribution: &'a SkyDistribution,
+    mesh_instances:   Vec<MeshInstance>,
+    meshes:           &'a AssetStore<Mesh>,
+    textures:         &'a TextureStore,
+    materials:        &'a MaterialStore,
+    tlas:             Bvh,
+    /// Ordered to match the leaves of `tlas`
+    objects:          Vec<Object>,
+}
+
+impl CpuRenderer {
+    pub fn new(width: u32, height: u32) -> Self {
+        let mut renderer = Self {
+            width,
+            height,
+            frame: vec![0.0; (width * height * 4) as usize],
+
+            textures: TextureStore::new(),
+
+            materials: MaterialStore::new(),
+            material_textures: vec![],
+            _basic_material: None,
+
+            meshes: AssetStore::new(),
+
+            // the default sky is a plain colour, so it doesn't need any textures
+            sky_box: None,
+            sky_distribution: SkyDistribution::new(&SkyBoxComponent::default(), &TextureStore::new()),
+
+            render_mode: RenderMode::default(),
+            sample_count: 0,
+            accumulation: vec![Vec3::ZERO; (width * height) as usize],
+            frame_state: None,
+        };
+
+        // same default texture and material as the GPU renderer, so ids match up. The basic material keeps the
+        // texture loaded
+        renderer.get_texture_by_colour(soft_blue!());
+        renderer._basic_material = Some(renderer.get_material(Material::basic()));
+        renderer
+    }
+
+    /// Goes back to the default sky until the next frame, which builds the sky's distribution again and lets go of
+    /// its textures
+    fn reset_sky(&mut self) {
+        self.sky_box = None;
+        self.sky_distribution = SkyDistribution::new(&SkyBoxComponent::default(), &self.textures);
+    }
+
+    /// Handles to the textures a material uses
+    fn texture_handles(&self, material: &Material) -> Vec<Handle<Texture>> {
+        material
+            .texture_ids()
+            .filter_map(|id| self.textures.handle(id))
+            .collect()
+    }
+}
+
+impl Renderer for CpuRenderer {
+    fn get_texture_by_path(&mut self, path: &str) -> Result<Handle<Texture>, TextureError> {
+        let key = (path.to_owned(), false);
+        if let Some(handle) = self.textures.find(&key) {
+            Ok(handle)
+        } else {
+            debug!("Loading texture from path \"{}\"", path);
+            let mut texture = Texture::from_path(path)?;
+            texture.generate_mips();
+            Ok(self.textures.insert(path, Some(key), texture))
+        }
+    }
+    fn get_data_texture_by_path(&mut self, path: &str) -> Result<Handle<Texture>, TextureError> {
+        let key = (path.to_owned(), true);
+        if let Some(handle) = self.textures.find(&key) {
+            Ok(handle)
+        } else {
+            debug!("Loading data texture from path \"{}\"", path);
+            let mut texture = Texture::from_data_path(path)?;
+            texture.generate_mips();
+            Ok(self.textures.insert(path, Some(key), texture))
+        }
+    }
+    fn get_texture_by_colour(&mut self, colour: Vec3) -> Handle<Texture> {
+        let path = Texture::colour_path(colour);
+        let key = (path.clone(), false);
+        if let Some(handle) = self.textures.find(&key) {
+            handle
+        } else {
+            self.textures
+                .insert(&path, Some(key), Texture::from_colour_srgb(colour))
+        }
+    }
+
+    fn get_mesh_by_path(&mut self, path: &str) -> Result<Handle<Mesh>, MeshError> {
+        if let Some(handle) = self.meshes.find(&path.to_owned()) {
+            Ok(handle)
+        } else {
+            debug!("Loading mesh from path \"{}\"", path);
+            let mesh = Mesh::from_path(path, |m| m.load(self))?;
+            Ok(self.meshes.insert(path, Some(path.to_owned()), mesh))
+        }
+    }
+
+    fn add_texture(&mut self, name: &str, mut texture: Texture) -> Handle<Texture> {
+        texture.generate_mips();
+        self.textures.insert(name, None, texture)
+    }
+    fn add_mesh(&mut self, name: &str, mesh: Mesh) -> Handle<Mesh> { self.meshes.insert(name, None, mesh) }
+
+    fn replace_texture(&mut self, texture: &Handle<Texture>, mut replacement: Texture) {
+        replacement.sampler = self.textures[texture.id()].sampler;
+        replacement.generate_mips();
+        self.textures.replace(texture, replacement);
+
+        // the sky's distribution is built from its textures
+        self.reset_sky();
+        self.sample_count = 0;
+    }
+    fn replace_mesh(&mut self, mesh: &Handle<Mesh>, replacement: Mesh) {
+        self.meshes.replace(mesh, replacement);
+        self.sample_count = 0;
+    }
+
+    fn unload_unused(&mut self) {
+        // the last frame's sky is kept to tell when it changes, which would keep its textures loaded
+        self.reset_sky();
+        self.frame_state = None;
+
+        // meshes hold on to their materials, and materials to their textures
+        let meshes = self.meshes.unload_unused();
+        let materials = self.materials.unload_unused();
+        for &mat_id in &materials {
+            self.material_textures[mat_id as usize].clear();
+        }
+        let textures = self.textures.unload_unused();
+        debug!(
+            "Unloaded {} meshes, {} materials and {} textures",
+            meshes.len(),
+            materials.len(),
+            textures.len()
+        );
+    }
+
+    fn get_material(&mut self, material: Material) -> Handle<Material> {
+        let key = MaterialKey::from(&material);
+        if let Some(handle) = self.materials.find(&key) {
+            handle
+        } else {
+            let handle = self.materials.insert("", Some(key), material);
+            self.material_textures
+                .resize_with(self.materials.id_count() as usize, Vec::new);
+            self.material_textures[handle.id() as usize] = self.texture_handles(&material);
+            handle
+        }
+    }
+
+    fn get_texture_path(&self, tex_id: u32) -> Option<&str> { self.textures.name(tex_id) }
+    fn get_mesh_path(&self, mesh_id: u32) -> Option<&str> { self.meshes.name(mesh_id) }
+    fn get_material_data(&self, mat_id: u32) -> Option<&Material> { self.materials.get(mat_id) }
+
+    fn set_material(&mut self, mat_id: u32, material: Material) {
+        match self.materials.get_mut(mat_id) {
+            Some(m) => *m = material,
+            None => return,
+        }
+        self.materials.set_key(mat_id, Some(MaterialKey::from(&material)));
+        self.material_textures[mat_id as usize] = self.texture_handles(&material);
+    }
+
+    fn get_texture_sampler(&self, tex_id: u32) -> Option<TextureSampler> {
+        self.textures.get(tex_id).map(|t| t.sampler)
+    }
+
+    fn set_texture_sampler(&mut self, tex_id: u32, sampler: TextureSampler) {
+        if let Some(texture) = self.textures.get_mut(tex_id) {
+            texture.sampler = sampler;
+        }
+        self.sample_count = 0;
+    }
+
+    fn resize(&mut self, width: u32, height: u32) {
+        self.width = width;
+        self.height = height;
+        self.frame = vec![0.0; (width * height * 4) as usize];
+        self.accumulation = vec![Vec3::ZERO; (width * height) as usize];
+        self.sample_count = 0;
+    }
+
+    fn read_frame(&self) -> Frame { Frame::new(self.width, self.height, self.frame.clone()) }
+
+    fn draw(&mut self, scene: &mut Scene) {
+        // get the first camera from the query
+        let (_, (camera_transform, camera_component)) = scene
+            .query_mut::<(&TransformComponent, &CameraComponent)>()
+            .into_iter()
+            .next()
+            .unwrap();
+
+        let zdepth = (camera_component.fov * 0.5).to_radians().tan().recip();
+        let camera = Camera {
+            position: camera_transform.position,
+            rotation: camera_component.get_rot_mat(),
+            zdepth,
+            pixel_spread: (zdepth * self.height as f32).recip(),
+        };
+
+        let spheres = scene
+            .query_mut::<(&TransformComponent, &SphereRenderComponent, &MaterialComponent)>()
+            .into_iter()
+            .map(|(_, (t, s, m))| {
+                let model = t.model_matrix();
+                Sphere {
+                    model,
+                    inv_model: model.inverse(),
+                    radius: s.radius,
+                    mat_id: m.material.id(),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let planes = scene
+            .query_mut::<(&TransformComponent, &PlaneRenderComponent, &MaterialComponent)>()
+            .into_iter()
+            .map(|(_, (t, p, m))| {
+                let p = p.to_world(t);
+                Plane {
+                    position: t.position,
+                    normal:   p.normal,
+                    tangent:  p.tangent,
+                    width:    p.width,
+                    height:   p.height,
+                    mat_id:   m.material.id(),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let lights = gather_lights(scene);
+
+        let sky_box = scene
+            .query_mut::<&SkyBoxComponent>()
+            .into_iter()
+            .next()
+            .map(|(_, s)| s.clone());
+        if sky_box != self.sky_box {
+            debug!("Building the sky's distribution");
+            self.sky_distribution = SkyDistribution::new(&sky_box.clone().unwrap_or_default(), &self.textures);
+            self.sky_box = sky_box.clone();
+        }
+        let sky = sky_box.clone().unwrap_or_default();
+
+        let mesh_instances = scene
+            .query_mut::<(&TransformComponent, &MeshRenderComponent, Option<&MaterialComponent>)>()
+            .into_iter()
+            .map(|(_, (t, mesh, m))| {
+                let model = t.model_matrix();
+                MeshInstance {
+                    model,
+                    inv_model: model.inverse(),
+                    mesh_id: mesh.mesh.id(),
+                    mat_id: m.map(|m| m.material.id()),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // rebuilt every frame, it's cheap compared to rendering on the CPU
+        let bounds = spheres
+            .iter()
+            .map(|s| Aabb::from_sphere(Vec3::ZERO, s.radius).transform(s.model))
+            .chain(
+                planes
+                    .iter()
+                    .map(|p| Aabb::from_rect(p.position, p.normal, p.tangent, p.width, p.height)),
+            )
+            .chain(
+                mesh_instances
+                    .iter()
+                    .map(|m| self.meshes[m.mesh_id].bvh.bounds().transform(m.model)),
+            )
+            .collect::<Vec<_>>();
+        let (tlas, order) = Bvh::build(&bounds);
+
+        let (num_spheres, num_planes) = (spheres.len(), planes.len());
+        let objects = order
+            .iter()
+            .map(|&i| match i as usize {
+                i if i < num_spheres => Object::Sphere(i),
+                i if i < num_spheres + num_planes => Object::Plane(i - num_spheres),
+                i => Object::Mesh(i - num_spheres - num_planes),
+            })
+            .collect();
+
+        // path tracing starts again whenever anything that's drawn changes
+        let frame_state = FrameState {
+            camera:         camera.clone(),
+            spheres:        spheres.clone(),
+            planes:         planes.clone(),
+            lights:         lights.clone(),
+            sky:            sky.clone(),
+            mesh_instances: mesh_instances.clone(),
+            materials:      self.materials.iter().map(|(id, m)| (id, *m)).collect(),
+        };
+        if self.frame_state.as_ref() != Some(&frame_state) {
+            self.sample_count = 0;
+            self.frame_state = Some(frame_state);
+        }
+
+        let scene_data = SceneData {
+            spheres,
+            planes,
+            lights,
+            sky,
+            sky_lights_scene: sky_box.is_some(),
+            sky_distribution: &self.sky_distribution,
+            mesh_instances,
+            meshes: &self.meshes,
+            textures: &self.textures,
+            materials: &self.materials,
+            tlas,
+            objects,
+        };
+
+        let (width, height) = (self.width, self.height);
+        let resolution = vec2(width as f32, height as f32);
+        let (render_mode, sample_count) = (self.render_mode, self.sample_count);
+
+        self.frame
+            .par_chunks_exact_mut(width as usize * 4)
+            .zip(self.accumulation.par_chunks_exact_mut(width as usize))
+            .enumerate()
+            .for_each(|(y, (row, sums))| {
+                for (x, (pixel, sum)) in row.chunks_exact_mut(4).zip(sums.iter_mut()).enumerate() {
+                    let pix_coord = vec2(x as f32, y as f32);
+
+                    // -0.5..0.5 uv coords
+                    let mut uv = (pix_coord - (resolution / 2.0)) / resolution.y;
+                    uv.y = -uv.y;
+
+                    let hdr_colour = match render_mode {
+                        RenderMode::Lightmap => scene_data.render_pixel(&camera, uv),
+                        RenderMode::PathTraced => {
+                            let mut rng = pcg_hash((y as u32 * width) + x as u32) ^ pcg_hash(sample_count);
+
+                            // jitter within the pixel, so the edges are antialiased as it converges
+                            let mut jitter = (random2(&mut rng) - 0.5) / resolution.y;
+                            jitter.y = -jitter.y;
+
+                            let mut path_sample = scene_data.path_trace_pixel(&camera, uv + jitter, &mut rng);
+
+                            // a single bad sample would never average out
+                            if !path_sample.is_finite() {
+                                path_sample = Vec3::ZERO;
+                            }
+
+                            *sum = if sample_count > 0 {
+                                *sum + path_sample
+                            } else {
+                                path_sample
+                            };
+                            *sum / (sample_count + 1) as f32
+                        }
+                    };
+
+                    let mut colour = aces_tonemap(hdr_colour);
+                    colour += dither(x, y, COLOUR_DEPTH, colour);
+
+                    pixel.copy_from_slice(&[colour.x, colour.y, colour.z, 0.0]);
+                }
+            });
+
+        if render_mode == RenderMode::PathTraced {
+            self.sample_count += 1;
+        }
+    }
+
+    fn render_mode(&self) -> RenderMode { self.render_mode }
+    fn set_render_mode(&mut self, mode: RenderMode) {
+        if mode != self.render_mode {
+            self.render_mode = mode;
+            self.sample_count = 0;
+        }
+    }
+
+    fn sample_count(&self) -> u32 {
+        match self.render_mode {
+            RenderMode::Lightmap => 1,
+            RenderMode::PathTraced => self.sample_count,
+        }
+    }
+}
+
+impl SceneData<'_> {
+    fn render_pixel(&self, camera: &Camera, uv: Vec2) -> Vec3 {
+        let mut colour = Vec3::ZERO;
+
+        let dir = (camera.rotation * uv.extend(camera.zdepth)).normalize();
+
+        // reflections and refractions split the path, so keep the rays still to be traced on a stack
+        let mut stack = Vec::with_capacity(RAY_STACK_SIZE);
+        stack.push(PendingRay {
+            ray:          Ray {
+                origin:    camera.position,
+                direction: dir,
+            },
+            cone:         RayCone {
+                width:  0.0,
+                spread: camera.pixel_spread,
+            },
+            transmission: Vec3::ONE,
+            absorption:   Vec3::ZERO,
+            depth:        0,
+        });
+
+        while let Some(pending) = stack.pop() {
+            let ray = &pending.ray;
+            let mut hit = match self.cast_ray(ray, pending.cone) {
+                Some(hit) => hit,
+                None => {
+                    colour += pending.transmission * self.sample_sky(ray.direction);
+                    continue;
+                }
+            };
+
+            // Beer-Lambert, light is absorbed on its way through a medium
+            let distance = ray.origin.distance(hit.position);
+            let transmission = pending.transmission * (-pending.absorption * distance).exp();
+            let cone = pending.cone.at(distance);
+
+            // hitting the inside of an object, so face the normal back at the ray
+            let entering = ray.direction.dot(hit.normal) < 0.0;
+            if !entering {
+                hit.normal = -hit.normal;
+            }
+
+            colour += transmission * self.shade_object(ray.direction, &hit);
+
+            if pending.depth + 1 >= MAX_BOUNCES {
+                continue;
+            }
+
+            let (reflect_weight, refract_weight, refracted) =
+                reflection_weight(hit.normal, ray.direction, &hit.mat, hit.colour, entering);
+
+            // keep going while the rays are still visible, nudging them off the surface
+            if is_visible(transmission * refract_weight) && stack.len() < RAY_STACK_SIZE {
+                stack.push(PendingRay {
+                    ray: Ray {
+                        origin:    hit.position + (refracted * EPSILON * 3.0),
+                        direction: refracted,
+                    },
+                    cone,
+                    transmission: transmission * refract_weight,
+                    absorption: if entering { hit.mat.absorption } else { Vec3::ZERO },
+                    depth: pending.depth + 1,
+                });
+            }
+
+            if is_visible(transmission * reflect_weight) && stack.len() < RAY_STACK_SIZE {
+                let reflected = ray.direction.reflect(hit.normal);
+                stack.push(PendingRay {
+                    ray: Ray {
+                        origin:    hit.position + (reflected * EPSILON * 3.0),
+                        direction: reflected,
+                    },
+                    cone,
+                    transmission: transmission * reflect_weight,
+                    absorption: pending.absorption,
+                    depth: pending.depth + 1,
+                });
+            }
+        }
+
+        colour
+    }
+
+    fn cast_ray(&self, ray: &Ray, cone: RayCone) -> Option<HitInfo> {
+        enum Hit {
+            Sphere(usize),
+            Plane(usize),
+            Triangle {
+                instance: usize,
+                triangle: usize,
+                uv:       Vec2,
+            },
+        }
+
+        let mut least_dist = f32::MAX;
+        let mut hit = None;
+
+        // Walk the top level BVH over all objects, nearest first
+        self.tlas
+            .traverse(ray.origin, ray.direction, 0.0, least_dist, |objects, _| {
+                for object in &self.objects[objects] {
+                    match *object {
+                        Object::Sphere(i) => {
+                            let sphere = &self.spheres[i];
+                            let obj_dist = ray_sphere_intersect(&ray.to_object_space(&sphere.inv_model), sphere.radius);
+                            if obj_dist < least_dist {
+                                least_dist = obj_dist;
+                                hit = Some(Hit::Sphere(i));
+                            }
+                        }
+                        Object::Plane(i) => {
+                            let obj_dist = self.intersect_plane(ray, &self.planes[i]);
+                            if obj_dist < least_dist {
+                                least_dist = obj_dist;
+                                hit = Some(Hit::Plane(i));
+                            }
+                        }
+                        Object::Mesh(i) => {
+                            if let Some((triangle, uv)) =
+                                self.intersect_mesh(ray, &self.mesh_instances[i], &mut least_dist)
+                            {
+                                hit = Some(Hit::Triangle {
+                                    instance: i,
+                                    triangle,
+                                    uv,
+                                });
+                            }
+                        }
+                    }
+                }
+                least_dist
+            });
+
+        let position = ray.origin + (least_dist * ray.direction);
+
+        // uv_density is how far uvs go per unit of distance across the surface, on average
+        let (normal, mat, uv, vertex_colour, tangent, uv_density) = match hit? {
+            Hit::Sphere(idx) => {
+                let sphere = &self.spheres[idx];
+                let local_ray = ray.to_object_space(&sphere.inv_model);
+                let local_normal = (local_ray.origin + (least_dist * local_ray.direction)).normalize();
+                let normal = normal_to_world(local_normal, &sphere.inv_model);
+
+                // uvs stay fixed to the sphere as it rotates
+                let uv = vec2(
+                    0.5 + (local_normal.x.atan2(-local_normal.z) / std::f32::consts::TAU),
+                    0.5 + (local_normal.y.asin() / PI),
+                );
+                // around the y axis, the way u increases. v goes up, the other way to normal.cross(tangent)
+                let tangent = sphere
+                    .model
+                    .transform_vector3(vec3(-local_normal.z, 0.0, local_normal.x))
+                    .extend(-1.0);
+
+                // u goes once around the sphere, and v halfway
+                let radius = sphere.radius * sphere.model.x_axis.truncate().length();
+                let uv_density = (PI * std::f32::consts::SQRT_2 * radius).recip();
+                (
+                    normal,
+                    self.materials[sphere.mat_id],
+                    uv,
+                    Vec3::ONE,
+                    tangent,
+                    uv_density,
+                )
+            }
+            Hit::Plane(idx) => {
+                let plane = &self.planes[idx];
+                let uv = plane.uv(position);
+                let tangent = plane.tangent.extend(1.0);
+                (
+                    plane.normal,
+                    self.materials[plane.mat_id],
+                    uv,
+                    Vec3::ONE,
+                    tangent,
+                    (plane.width * plane.height).sqrt().recip(),
+                )
+            }
+            Hit::Triangle { instance, triangle, uv } => {
+                let m = &self.mesh_instances[instance];
+                let mesh = &self.meshes[m.mesh_id];
+                let triangle = &mesh.triangles[triangle];
+                let mat = *self.triangle_material(m, triangle);
+
+                let v1 = &mesh.vertices[triangle.v1_idx as usize];
+                let v2 = &mesh.vertices[triangle.v2_idx as usize];
+                let v3 = &mesh.vertices[triangle.v3_idx as usize];
+
+                let (r, s) = (uv.x, uv.y);
+                let w = 1.0 - r - s;
+
+                let normal = normal_to_world((w * v1.normal) + (r * v2.normal) + (s * v3.normal), &m.inv_model);
+                let uv = (w * v1.uv) + (r * v2.uv) + (s * v3.uv);
+                let vertex_colour = (w * v1.colour) + (r * v2.colour) + (s * v3.colour);
+                let local_tangent = (w * v1.tangent) + (r * v2.tangent) + (s * v3.tangent);
+                let tangent = m.model.transform_vector3(local_tangent.truncate()).extend(v1.tangent.w);
+
+                // from the triangle's area in uv space and in the world, both doubled
+                let edge1 = m.model.transform_vector3(v2.position - v1.position);
+                let edge2 = m.model.transform_vector3(v3.position - v1.position);
+                let world_area = edge1.cross(edge2).length();
+                let uv_area = (v2.uv - v1.uv).perp_dot(v3.uv - v1.uv).abs();
+                let uv_density = if world_area > 0.0 {
+                    (uv_area / world_area).sqrt()
+                } else {
+                    0.0
+                };
+                (normal, mat, uv, vertex_colour, tangent, uv_density)
+            }
+        };
+
+        // the width of the ray's cone in uv space, stretched where it meets the surface at an angle. Kept from
+        // getting too wide at grazing angles, as the filtering doesn't stretch along with it
+        let cos_incidence = normal.dot(ray.direction).abs().max(0.1);
+        let footprint = cone.at(least_dist).width * uv_density / cos_incidence;
+
+        let mat = self.sample_material(&mat, uv, footprint);
+        let normal = self.sample_normal_map(&mat, uv, footprint, normal, tangent);
+
+        let colour = self.sample_texture(&mat, uv, footprint) * vertex_colour;
+        // There are no lightmaps on the CPU, so use a constant ambient term in place of radiosity
+        let radiosity = Vec3::splat(AMBIENT) * self.sample_occlusion(&mat, uv, footprint);
+
+        Some(HitInfo {
+            position,
+            normal,
+            mat,
+            colour,
+            radiosity,
+        })
+    }
+
+    // Same as the shader version, kept in sync so they can be compared.
+    // How much light gets through to a light, black if anything opaque is in the way
+    fn cast_shadow_ray(&self, ray: &Ray, light_dist: f32) -> Vec3 {
+        // ray must be normalized
+        let mut shade = Vec3::ONE;
+
+        self.tlas
+            .traverse(ray.origin, ray.direction, 0.0, light_dist, |objects, max_dist| {
+                for object in &self.objects[objects] {
+                    // find where the ray goes in and out of the object
+                    match *object {
+                        Object::Sphere(i) => {
+                            let sphere = &self.spheres[i];
+                            let mut local_ray = ray.to_object_space(&sphere.inv_model);
+                            let entry = ray_sphere_intersect(&local_ray, sphere.radius);
+
+                            if entry < light_dist {
+                                local_ray.origin += local_ray.direction * (entry + EPSILON);
+                                let exit = ray_sphere_intersect(&local_ray, sphere.radius).min(light_dist - entry);
+                                shade *= shadow_transmittance(&self.materials[sphere.mat_id], exit);
+                            }
+                        }
+                        Object::Plane(i) => {
+                            let plane = &self.planes[i];
+                            if self.intersect_plane(ray, plane) < light_dist {
+                                shade *= shadow_transmittance(&self.materials[plane.mat_id], 0.0);
+                            }
+                        }
+                        Object::Mesh(i) => {
+                            let m = &self.mesh_instances[i];
+                            let mut entry = light_dist;
+
+                            if let Some((triangle, _)) = self.intersect_mesh(ray, m, &mut entry) {
+                                let exit_ray = Ray {
+                                    origin:    ray.origin + (ray.direction * (entry + EPSILON)),
+                                    direction: ray.direction,
+                                };
+                                let mut exit = (light_dist - entry - EPSILON).max(0.0);
+                                self.intersect_mesh(&exit_ray, m, &mut exit);
+                                let triangle = &self.meshes[m.mesh_id].triangles[triangle];
+                                shade *= shadow_transmittance(self.triangle_material(m, triangle), exit);
+                            }
+                        }
+                    }
+
+                    if shade == Vec3::ZERO {
+                        // no need to look any further
+                        return 0.0;
+                    }
+                }
+                max_dist
+            });
+
+        shade
+    }
+
+    /// Walks the mesh's BVH to find the closest triangle hit nearer than `least_dist`, updating it.
+    /// Returns the index of the triangle and the barycentric coordinates of the hit.
+    fn intersect_mesh(&self, ray: &Ray, m: &MeshInstance, least_dist: &mut f32) -> Option<(usize, Vec2)> {
+        let mesh = &self.meshes[m.mesh_id];
+        let mut hit = None;
+
+        // the BVH is in object space, so move the ray there instead of moving every vertex
+        let local_ray = ray.to_object_space(&m.inv_model);
+
+        mesh.bvh.traverse(
+            local_ray.origin,
+            local_ray.direction,
+            0.0,
+            *least_dist,
+            |triangles, _| {
+                for t in triangles {
+                    let triangle = &mesh.triangles[t];
+                    let p1 = mesh.vertices[triangle.v1_idx as usize].position;
+                    let p2 = mesh.vertices[triangle.v2_idx as usize].position;
+                    let p3 = mesh.vertices[triangle.v3_idx as usize].position;
+
+                    let (obj_dist, tu, tv) = ray_triangle_intersect(&local_ray, p1, p2, p3);
+                    if obj_dist < *least_dist && !self.is_cut_out_triangle(m, triangle, vec2(tu, tv)) {
+                        *least_dist = obj_dist;
+                        hit = Some((t, vec2(tu, tv)));
+                    }
+                }
+                *least_dist
+            },
+        );
+
+        hit
+    }
+
+    /// Like `ray_plane_intersect`, but goes through the parts its material cuts out
+    fn intersect_plane(&self, ray: &Ray, plane: &Plane) -> f32 {
+        let dist = ray_plane_intersect(ray, plane);
+        if dist < f32::MAX {
+            let uv = plane.uv(ray.origin + (dist * ray.direction));
+            if self.is_cut_out(&self.materials[plane.mat_id], uv) {
+                return f32::MAX;
+            }
+        }
+        dist
+    }
+
+    /// Whether a triangle is cut out by its material where it's hit, at barycentric coordinates bary
+    fn is_cut_out_triangle(&self, m: &MeshInstance, triangle: &Triangle, bary: Vec2) -> bool {
+        let mat = self.triangle_material(m, triangle);
+        if mat.alpha_cutoff.is_none() {
+            return false;
+        }
+
+        let vertices = &self.meshes[m.mesh_id].vertices;
+        let uv = ((1.0 - bary.x - bary.y) * vertices[triangle.v1_idx as usize].uv)
+            + (bary.x * vertices[triangle.v2_idx as usize].uv)
+            + (bary.y * vertices[triangle.v3_idx as usize].uv);
+        self.is_cut_out(mat, uv)
+    }
+
+    /// Whether the material's texture is transparent enough at uv for rays to go through. Always tested on the
+    /// full size texture, so cutouts don't shrink or grow with distance
+    fn is_cut_out(&self, mat: &Material, uv: Vec2) -> bool {
+        mat.alpha_cutoff
+            .is_some_and(|cutoff| self.textures[mat.tex_id].sample_alpha(uv * mat.tex_scale) < cutoff)
+    }
+
+    /// The light reflected at a hit from a single point on a light, chosen with xi, with shadows
+    fn light_sample(&self, light: &Light, info: &HitInfo, view_direction: Vec3, xi: Vec2) -> Vec3 {
+        let (radiance, vec_to_light) = light.sample(info.position, xi);
+
+        let light_dist = vec_to_light.length();
+        let light_dir = vec_to_light / light_dist;
+        let lambert = info.normal.dot(light_dir);
+
+        if lambert <= 0.0 || radiance.length_squared() <= 0.0 {
+            return Vec3::ZERO;
+        }
+
+        let shadow_ray = Ray {
+            origin:    info.position + (info.normal * EPSILON * 5.0),
+            direction: light_dir,
+        };
+        let shade = self.cast_shadow_ray(&shadow_ray, light_dist);
+
+        if shade == Vec3::ZERO {
+            return Vec3::ZERO;
+        }
+
+        shade * brdf(info.normal, light_dir, view_direction, &info.mat, info.colour) * radiance
+    }
+
+    /// The light reflected at a hit from one light, with shadows, averaged over a grid of samples on area lights
+    fn direct_light(&self, light: &Light, info: &HitInfo, view_direction: Vec3) -> Vec3 {
+        let num_samples = if light.is_area_light() {
+            AREA_LIGHT_GRID * AREA_LIGHT_GRID
+        } else {
+            1
+        };
+
+        // rotate the grid by a different amount for each point, so the banding between samples turns into noise
+        let h = hash_position(info.position);
+        let offset = vec2(hash_to_float(h), hash_to_float(pcg_hash(h)));
+
+        let mut total = Vec3::ZERO;
+        for s in 0..num_samples {
+            let cell = vec2((s % AREA_LIGHT_GRID) as f32, (s / AREA_LIGHT_GRID) as f32);
+            let xi = (((cell + 0.5) / AREA_LIGHT_GRID as f32) + offset).fract();
+
+            total += self.light_sample(light, info, view_direction, xi);
+        }
+
+        total / num_samples as f32
+    }
+
+    /// The light coming from the sky in a direction
+    fn sample_sky(&self, direction: Vec3) -> Vec3 { sample_sky(&self.sky, self.textures, direction) }
+
+    /// The light from the sky reaching a point, with shadows, over pi so it's in the same units as radiosity
+    fn sky_irradiance(&self, position: Vec3, normal: Vec3) -> Vec3 {
+        let num_samples = SKY_IRRADIANCE_GRID * SKY_IRRADIANCE_GRID;
+
+        // rotate the grid by a different amount for each point, so the banding between samples turns into noise
+        let h = hash_position(position);
+        let offset = vec2(hash_to_float(h), hash_to_float(pcg_hash(h)));
+
+        let mut total = Vec3::ZERO;
+        for s in 0..num_samples {
+            let cell = vec2((s % SKY_IRRADIANCE_GRID) as f32, (s / SKY_IRRADIANCE_GRID) as f32);
+            let xi = (((cell + 0.5) / SKY_IRRADIANCE_GRID as f32) + offset).fract();
+
+            let (direction, pdf) = self.sky_distribution.sample(xi);
+            let n_dot_l = normal.dot(direction);
+            if n_dot_l <= 0.0 || pdf <= 0.0 {
+                continue;
+            }
+
+            // far enough away that it's never in front of anything
+            let shadow_ray = Ray {
+                origin: position + (normal * EPSILON * 5.0),
+                direction,
+            };
+            let shade = self.cast_shadow_ray(&shadow_ray, 1e6);
+
+            total += shade * self.sample_sky(direction) * n_dot_l / pdf;
+        }
+
+        total / (num_samples as f32 * PI)
+    }
+
+    /// The sky reflected by a glossy surface, with shadows. Smooth surfaces trace their reflections already, so this
+    /// fades in as they get rougher
+    fn sky_specular(&self, info: &HitInfo, view_direction: Vec3) -> Vec3 {
+        let (mat, normal) = (&info.mat, info.normal);
+
+        let smoothness = 1.0 - (mat.roughness / MAX_REFLECTION_ROUGHNESS).clamp(0.0, 1.0);
+        let fade = 1.0 - (smoothness * smoothness);
+        if fade <= 0.0 {
+            return Vec3::ZERO;
+        }
+
+        let v = -view_direction;
+        let roughness = mat.roughness.max(MIN_ROUGHNESS);
+        let alpha = roughness * roughness;
+        let f0 = base_reflectance(mat, info.colour);
+        let n_dot_v = normal.dot(v).max(1e-4);
+
+        let num_samples = SKY_SPECULAR_GRID * SKY_SPECULAR_GRID;
+        let h = hash_position(info.position);
+        let offset = vec2(hash_to_float(h), hash_to_float(pcg_hash(h)));
+
+        let mut total = Vec3::ZERO;
+        for s in 0..num_samples {
+            let cell = vec2((s % SKY_SPECULAR_GRID) as f32, (s / SKY_SPECULAR_GRID) as f32);
+            let xi = (((cell + 0.5) / SKY_SPECULAR_GRID as f32) + offset).fract();
+
+            let m = sample_ggx(normal, alpha, xi);
+            let direction = view_direction.reflect(m);
+            let n_dot_l = normal.dot(direction);
+            if n_dot_l <= 0.0 {
+                continue;
+            }
+
+            let n_dot_h = normal.dot(m).max(1e-4);
+            let v_dot_h = v.dot(m).max(0.0);
+
+            let shadow_ray = Ray {
+                origin: info.position + (normal * EPSILON * 5.0),
+                direction,
+            };
+            let shade = self.cast_shadow_ray(&shadow_ray, 1e6);
+
+            // the brdf times the cosine term, over the pdf of sampling the microfacet normal
+            total += shade
+                * self.sample_sky(direction)
+                * fresnel_schlick(v_dot_h, f0)
+                * geometry_smith(n_dot_v, n_dot_l, alpha)
+                * v_dot_h
+                / (n_dot_v * n_dot_h);
+        }
+
+        fade * total / num_samples as f32
+    }
+
+    /// The light from one direction in the sky, chosen with xi, reflected at a hit, with shadows. Weighted against
+    /// scatter finding the sky itself
+    fn sky_sample(&self, hit: &HitInfo, view_direction: Vec3, xi: Vec2) -> Vec3 {
+        let (direction, pdf) = self.sky_distribution.sample(xi);
+        if pdf <= 0.0 {
+            return Vec3::ZERO;
+        }
+
+        let f = brdf(hit.normal, direction, view_direction, &hit.mat, hit.colour);
+        if f == Vec3::ZERO {
+            return Vec3::ZERO;
+        }
+
+        // far enough away that it's never in front of anything
+        let shadow_ray = Ray {
+            origin: hit.position + (hit.normal * EPSILON * 5.0),
+            direction,
+        };
+        let shade = self.cast_shadow_ray(&shadow_ray, 1e6);
+        if shade == Vec3::ZERO {
+            return Vec3::ZERO;
+        }
+
+        let weight = power_heuristic(
+            pdf,
+            scatter_pdf(hit.normal, direction, view_direction, &hit.mat, hit.colour),
+        );
+
+        // brdf is scaled by pi to match the lights, the sky is in radiance
+        shade * f * self.sample_sky(direction) * weight / (PI * pdf)
+    }
+
+    /// The light reflected towards the viewer at a hit, the normal must face the viewer
+    fn shade_object(&self, direction: Vec3, info: &HitInfo) -> Vec3 {
+        let mat = &info.mat;
+
+        // Sum the direct light from every light
+        let mut direct = self.lights.iter().fold(Vec3::ZERO, |total, light| {
+            total + self.direct_light(light, info, direction)
+        });
+
+        // only diffuse surfaces pick up bounced light
+        let mut indirect = info.radiosity * info.colour * diffuse_weight(mat);
+
+        // There are no lightmaps on the CPU, so the sky's diffuse light is found at every hit, along with its glossy
+        // reflections
+        if self.sky_lights_scene {
+            indirect += self.sky_irradiance(info.position, info.normal) * info.colour * diffuse_weight(mat);
+            direct += self.sky_specular(info, direction);
+        }
+
+        indirect + direct + mat.emissive
+    }
+
+    /// One Monte Carlo sample of the light reaching the camera through uv, the same as path_trace.glsl
+    fn path_trace_pixel(&self, camera: &Camera, uv: Vec2, rng: &mut u32) -> Vec3 {
+        let mut ray = Ray {
+            origin:    camera.position,
+            direction: (camera.rotation * uv.extend(camera.zdepth)).normalize(),
+        };
+
+        let mut colour = Vec3::ZERO;
+        let mut throughput = Vec3::ONE;
+        let mut absorption = Vec3::ZERO;
+        // camera rays can't be picked by sky_sample
+        let mut bsdf_pdf = 0.0;
+        let mut cone = RayCone {
+            width:  0.0,
+            spread: camera.pixel_spread,
+        };
+
+        for bounce in 0..MAX_PATH_BOUNCES {
+            let mut hit = match self.cast_ray(&ray, cone) {
+                Some(hit) => hit,
+                None => {
+                    let weight = if bsdf_pdf > 0.0 {
+                        power_heuristic(bsdf_pdf, self.sky_distribution.pdf(ray.direction))
+                    } else {
+                        1.0
+                    };
+                    colour += throughput * weight * self.sample_sky(ray.direction);
+                    break;
+                }
+            };
+
+            // Beer-Lambert, light is absorbed on its way through a medium
+            let distance = ray.origin.distance(hit.position);
+            throughput *= (-absorption * distance).exp();
+            cone = cone.at(distance);
+
+            let entering = ray.direction.dot(hit.normal) < 0.0;
+            if !entering {
+                hit.normal = -hit.normal;
+            }
+
+            // lights aren't part of the scene, so they can't be hit by a path and are sampled directly at every hit
+            // instead. Emissive objects can be hit, so their light is only added here
+            colour += throughput * hit.mat.emissive;
+            for light in &self.lights {
+                colour += throughput * self.light_sample(light, &hit, ray.direction, random2(rng));
+            }
+
+            // the sky can be hit, but small bright parts of it are rarely found, so it's sampled directly too
+            if hit.mat.transmission * (1.0 - hit.mat.metallic) <= 0.0 {
+                colour += throughput * self.sky_sample(&hit, ray.direction, random2(rng));
+            }
+
+            if !scatter(
+                &mut ray,
+                &hit,
+                entering,
+                &mut throughput,
+                &mut absorption,
+                rng,
+                &mut bsdf_pdf,
+            ) {
+                break;
+            }
+
+            // russian roulette, end paths that won't add much, boosting the rest so it stays unbiased
+            if bounce >= MIN_PATH_BOUNCES {
+                let p = throughput.max_element().clamp(0.05, 1.0);
+                if random(rng) > p {
+                    break;
+                }
+                throughput /= p;
+            }
+        }
+
+        colour
+    }
+
+    /// The instance's material if it has one, otherwise the triangle's from the mesh file, or the default material
+    /// if neither do
+    fn triangle_material(&self, m: &MeshInstance, triangle: &Triangle) -> &Material {
+        &self.materials[m.mat_id.or(triangle.mat_id).unwrap_or(0)]
+    }
+
+    /// One of the material's textures at uv, repeated by its tex_scale. footprint is the width of the area the ray
+    /// sees in uv space, which picks the mip level
+    fn sample_map(&self, tex_id: u32, mat: &Material, uv: Vec2, footprint: f32) -> Vec3 {
+        self.textures[tex_id].sample_footprint(uv * mat.tex_scale, footprint * mat.tex_scale.max_element())
+    }
+
+    fn sample_texture(&self, mat: &Material, uv: Vec2, footprint: f32) -> Vec3 {
+        self.sample_map(mat.tex_id, mat, uv, footprint) * mat.base_colour
+    }
+
+    /// The material with its roughness/metallic and emissive maps applied at uv
+    fn sample_material(&self, mat: &Material, uv: Vec2, footprint: f32) -> Material {
+        let mut mat = *mat;
+        if let Some(tex_id) = mat.metallic_roughness_tex_id {
+            let metallic_roughness = self.sample_map(tex_id, &mat, uv, footprint);
+            mat.roughness *= metallic_roughness.y;
+            mat.metallic *= metallic_roughness.z;
+        }
+        if let Some(tex_id) = mat.emissive_tex_id {
+            mat.emissive *= self.sample_map(tex_id, &mat, uv, footprint);
+        }
+        mat
+    }
+
+    /// How much of the light bounced from other surfaces reaches uv
+    fn sample_occlusion(&self, mat: &Material, uv: Vec2, footprint: f32) -> f32 {
+        mat.occlusion_tex_id
+            .map_or(1.0, |tex_id| self.sample_map(tex_id, mat, uv, footprint).x)
+    }
+
+    /// The normal bent by the material's normal map. tangent points along +u, and its w is the sign of the bitangent
+    fn sample_normal_map(&self, mat: &Material, uv: Vec2, footprint: f32, normal: Vec3, tangent: Vec4) -> Vec3 {
+        let tex_id = match mat.normal_tex_id {
+            Some(tex_id) => tex_id,
+            None => return normal,
+        };
+
+        // tangents can be zero where uvs meet at a point, like a sphere's poles
+        let t = tangent.truncate() - (normal * normal.dot(tangent.truncate()));
+        if t.length_squared() < 1e-8 {
+            return normal;
+        }
+        let t = t.normalize();
+        let b = normal.cross(t) * tangent.w;
+
+        let n = self.sample_map(tex_id, mat, uv, footprint) * 2.0 - Vec3::ONE;
+        // +y is up the image, and v goes down it
+        ((t * n.x) - (b * n.y) + (normal * n.z)).normalize()
+    }
+}
+
+impl Ray {
+    /// The direction isn't renormalised, so distances along the ray are the same in both spaces
+    fn to_object_space(&self, inv_model: &Mat4) -> Ray {
+        Ray {
+            origin:    inv_model.transform_point3(self.origin),
+            direction: inv_model.transform_vector3(self.direction),
+        }
+    }
+}
+
+impl Plane {
+    /// The uv of a point on the plane, 0..1 across its width and height
+    fn uv(&self, position: Vec3) -> Vec2 {
+        let bitangent = self.normal.cross(self.tangent);
+        let delta = position - self.position;
+        vec2(0.5, 0.5) + vec2(self.tangent.dot(delta) / self.width, bitangent.dot(delta) / self.height)
+    }
+}
+
+/// Normals are transformed by the inverse transpose of the model matrix
+fn normal_to_world(normal: Vec3, inv_model: &Mat4) -> Vec3 {
+    (Mat3::from_mat4(*inv_model).transpose() * normal).normalize()
+}
+
+/// The ray must be in the sphere's object space
+fn ray_sphere_intersect(ray: &Ray, radius: f32) -> f32 {
+    // vector from sphere center to ray origin.
+    let c_to_o = ray.origin;
+
+    // quadratic formula constants for line-sphere intersection
+    let a = ray.direction.dot(ray.direction);
+    let b = 2.0 * ray.direction.dot(c_to_o);
+    let c = c_to_o.dot(c_to_o) - (radius * radius);
+
+    // distance of two intersection points
+    let discrim = (b * b) - (4.0 * a * c);
+    if discrim < 0.0 {
+        return f32::MAX;
+    }
+
+    // now solve the quadratic, using a more stable computer friendly formula
+    let q = -0.5 * (b + (b.signum() * discrim.sqrt()));
+    let (d0, d1) = (q / a, c / q);
+    let (d0, d1) = if d0 > d1 { (d1, d0) } else { (d0, d1) };
+
+    // negative distances mean we intersect behind, we want d0 to be the positive intersection
+    if d0 >= 0.0 {
+        d0
+    } else if d1 >= 0.0 {
+        d1
+    } else {
+        f32::MAX
+    }
+}
+
+fn ray_plane_intersect(ray: &Ray, plane: &Plane) -> f32 {
+    let denom = -plane.normal.dot(ray.direction);
+    if denom <= 1e-6 {
+        return f32::MAX;
+    }
+
+    let to_plane = plane.position - ray.origin;
+    let d = to_plane.dot(-plane.normal) / denom;
+    if d < 0.0 {
+        return f32::MAX;
+    }
+
+    let bitangent = plane.normal.cross(plane.tangent);
+    let position = ray.origin + (d * ray.direction);
+
+    let delta = position - plane.position;
+    let xy = vec2(0.5 * plane.width, 0.5 * plane.height) + vec2(plane.tangent.dot(delta), bitangent.dot(delta));
+
+    if xy.x > plane.width || xy.x < 0.0 || xy.y > plane.height || xy.y < 0.0 {
+        f32::MAX
+    } else {
+        d
+    }
+}
+
+/// Möller–Trumbore intersection, returns the distance and the barycentric coordinates of the hit
+fn ray_triangle_intersect(ray: &Ray, p1: Vec3, p2: Vec3, p3: Vec3) -> (f32, f32, f32) {
+    let edge1 = p2 - p1;
+    let edge2 = p3 - p1;
+
+    let h = ray.direction.cross(edge2);
+    let det = h.dot(edge1);
+
+    let inv_det = 1.0 / det;
+    let s = ray.origin - p1;
+    let u = inv_det * s.dot(h);
+
+    if !(0.0..=1.0).contains(&u) {
+        return (f32::MAX, u, 0.0); // outside triangle
+    }
+
+    let q = s.cross(edge1);
+    let v = inv_det * ray.direction.dot(q);
+
+    if v < 0.0 || (u + v) > 1.0 {
+        return (f32::MAX, u, v);
+    }
+
+    let t = inv_det * edge2.dot(q);
+
+    if t < 0.0 {
+        (f32::MAX, u, v) // intersection behind
+    } else {
+        (t, u, v)
+    }
+}
+
+// TODO: Blinn Phong?
+// Schlick's approximation of how much light is reflected rather than refracted
+fn fresnel_schlick(cos_theta: f32, f0: Vec3) -> Vec3 { f0 + ((1.0 - f0) * (1.0 - cos_theta).clamp(0.0, 1.0).powi(5)) }
+
+// Fraction of light reflected at the boundary between two dielectrics, eta is the ratio of their iors (from / to)
+// and cos_i is for the incoming ray. 1.0 when there is total internal reflection
+fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+    let sin_t2 = eta * eta * (1.0 - (cos_i * cos_i));
+    if sin_t2 >= 1.0 {
+        return 1.0;
+    }
+
+    // Schlick's approximation, using the angle on the less dense side
+    let cos_t = (1.0 - sin_t2).sqrt();
+    let r = (eta - 1.0) / (eta + 1.0);
+    let f0 = r * r;
+    f0 + ((1.0 - f0) * (1.0 - if eta > 1.0 { cos_t } else { cos_i }).powi(5))
+}
+
+// How much of the light hitting a surface is scattered diffusely, metals and transmissive materials have none
+fn diffuse_weight(mat: &Material) -> f32 { (1.0 - mat.metallic) * (1.0 - mat.transmission) }
+
+// Reflectance at normal incidence, dielectrics get it from their ior and metals from their colour
+fn base_reflectance(mat: &Material, albedo: Vec3) -> Vec3 {
+    let r = (mat.ior - 1.0) / (mat.ior + 1.0);
+    Vec3::splat(r * r).lerp(albedo, mat.metallic)
+}
+
+// GGX/Trowbridge-Reitz normal distribution
+fn distribution_ggx(n_dot_h: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let d = (n_dot_h * n_dot_h * (a2 - 1.0)) + 1.0;
+    a2 / (PI * d * d)
+}
+
+// Smith masking-shadowing, with the Schlick-GGX approximation
+fn geometry_smith(n_dot_v: f32, n_dot_l: f32, alpha: f32) -> f32 {
+    let k = alpha / 2.0;
+    let g_v = n_dot_v / ((n_dot_v * (1.0 - k)) + k);
+    let g_l = n_dot_l / ((n_dot_l * (1.0 - k)) + k);
+    g_v * g_l
+}
+
+// Cook-Torrance specular with a lambertian diffuse lobe, times the cosine term.
+// Lights are in units where a white lambertian surface facing one reflects all of its intensity,
+// so this is scaled by pi to match. light_dir must be normalized, view_direction points at the surface
+fn brdf(normal: Vec3, light_dir: Vec3, view_direction: Vec3, mat: &Material, albedo: Vec3) -> Vec3 {
+    let n_dot_l = normal.dot(light_dir);
+    if n_dot_l <= 0.0 {
+        return Vec3::ZERO;
+    }
+
+    let v = -view_direction;
+    let h = (light_dir + v).normalize();
+    let n_dot_v = normal.dot(v).max(1e-4);
+    let n_dot_h = normal.dot(h).max(0.0);
+    let v_dot_h = v.dot(h).max(0.0);
+
+    let roughness = mat.roughness.max(MIN_ROUGHNESS);
+    let alpha = roughness * roughness;
+
+    let f = fresnel_schlick(v_dot_h, base_reflectance(mat, albedo));
+    let specular =
+        f * distribution_ggx(n_dot_h, alpha) * geometry_smith(n_dot_v, n_dot_l, alpha) / (4.0 * n_dot_v * n_dot_l);
+    let diffuse = (1.0 - f) * diffuse_weight(mat) * albedo / PI;
+
+    PI * (diffuse + specular) * n_dot_l
+}
+
+// How much of its surroundings a surface reflects, fading out as it gets rougher.
+// Also returns how much is refracted through it, and the direction.
+// normal must face the incoming ray, entering is whether it's going into the object
+fn reflection_weight(
+    normal: Vec3, view_direction: Vec3, mat: &Material, albedo: Vec3, entering: bool,
+) -> (Vec3, Vec3, Vec3) {
+    let smoothness = 1.0 - (mat.roughness / MAX_REFLECTION_ROUGHNESS).clamp(0.0, 1.0);
+    let cos_i = normal.dot(-view_direction).max(0.0);
+
+    let f = fresnel_schlick(cos_i, base_reflectance(mat, albedo));
+    let mut reflect_weight = f * smoothness * smoothness;
+
+    // TODO: blur refraction through rough surfaces
+    let transmissive = mat.transmission * (1.0 - mat.metallic);
+    let mut refract_weight = Vec3::ZERO;
+    let mut refracted = Vec3::ZERO;
+
+    if transmissive > 0.0 {
+        let eta = if entering { 1.0 / mat.ior } else { mat.ior };
+        let f_dielectric = fresnel_dielectric(cos_i, eta);
+
+        if f_dielectric < 1.0 {
+            refracted = refract(view_direction, normal, eta);
+            refract_weight = (1.0 - f_dielectric) * transmissive * albedo;
+        }
+
+        // the light that isn't refracted is all reflected
+        reflect_weight = reflect_weight.lerp(Vec3::splat(f_dielectric), transmissive);
+    }
+
+    (reflect_weight, refract_weight, refracted)
+}
+
+// The same as glsl's refract, eta is the ratio of the iors
+fn refract(incident: Vec3, normal: Vec3, eta: f32) -> Vec3 {
+    let n_dot_i = normal.dot(incident);
+    let k = 1.0 - (eta * eta * (1.0 - (n_dot_i * n_dot_i)));
+    if k < 0.0 {
+        Vec3::ZERO
+    } else {
+        (eta * incident) - (((eta * n_dot_i) + k.sqrt()) * normal)
+    }
+}
+
+fn is_visible(transmission: Vec3) -> bool { transmission.max_element() > 1e-3 }
+
+// How much light gets through an object on its way to a light, after travelling thickness through it.
+// Transmissive objects let some through, tinted by their colour and absorbed along the way (Beer-Lambert).
+// Refraction isn't followed, so there are no caustics
+fn shadow_transmittance(mat: &Material, thickness: f32) -> Vec3 {
+    let transmissive = mat.transmission * (1.0 - mat.metallic);
+    transmissive * mat.base_colour * (-mat.absorption * thickness).exp()
+}
+
+impl Light {
+    fn is_area_light(&self) -> bool { matches!(self.light_type, LightType::Rect | LightType::Sphere) }
+
+    /// Picks a point on the light to shade position from, using xi (0.0..1.0) to choose it on area lights.
+    /// Returns the light arriving from that point, ignoring shadows, and the vector to it
+    fn sample(&self, position: Vec3, xi: Vec2) -> (Vec3, Vec3) {
+        let mut radiance = self.colour * self.intensity;
+
+        if self.light_type == LightType::Directional {
+            // far enough away that it's never in front of anything
+            return (radiance, -self.direction * 1e6);
+        }
+
+        let mut light_pos = self.position;
+        if self.light_type == LightType::Rect {
+            light_pos += ((xi.x - 0.5) * self.tangent) + ((xi.y - 0.5) * self.bitangent);
+        } else if self.light_type == LightType::Sphere {
+            // a point on the sphere, flipped onto the side facing us
+            let z = 1.0 - (2.0 * xi.x);
+            let r = (1.0 - (z * z)).max(0.0).sqrt();
+            let phi = std::f32::consts::TAU * xi.y;
+            let mut dir = vec3(r * phi.cos(), r * phi.sin(), z);
+            if dir.dot(position - self.position) < 0.0 {
+                dir = -dir;
+            }
+
+            light_pos += self.radius * dir;
+        }
+
+        let vec_to_light = light_pos - position;
+        let dist_sqd = vec_to_light.dot(vec_to_light);
+        let light_dir = vec_to_light / dist_sqd.sqrt();
+        radiance /= dist_sqd;
+
+        if self.light_type == LightType::Spot {
+            let cos_angle = (-light_dir).dot(self.direction);
+            radiance *= smoothstep((cos_angle - self.cos_outer) / (self.cos_inner - self.cos_outer));
+        } else if self.light_type == LightType::Rect {
+            // one sided, and dimmer at grazing angles
+            radiance *= (-light_dir).dot(self.direction).max(0.0);
+        }
+
+        (radiance, vec_to_light)
+    }
+}
+
+// Builds a basis around n and moves v into it, from "Building an Orthonormal Basis, Revisited" (Duff et al.)
+fn to_basis(v: Vec3, n: Vec3) -> Vec3 {
+    let s = if n.z >= 0.0 { 1.0 } else { -1.0 };
+    let a = -1.0 / (s + n.z);
+    let b = n.x * n.y * a;
+    let tangent = vec3(1.0 + (s * n.x * n.x * a), s * b, -s * n.x);
+    let bitangent = vec3(b, s + (n.y * n.y * a), -n.y);
+    (v.x * tangent) + (v.y * bitangent) + (v.z * n)
+}
+
+fn sample_cosine_hemisphere(normal: Vec3, xi: Vec2) -> Vec3 {
+    let r = xi.x.sqrt();
+    let phi = std::f32::consts::TAU * xi.y;
+    to_basis(vec3(r * phi.cos(), r * phi.sin(), (1.0 - xi.x).sqrt()), normal)
+}
+
+// A microfacet normal from the GGX distribution
+fn sample_ggx(normal: Vec3, alpha: f32, xi: Vec2) -> Vec3 {
+    let phi = std::f32::consts::TAU * xi.x;
+    let cos_theta = ((1.0 - xi.y) / (1.0 + (((alpha * alpha) - 1.0) * xi.y))).sqrt();
+    let sin_theta = (1.0 - (cos_theta * cos_theta)).max(0.0).sqrt();
+    to_basis(vec3(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta), normal)
+}
+
+// Weights between two ways of picking the same direction, from their pdfs
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let (a, b) = (pdf * pdf, other_pdf * other_pdf);
+    if a + b > 0.0 {
+        a / (a + b)
+    } else {
+        0.0
+    }
+}
+
+// The pdf over solid angle of scatter picking light_dir from a material that isn't transmissive.
+// light_dir must be normalized, view_direction points at the surface
+fn scatter_pdf(normal: Vec3, light_dir: Vec3, view_direction: Vec3, mat: &Material, albedo: Vec3) -> f32 {
+    let n_dot_l = normal.dot(light_dir);
+    if n_dot_l <= 0.0 {
+        return 0.0;
+    }
+
+    let v = -view_direction;
+    let h = (light_dir + v).normalize();
+    let n_dot_h = normal.dot(h).max(1e-4);
+    let v_dot_h = v.dot(h).max(1e-4);
+
+    let roughness = mat.roughness.max(MIN_ROUGHNESS);
+    let alpha = roughness * roughness;
+
+    let f = fresnel_schlick(normal.dot(v).max(0.0), base_reflectance(mat, albedo));
+    let average_f = (f.x + f.y + f.z) / 3.0;
+    let p_specular = (average_f + ((1.0 - average_f) * mat.metallic)).clamp(0.1, 1.0);
+
+    // the microfacet normal's pdf, moved over to the reflected direction
+    let specular_pdf = distribution_ggx(n_dot_h, alpha) * n_dot_h / (4.0 * v_dot_h);
+    let diffuse_pdf = n_dot_l / PI;
+
+    (diffuse_pdf * (1.0 - p_specular)) + (specular_pdf * p_specular)
+}
+
+// Picks the direction a path carries on in after a hit, and multiplies throughput by how much light comes back
+// along it. Returns false if the path ends. The normal must face the incoming ray. bsdf_pdf is the pdf of the
+// direction for weighting against sky_sample, 0.0 when sky_sample doesn't cover it
+fn scatter(
+    ray: &mut Ray, hit: &HitInfo, entering: bool, throughput: &mut Vec3, absorption: &mut Vec3, rng: &mut u32,
+    bsdf_pdf: &mut f32,
+) -> bool {
+    let mat = &hit.mat;
+    let albedo = hit.colour;
+    let normal = hit.normal;
+    let v = -ray.direction;
+
+    let roughness = mat.roughness.max(MIN_ROUGHNESS);
+    let alpha = roughness * roughness;
+    let h = sample_ggx(normal, alpha, random2(rng));
+    let v_dot_h = v.dot(h).max(0.0);
+
+    let transmissive = mat.transmission * (1.0 - mat.metallic);
+
+    let direction = if random(rng) < transmissive {
+        // through a dielectric, split between reflection and refraction by the fresnel term.
+        // The masking term is ignored here
+        let eta = if entering { mat.ior.recip() } else { mat.ior };
+
+        if random(rng) < fresnel_dielectric(v_dot_h, eta) {
+            ray.direction.reflect(h)
+        } else {
+            *throughput *= albedo;
+            *absorption = if entering { mat.absorption } else { Vec3::ZERO };
+            refract(ray.direction, h, eta)
+        }
+    } else {
+        // the rest is a specular lobe over a diffuse one, pick one of them by roughly how much they reflect
+        let f0 = base_reflectance(mat, albedo);
+        let f = fresnel_schlick(normal.dot(v).max(0.0), f0);
+        let average_f = (f.x + f.y + f.z) / 3.0;
+        let p_specular = (average_f + ((1.0 - average_f) * mat.metallic)).clamp(0.1, 1.0);
+
+        if random(rng) < p_specular {
+            let direction = ray.direction.reflect(h);
+
+            let n_dot_l = normal.dot(direction);
+            let n_dot_v = normal.dot(v).max(1e-4);
+            let n_dot_h = normal.dot(h).max(1e-4);
+            if n_dot_l <= 0.0 {
+                return false;
+            }
+
+            // the brdf times the cosine term, over the pdf of sampling the microfacet normal
+            *throughput *= fresnel_schlick(v_dot_h, f0) * geometry_smith(n_dot_v, n_dot_l, alpha) * v_dot_h
+                / (n_dot_v * n_dot_h * p_specular);
+            direction
+        } else {
+            // only the part of the material that isn't transmissive is left to be diffuse
+            *throughput *= (1.0 - f) * albedo * diffuse_weight(mat) / ((1.0 - transmissive) * (1.0 - p_specular));
+            sample_cosine_hemisphere(normal, random2(rng))
+        }
+    };
+
+    if direction == Vec3::ZERO {
+        return false;
+    }
+
+    // brdf, which sky_sample uses, doesn't cover what transmissive materials reflect and refract, so the sky is only
+    // found by scattering from them
+    *bsdf_pdf = if transmissive > 0.0 {
+        0.0
+    } else {
+        scatter_pdf(normal, direction, ray.direction, mat, albedo)
+    };
+
+    // nudge the ray off the surface
+    *ray = Ray {
+        origin: hit.position + (direction * EPSILON * 3.0),
+        direction,
+    };
+    true
+}
+
+// Hashing for noise, the same as random.glsl so both renderers get the same pattern
+
+// PCG hash, from "Hash Functions for GPU Rendering" (Jarzynski and Olano)
+fn pcg_hash(v: u32) -> u32 {
+    let state = v.wrapping_mul(747796405).wrapping_add(2891336453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+    (word >> 22) ^ word
+}
+
+fn hash_position(p: Vec3) -> u32 { pcg_hash(p.x.to_bits() ^ pcg_hash(p.y.to_bits() ^ pcg_hash(p.z.to_bits()))) }
+
+// 0.0..1.0
+fn hash_to_float(h: u32) -> f32 { (h as f64 / 4294967296.0) as f32 }
+
+// 0.0..1.0, advancing the state so the next call gives a new number
+fn random(state: &mut u32) -> f32 {
+    *state = pcg_hash(*state);
+    hash_to_float(*state)
+}
+
+fn random2(state: &mut u32) -> Vec2 { vec2(random(state), random(state)) }
+
+fn aces_tonemap(colour: Vec3) -> Vec3 {
+    // column major, the same as the GLSL mat3 constructor
+    let m1 = Mat3::from_cols_array(&[
+        0.59719, 0.07600, 0.02840, 0.35458, 0.90834, 0.13383, 0.04823, 0.01566, 0.83777,
+    ]);
+    let m2 = Mat3::from_cols_array(&[
+        1.60475, -0.10208, -0.00327, -0.53108, 1.10813, -0.07276, -0.07367, -0.00605, 1.07602,
+    ]);
+    let v = m1 * colour;
+    let a = v * (v + 0.0245786) - 0.000090537;
+    let b = v * (0.983729 * v + 0.432951) + 0.238081;
+
+    m2 * (a / b)
+}
+
+fn dither(x: usize, y: usize, colour_depth: f32, colour: Vec3) -> Vec3 {
+    const DITHER_SCALE: f32 = 2.0;
+
+    // go from 0..64 to -0.5..0.5
+    let dither_amount = BAYER[x % 8][y % 8];
+    colour.normalize_or_zero() * (DITHER_SCALE * (dither_amount - 32) as f32 / 64.0) / colour_depth
+}
+
+fn smoothstep(x: f32) -> f32 {
+    let t = x.clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
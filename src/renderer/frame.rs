@@ -0,0 +1,55 @@
+use std::path::Path;
+
+use image::{DynamicImage, ImageResult, Rgba, Rgba32FImage, RgbaImage};
+
+use super::linear_to_srgb;
+
+/// A rendered frame read back from a renderer, as linear RGBA floats in rows from top to bottom. It's already
+/// tonemapped and dithered for display, so the values are between 0 and 1
+pub struct Frame {
+    pub width:  u32,
+    pub height: u32,
+    pub data:   Vec<f32>,
+}
+
+impl Frame {
+    pub fn new(width: u32, height: u32, data: Vec<f32>) -> Self { Self { width, height, data } }
+
+    /// Saves the frame to an image file, the format is chosen from the extension.
+    /// `.exr` files are saved as linear floats without rounding to 8 bits, anything else is converted to 8 bit sRGB.
+    /// Either way it's the tonemapped image, not the HDR radiance before it.
+    pub fn save(&self, path: &str) -> ImageResult<()> {
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+
+        if extension.as_deref() == Some("exr") {
+            self.to_rgba32f().save(path)
+        } else {
+            DynamicImage::ImageRgba8(self.to_rgba8()).save(path)
+        }
+    }
+
+    pub fn to_rgba32f(&self) -> Rgba32FImage {
+        Rgba32FImage::from_fn(self.width, self.height, |x, y| {
+            let [r, g, b] = self.get_pixel(x, y);
+            // the shader doesn't write a meaningful alpha, so the frame is opaque
+            Rgba([r, g, b, 1.0])
+        })
+    }
+
+    pub fn to_rgba8(&self) -> RgbaImage {
+        RgbaImage::from_fn(self.width, self.height, |x, y| {
+            let [r, g, b] = self
+                .get_pixel(x, y)
+                .map(|c| (linear_to_srgb(c.clamp(0.0, 1.0)) * 255.0).round() as u8);
+            Rgba([r, g, b, 255])
+        })
+    }
+
+    pub fn get_pixel(&self, x: u32, y: u32) -> [f32; 3] {
+        let idx = ((y * self.width + x) * 4) as usize;
+        [self.data[idx], self.data[idx + 1], self.data[idx + 2]]
+    }
+}
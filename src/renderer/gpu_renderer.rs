@@ -15,35 +15,86 @@ use crate::{
 };
 
 use log::debug;
-use render_mod::ty::{MeshInstance, Plane, PointLight, Sphere, Triangle, Vertex};
-use vulkano::sampler::{Filter, SamplerAddressMode, SamplerCreateInfo};
+use render_mod::ty::{BvhNode, Light, MeshInstance, ObjectRef, Plane, Sphere, Triangle, Vertex};
+use vulkano::{
+    format::Format,
+    sampler::{Filter, SamplerAddressMode, SamplerCreateInfo, SamplerMipmapMode, LOD_CLAMP_NONE},
+};
 
 use super::{
-    srgb_to_linear, CameraComponent, MaterialComponent, MeshRenderComponent, PlaneRenderComponent, PointLightComponent,
-    SphereRenderComponent, Texture, TransformComponent,
+    srgb_to_linear, Aabb, AssetStore, Bvh, CameraComponent, Frame, Handle, Material, MaterialComponent, MaterialKey,
+    MaterialStore, MeshError, MeshRenderComponent, PlaneRenderComponent, RenderMode, Renderer, Sky, SkyBoxComponent,
+    SphereRenderComponent, Texture, TextureAddressMode, TextureData, TextureError, TextureFilter, TextureSampler,
+    TextureStore, TransformComponent,
 };
 
+use super::lights::gather_lights;
+use super::sky::{normalize_sun_direction, SkyDistribution};
+
 const RESOLUTION_U: u32 = 2;
 const RESOLUTION_V: u32 = 2;
 const LM_WIDTH: u32 = 18 * RESOLUTION_U;
 const LM_HEIGHT: u32 = 18 * RESOLUTION_V;
 
+// Same as the object types in defines.glsl
+const OBJ_SPHERE: u32 = 0;
+const OBJ_PLANE: u32 = 1;
+const OBJ_MESH: u32 = 2;
+const OBJ_NONE: u32 = 3;
+
+// Same as NO_MATERIAL and NO_TEXTURE in defines.glsl
+const NO_MATERIAL: u32 = u32::MAX;
+const NO_TEXTURE: u32 = u32::MAX;
+
+// Same as the sky types in defines.glsl
+const SKY_COLOUR: u32 = 0;
+const SKY_EQUIRECT: u32 = 1;
+const SKY_CUBEMAP: u32 = 2;
+const SKY_PROCEDURAL: u32 = 3;
+
+// Same as the render modes in defines.glsl
+const RENDER_LIGHTMAP: u32 = 0;
+const RENDER_PATH_TRACED: u32 = 1;
+
 pub struct GPURenderer {
     backend: Arc<RefCell<VkBackend>>,
 
     sphere_buffer: Arc<Buffer<Sphere>>,
     plane_buffer:  Arc<Buffer<Plane>>,
-    lights_buffer: Arc<Buffer<PointLight>>,
-
-    texture_paths: Vec<String>,
-    albedo_array:  Arc<TextureArray>,
-
-    mesh_paths:           Vec<String>,
-    meshes:               Vec<Mesh>,
+    lights_buffer: Arc<Buffer<Light>>,
+    sky_buffer:    Arc<Buffer<render_mod::ty::Sky>>,
+
+    /// The sky box the distribution in `sky_cdf_buffer` was built for, `None` for the default sky
+    sky_box:        Option<SkyBoxComponent>,
+    sky_cdf_buffer: Arc<Buffer<f32>>,
+
+    /// Kept on the CPU as well, to build the sky's distribution from
+    textures:     TextureStore,
+    /// Indexed by texture id, unloaded textures are left as a single texel until their id is reused
+    albedo_array: Arc<TextureArray>,
+
+    materials:         MaterialStore,
+    /// The textures each material uses by id, so they stay loaded for as long as the material
+    material_textures: Vec<Vec<Handle<Texture>>>,
+    /// Material 0, which objects without their own use, held so it's never unloaded
+    _basic_material:   Option<Handle<Material>>,
+    material_buffer:   Arc<Buffer<render_mod::ty::Material>>,
+    /// Whether the material buffer needs uploading again before the next frame
+    materials_changed: bool,
+
+    meshes:               AssetStore<Mesh>,
+    /// Where each mesh's triangles, vertices and BVH nodes start in the buffers, by mesh id
+    mesh_starts:          Vec<[u32; 3]>,
     vertex_buffer:        Arc<Buffer<Vertex>>,
     triangle_buffer:      Arc<Buffer<Triangle>>,
+    bvh_buffer:           Arc<Buffer<BvhNode>>,
     mesh_instance_buffer: Arc<Buffer<MeshInstance>>,
 
+    tlas_buffer:   Arc<Buffer<BvhNode>>,
+    object_buffer: Arc<Buffer<ObjectRef>>,
+    /// Object bounds the top level BVH was last built from, so it's only rebuilt when they change
+    tlas_bounds:   Option<Vec<Aabb>>,
+
     radiosity_computed: bool,
     current_emissives:  Arc<ImageArray>,
     new_emissives:      Arc<ImageArray>,
@@ -52,6 +103,12 @@ pub struct GPURenderer {
     sample_albedos:     Arc<ImageArray>,
     sample_normals:     Arc<ImageArray>,
     sample_sizes:       Arc<ImageArray>,
+
+    render_mode:  RenderMode,
+    sample_count: u32,
+    accumulation: Arc<ImageArray>,
+    /// Everything uploaded for the last frame, path tracing starts again when it changes
+    frame_state:  Vec<u8>,
 }
 
 //TODO: report variable descriptor bug
@@ -65,15 +122,18 @@ impl GPURenderer {
         let sphere_buffer = backend.borrow().gen_buffer(1);
         let plane_buffer = backend.borrow().gen_buffer(1);
         let lights_buffer = backend.borrow().gen_buffer(1);
+        let sky_buffer = backend.borrow().gen_buffer(1);
+        let sky_cdf_buffer = backend.borrow().gen_buffer(1);
+        let material_buffer = backend.borrow().gen_buffer(1);
 
         let vertex_buffer = backend.borrow().gen_buffer(1);
         let triangle_buffer = backend.borrow().gen_buffer(1);
+        let bvh_buffer = backend.borrow().gen_buffer(1);
         let mesh_instance_buffer = backend.borrow().gen_buffer(1);
 
-        let tex_sampler = Arc::new(Sampler::new(
-            backend.clone(),
-            SamplerCreateInfo::simple_repeat_linear_no_mipmap(),
-        ));
+        let tlas_buffer = backend.borrow().gen_buffer(1);
+        let object_buffer = backend.borrow().gen_buffer(1);
+
         let albedo_array = Arc::new(TextureArray::new(backend.clone()));
 
         let lm_sampler = Arc::new(Sampler::new(
@@ -94,6 +154,10 @@ impl GPURenderer {
         let sample_normals = Arc::new(ImageArray::new(backend.clone()));
         let sample_sizes = Arc::new(ImageArray::new(backend.clone()));
 
+        let accumulation = Arc::new(ImageArray::new(backend.clone()));
+        let [width, height] = backend.borrow().dimensions();
+        accumulation.push_image(width, height);
+
         let render_mod = render_mod::load(backend.borrow().device.clone()).unwrap();
         let radiosity_mod = radiosity_mod::load(backend.borrow().device.clone()).unwrap();
 
@@ -106,9 +170,16 @@ impl GPURenderer {
                 vertex_buffer.clone(),
                 triangle_buffer.clone(),
                 mesh_instance_buffer.clone(),
+                bvh_buffer.clone(),
+                tlas_buffer.clone(),
+                object_buffer.clone(),
+                material_buffer.clone(),
+                sky_buffer.clone(),
+                sky_cdf_buffer.clone(),
             ]),
-            Set::new(&[tex_sampler.clone(), albedo_array.clone()]),
+            Set::new(&[albedo_array.clone()]),
             Set::new(&[lm_sampler, lightmaps.clone()]),
+            Set::new(&[accumulation.clone()]),
         ];
         let render_shader = Shader::load_from_module(render_mod, &render_shader_sets);
 
@@ -120,8 +191,14 @@ impl GPURenderer {
                 vertex_buffer.clone(),
                 triangle_buffer.clone(),
                 mesh_instance_buffer.clone(),
+                bvh_buffer.clone(),
+                tlas_buffer.clone(),
+                object_buffer.clone(),
+                material_buffer.clone(),
+                sky_buffer.clone(),
+                sky_cdf_buffer.clone(),
             ]),
-            Set::new(&[tex_sampler, albedo_array.clone()]),
+            Set::new(&[albedo_array.clone()]),
             Set::new(&[current_emissives.clone()]),
             Set::new(&[new_emissives.clone()]),
             Set::new(&[lightmaps.clone()]),
@@ -136,22 +213,40 @@ impl GPURenderer {
             .borrow_mut()
             .compute_setup(vec![radiosity_shader, render_shader]);
 
+        // the default sky is a plain colour, so it doesn't need any textures
+        sky_cdf_buffer.write(&SkyDistribution::new(&SkyBoxComponent::default(), &TextureStore::new()).cdf);
+
         let mut renderer = Self {
             backend,
 
             sphere_buffer,
             plane_buffer,
             lights_buffer,
+            sky_buffer,
 
-            texture_paths: vec![],
+            sky_box: None,
+            sky_cdf_buffer,
+
+            textures: TextureStore::new(),
             albedo_array,
 
-            mesh_paths: vec![],
-            meshes: vec![],
+            materials: MaterialStore::new(),
+            material_textures: vec![],
+            _basic_material: None,
+            material_buffer,
+            materials_changed: false,
+
+            meshes: AssetStore::new(),
+            mesh_starts: vec![],
             vertex_buffer,
             triangle_buffer,
+            bvh_buffer,
             mesh_instance_buffer,
 
+            tlas_buffer,
+            object_buffer,
+            tlas_bounds: None,
+
             radiosity_computed: false,
             current_emissives,
             new_emissives,
@@ -160,9 +255,15 @@ impl GPURenderer {
             sample_albedos,
             sample_normals,
             sample_sizes,
+
+            render_mode: RenderMode::default(),
+            sample_count: 0,
+            accumulation,
+            frame_state: vec![],
         };
 
         renderer.get_texture_by_colour(soft_blue!());
+        renderer._basic_material = Some(renderer.get_material(Material::basic()));
         renderer
     }
 
@@ -178,75 +279,252 @@ impl GPURenderer {
 
     fn get_lightmap_len(&self) -> u32 { self.lightmaps.variable_descriptor_count() }
 
-    pub fn get_texture_by_path(&mut self, path: &str) -> u32 {
-        if let Some(idx) = self.texture_paths.iter().position(|x| x == path) {
-            idx as u32
-        } else {
-            debug!("Loading texture from path \"{}\"", path);
-            let tex = Texture::from_path(path);
-            self.albedo_array.push_texture(tex.width, tex.height, tex.data);
+    /// Uploads a texture into the slot for its id
+    fn upload_texture(&self, tex_id: u32, texture: &Texture) {
+        let (data, format) = texture_upload(texture);
+        self.albedo_array.set_texture(
+            tex_id as usize,
+            texture.width,
+            texture.height,
+            data,
+            format,
+            (&texture.sampler).into(),
+        );
+    }
+
+    fn insert_texture(&mut self, name: &str, key: Option<(String, bool)>, texture: Texture) -> Handle<Texture> {
+        let handle = self.textures.insert(name, key, texture);
+        self.upload_texture(handle.id(), &self.textures[handle.id()]);
+        handle
+    }
 
-            self.texture_paths.push(path.to_owned());
-            (self.texture_paths.len() - 1) as u32
+    /// Handles to the textures a material uses
+    fn texture_handles(&self, material: &Material) -> Vec<Handle<Texture>> {
+        material
+            .texture_ids()
+            .filter_map(|id| self.textures.handle(id))
+            .collect()
+    }
+
+    /// Uploads every mesh's vertices, triangles and BVH nodes one after another, as they're indexed from one buffer
+    //TODO: We are reuploading all vertices everytime a mesh changes, a bit inefficient
+    fn upload_meshes(&mut self) {
+        let (mut vertices, mut triangles, mut bvh_nodes) = (vec![], vec![], vec![]);
+        self.mesh_starts = vec![[0; 3]; self.meshes.id_count() as usize];
+
+        for (mesh_id, mesh) in self.meshes.iter() {
+            self.mesh_starts[mesh_id as usize] =
+                [triangles.len() as u32, vertices.len() as u32, bvh_nodes.len() as u32];
+
+            vertices.extend(mesh.vertices.iter().map(Vertex::from));
+            triangles.extend(mesh.triangles.iter().map(|t| Triangle {
+                v1_idx: t.v1_idx,
+                v2_idx: t.v2_idx,
+                v3_idx: t.v3_idx,
+                mat_id: t.mat_id.unwrap_or(NO_MATERIAL),
+            }));
+            bvh_nodes.extend(mesh.bvh.nodes.iter().map(BvhNode::from));
+        }
+
+        // buffers can't be empty
+        if !vertices.is_empty() {
+            self.vertex_buffer.write(&vertices);
+            self.triangle_buffer.write(&triangles);
+            self.bvh_buffer.write(&bvh_nodes);
         }
     }
-    pub fn get_texture_by_colour(&mut self, colour: Vec3) -> u32 {
-        let path = format!(
-            "colour/{},{},{}",
-            (colour.x * 1024.0).round() as u16,
-            (colour.y * 1024.0).round() as u16,
-            (colour.z * 1024.0).round() as u16
-        );
-        if let Some(idx) = self.texture_paths.iter().position(|x| x == &path) {
-            idx as u32
+
+    /// Rebuilds the top level BVH over every object, if any of their bounds have changed since the last frame
+    fn update_tlas(&mut self, objects: Vec<ObjectRef>, bounds: Vec<Aabb>) {
+        if self.tlas_bounds.as_ref() == Some(&bounds) {
+            return;
+        }
+
+        let (tlas, order) = Bvh::build(&bounds);
+        debug!("Rebuilt top level BVH over {} objects", bounds.len());
+
+        if tlas.is_empty() {
+            // the shaders always start from a root node, so give them a leaf with nothing in it to hit
+            let leaf = super::BvhNode {
+                aabb:       Aabb::EMPTY,
+                left_first: 0,
+                count:      1,
+            };
+            self.tlas_buffer.write(&[(&leaf).into()]);
+            self.object_buffer.write(&[ObjectRef {
+                obj_type: OBJ_NONE,
+                obj_idx:  0,
+            }]);
         } else {
-            let tex = Texture::from_colour_srgb(colour);
-            self.albedo_array.push_texture(tex.width, tex.height, tex.data);
+            let nodes = tlas.nodes.iter().map(|n| n.into()).collect::<Vec<_>>();
+            self.tlas_buffer.write(&nodes);
+            let objects = order.iter().map(|&i| objects[i as usize]).collect::<Vec<_>>();
+            self.object_buffer.write(&objects);
+        }
 
-            self.texture_paths.push(path);
-            (self.texture_paths.len() - 1) as u32
+        self.tlas_bounds = Some(bounds);
+    }
+}
+
+impl Renderer for GPURenderer {
+    fn get_texture_by_path(&mut self, path: &str) -> Result<Handle<Texture>, TextureError> {
+        let key = (path.to_owned(), false);
+        if let Some(handle) = self.textures.find(&key) {
+            Ok(handle)
+        } else {
+            debug!("Loading texture from path \"{}\"", path);
+            Ok(self.insert_texture(path, Some(key), Texture::from_path(path)?))
+        }
+    }
+    fn get_data_texture_by_path(&mut self, path: &str) -> Result<Handle<Texture>, TextureError> {
+        let key = (path.to_owned(), true);
+        if let Some(handle) = self.textures.find(&key) {
+            Ok(handle)
+        } else {
+            debug!("Loading data texture from path \"{}\"", path);
+            Ok(self.insert_texture(path, Some(key), Texture::from_data_path(path)?))
+        }
+    }
+    fn get_texture_by_colour(&mut self, colour: Vec3) -> Handle<Texture> {
+        let path = Texture::colour_path(colour);
+        let key = (path.clone(), false);
+        if let Some(handle) = self.textures.find(&key) {
+            handle
+        } else {
+            self.insert_texture(&path, Some(key), Texture::from_colour_srgb(colour))
         }
     }
 
-    pub fn get_mesh_by_path(&mut self, path: &str) -> u32 {
-        if let Some(idx) = self.mesh_paths.iter().position(|x| x == path) {
-            idx as u32
+    fn get_mesh_by_path(&mut self, path: &str) -> Result<Handle<Mesh>, MeshError> {
+        if let Some(handle) = self.meshes.find(&path.to_owned()) {
+            Ok(handle)
         } else {
             debug!("Loading mesh from path \"{}\"", path);
 
-            let mesh = Mesh::from_path(path);
-            self.meshes.push(mesh);
+            let mesh = Mesh::from_path(path, |m| m.load(self))?;
+            let handle = self.meshes.insert(path, Some(path.to_owned()), mesh);
+            self.upload_meshes();
+            Ok(handle)
+        }
+    }
 
-            //TODO: We are reuploading all vertices everytime a mesh is added, a bit inefficient
-            let vertices = self
-                .meshes
-                .iter()
-                .flat_map(|m| m.vertices.iter().map(|v| v.into()).collect::<Vec<_>>())
-                .collect::<Vec<_>>();
-            self.vertex_buffer.write(&vertices);
+    fn add_texture(&mut self, name: &str, texture: Texture) -> Handle<Texture> {
+        self.insert_texture(name, None, texture)
+    }
+    fn add_mesh(&mut self, name: &str, mesh: Mesh) -> Handle<Mesh> {
+        let handle = self.meshes.insert(name, None, mesh);
+        self.upload_meshes();
+        handle
+    }
 
-            let triangles = self
-                .meshes
-                .iter()
-                .flat_map(|m| {
-                    m.triangles
-                        .iter()
-                        .map(|t| Triangle {
-                            v1_idx: t.v1_idx,
-                            v2_idx: t.v2_idx,
-                            v3_idx: t.v3_idx,
-                        })
-                        .collect::<Vec<_>>()
-                })
-                .collect::<Vec<_>>();
-            self.triangle_buffer.write(&triangles);
+    fn replace_texture(&mut self, texture: &Handle<Texture>, replacement: Texture) {
+        let sampler = self.textures[texture.id()].sampler;
+        self.textures.replace(texture, replacement.with_sampler(sampler));
+        self.upload_texture(texture.id(), &self.textures[texture.id()]);
+
+        // the sky's distribution is built from its textures, and the lightmaps from all of them
+        self.sky_box = None;
+        self.radiosity_computed = false;
+        self.sample_count = 0;
+    }
+    fn replace_mesh(&mut self, mesh: &Handle<Mesh>, replacement: Mesh) {
+        self.meshes.replace(mesh, replacement);
+        self.upload_meshes();
+
+        self.radiosity_computed = false;
+        self.sample_count = 0;
+    }
 
-            self.mesh_paths.push(path.to_owned());
-            (self.mesh_paths.len() - 1) as u32
+    fn unload_unused(&mut self) {
+        // the last frame's sky is kept to tell when it changes, which would keep its textures loaded
+        self.sky_box = None;
+
+        // meshes hold on to their materials, and materials to their textures
+        let meshes = self.meshes.unload_unused();
+        if !meshes.is_empty() {
+            self.upload_meshes();
+        }
+        let materials = self.materials.unload_unused();
+        for &mat_id in &materials {
+            self.material_textures[mat_id as usize].clear();
+        }
+
+        // the array can't have gaps, so unloaded textures are swapped for a single texel to free their memory
+        let textures = self.textures.unload_unused();
+        let placeholder = Texture::from_colour_srgb(Vec3::ZERO);
+        for &tex_id in &textures {
+            self.upload_texture(tex_id, &placeholder);
+        }
+
+        debug!(
+            "Unloaded {} meshes, {} materials and {} textures",
+            meshes.len(),
+            materials.len(),
+            textures.len()
+        );
+    }
+
+    fn get_material(&mut self, material: Material) -> Handle<Material> {
+        let key = MaterialKey::from(&material);
+        if let Some(handle) = self.materials.find(&key) {
+            handle
+        } else {
+            let handle = self.materials.insert("", Some(key), material);
+            self.material_textures
+                .resize_with(self.materials.id_count() as usize, Vec::new);
+            self.material_textures[handle.id() as usize] = self.texture_handles(&material);
+            self.materials_changed = true;
+            handle
         }
     }
 
-    pub fn draw(&mut self, scene: &mut Scene) {
+    fn get_texture_path(&self, tex_id: u32) -> Option<&str> { self.textures.name(tex_id) }
+    fn get_mesh_path(&self, mesh_id: u32) -> Option<&str> { self.meshes.name(mesh_id) }
+    fn get_material_data(&self, mat_id: u32) -> Option<&Material> { self.materials.get(mat_id) }
+
+    fn set_material(&mut self, mat_id: u32, material: Material) {
+        match self.materials.get_mut(mat_id) {
+            Some(m) => *m = material,
+            None => return,
+        }
+        self.materials.set_key(mat_id, Some(MaterialKey::from(&material)));
+        self.material_textures[mat_id as usize] = self.texture_handles(&material);
+        self.materials_changed = true;
+    }
+
+    fn get_texture_sampler(&self, tex_id: u32) -> Option<TextureSampler> {
+        self.textures.get(tex_id).map(|t| t.sampler)
+    }
+
+    fn set_texture_sampler(&mut self, tex_id: u32, sampler: TextureSampler) {
+        let texture = match self.textures.get_mut(tex_id) {
+            Some(texture) => texture,
+            None => return,
+        };
+        texture.sampler = sampler;
+        self.albedo_array.set_sampler(tex_id as usize, (&sampler).into());
+
+        // lightmaps are baked from the textures too
+        self.radiosity_computed = false;
+        self.sample_count = 0;
+    }
+
+    fn read_frame(&self) -> Frame {
+        let mut backend = self.backend.borrow_mut();
+        let [width, height] = backend.dimensions();
+        Frame::new(width, height, backend.read_frame())
+    }
+
+    fn draw(&mut self, scene: &mut Scene) {
+        // nothing is drawn until the frames can be resized, like after the window has been minimised
+        let pending_size = self.backend.borrow().pending_size();
+        if let Some([width, height]) = pending_size {
+            self.resize(width, height);
+            if self.backend.borrow().pending_size().is_some() {
+                return;
+            }
+        }
+
         // get the first camera from the query
         let (_, (camera_transform, camera_component)) = scene
             .query_mut::<(&TransformComponent, &CameraComponent)>()
@@ -261,77 +539,147 @@ impl GPURenderer {
         let camera_zdepth = (fov_deg * 0.5).to_radians().tan().recip();
         let camera_rotation = Mat4::from_mat3(rot_mat.transpose()).to_cols_array_2d();
 
-        //TODO: materials are an index into another buffer
-
-        let spheres = scene
+        let (spheres, sphere_bounds): (Vec<_>, Vec<_>) = scene
             .query_mut::<(&TransformComponent, &SphereRenderComponent, &MaterialComponent)>()
             .into_iter()
-            .map(|(_, (t, s, m))| Sphere {
-                position: t.position.to_array(),
-                radius: s.radius,
-                mat: m.into(),
-                ..Default::default()
+            .map(|(_, (t, s, m))| {
+                let model = t.model_matrix();
+                let sphere = Sphere {
+                    model: model.to_cols_array_2d(),
+                    inv_model: model.inverse().to_cols_array_2d(),
+                    radius: s.radius,
+                    mat_id: m.material.id(),
+                    ..Default::default()
+                };
+                (sphere, Aabb::from_sphere(Vec3::ZERO, s.radius).transform(model))
             })
-            .collect::<Vec<_>>();
+            .unzip();
 
         let planes = scene
             .query_mut::<(&TransformComponent, &PlaneRenderComponent, &MaterialComponent)>()
             .into_iter()
-            .map(|(_, (t, p, m))| Plane {
-                position: t.position.to_array(),
-                normal: p.normal.to_array(),
-                tangent: p.tangent.to_array(),
-                mat: m.into(),
-                width: p.width,
-                height: p.height,
-                ..Default::default()
+            .map(|(_, (t, p, m))| {
+                let p = p.to_world(t);
+                Plane {
+                    position: t.position.to_array(),
+                    normal: p.normal.to_array(),
+                    tangent: p.tangent.to_array(),
+                    mat_id: m.material.id(),
+                    width: p.width,
+                    height: p.height,
+                    ..Default::default()
+                }
             })
             .collect::<Vec<_>>();
 
-        let lights = scene
-            .query_mut::<(&TransformComponent, &PointLightComponent)>()
+        let lights = gather_lights(scene).iter().map(Light::from).collect::<Vec<_>>();
+
+        // only a sky that's been added lights the scene, the default one is just a background
+        let sky_box = scene
+            .query_mut::<&SkyBoxComponent>()
             .into_iter()
-            .map(|(_, (t, p))| PointLight {
-                position:  t.position.to_array(),
-                intensity: p.intensity,
-            })
-            .collect::<Vec<_>>();
+            .next()
+            .map(|(_, s)| s.clone());
+        if sky_box != self.sky_box {
+            debug!("Building the sky's distribution");
+            let distribution = SkyDistribution::new(&sky_box.clone().unwrap_or_default(), &self.textures);
+            self.sky_cdf_buffer.write(&distribution.cdf);
+            self.sky_box = sky_box.clone();
+
+            // the sky goes into the lightmaps
+            self.radiosity_computed = false;
+        }
+        let sky = render_mod::ty::Sky {
+            lights_scene: sky_box.is_some() as u32,
+            ..(&sky_box.unwrap_or_default()).into()
+        };
 
-        let mesh_instances = scene
-            .query_mut::<(&TransformComponent, &MeshRenderComponent, &MaterialComponent)>()
+        let (mesh_instances, mesh_bounds): (Vec<_>, Vec<_>) = scene
+            .query_mut::<(&TransformComponent, &MeshRenderComponent, Option<&MaterialComponent>)>()
             .into_iter()
             .map(|(_, (t, mesh, mat))| {
-                //TODO: Model space to world space transform
-                // Probably upload a mesh buffer which has positions, scale, lm_id
-                // also eventually unify all id's
-
-                let mut start_triangle_idx = 0;
-                let mut start_vertex_idx = 0;
-                for i in 0..mesh.mesh_id {
-                    start_triangle_idx += self.meshes[i as usize].len_triangles();
-                    start_vertex_idx += self.meshes[i as usize].len_vertices();
-                }
-                MeshInstance {
-                    position: t.position.to_array(),
+                //TODO: eventually unify all id's
+
+                let [start_triangle_idx, start_vertex_idx, start_node_idx] = self.mesh_starts[mesh.mesh.id() as usize];
+                let model = t.model_matrix();
+                let instance = MeshInstance {
+                    model: model.to_cols_array_2d(),
+                    inv_model: model.inverse().to_cols_array_2d(),
                     start_triangle_idx,
                     start_vertex_idx,
-                    num_triangles: self.meshes[mesh.mesh_id as usize].len_triangles(),
-                    mat: mat.into(),
-                }
+                    num_triangles: self.meshes[mesh.mesh.id()].len_triangles(),
+                    start_node_idx,
+                    // the materials from the mesh file are used if it doesn't have its own
+                    mat_id: mat.map_or(NO_MATERIAL, |m| m.material.id()),
+                    ..Default::default()
+                };
+                let bounds = self.meshes[mesh.mesh.id()].bvh.bounds().transform(model);
+                (instance, bounds)
+            })
+            .unzip();
+
+        // Objects are ordered spheres, then planes, then mesh instances, the same as their lightmaps
+        let objects = (0..spheres.len())
+            .map(|i| (OBJ_SPHERE, i))
+            .chain((0..planes.len()).map(|i| (OBJ_PLANE, i)))
+            .chain((0..mesh_instances.len()).map(|i| (OBJ_MESH, i)))
+            .map(|(obj_type, i)| ObjectRef {
+                obj_type,
+                obj_idx: i as u32,
             })
             .collect::<Vec<_>>();
+        let bounds = sphere_bounds
+            .into_iter()
+            .chain(
+                planes
+                    .iter()
+                    .map(|p| Aabb::from_rect(p.position.into(), p.normal.into(), p.tangent.into(), p.width, p.height)),
+            )
+            .chain(mesh_bounds)
+            .collect::<Vec<_>>();
+        self.update_tlas(objects, bounds);
 
+        if self.materials_changed {
+            // unloaded materials leave gaps, as the ids index the buffer
+            let mut materials = vec![render_mod::ty::Material::default(); self.materials.id_count() as usize];
+            for (mat_id, material) in self.materials.iter() {
+                materials[mat_id as usize] = material.into();
+            }
+            self.material_buffer.write(&materials);
+            self.materials_changed = false;
+            self.sample_count = 0;
+        }
+        // buffers can't be empty, the shaders go by `counts` instead of their lengths
         if !spheres.is_empty() {
             self.sphere_buffer.write(&spheres);
         }
-        self.plane_buffer.write(&planes);
-        self.lights_buffer.write(&lights);
+        if !planes.is_empty() {
+            self.plane_buffer.write(&planes);
+        }
+        if !lights.is_empty() {
+            self.lights_buffer.write(&lights);
+        }
+        self.sky_buffer.write(&[sky]);
         if !mesh_instances.is_empty() {
             self.mesh_instance_buffer.write(&mesh_instances);
         }
 
-        //TODO: broken if there are only planes in scene
-        //TODO: probably fixed by sending over object ids
+        // path tracing starts again whenever anything that's drawn changes
+        let frame_state = [
+            bytemuck::cast_slice(&camera_position),
+            bytemuck::bytes_of(&camera_zdepth),
+            bytemuck::cast_slice(&camera_rotation),
+            bytemuck::cast_slice(&spheres),
+            bytemuck::cast_slice(&planes),
+            bytemuck::cast_slice(&lights),
+            bytemuck::bytes_of(&sky),
+            bytemuck::cast_slice(&mesh_instances),
+        ]
+        .concat();
+        if frame_state != self.frame_state {
+            self.sample_count = 0;
+            self.frame_state = frame_state;
+        }
 
         //TODO: if objects are added or removed then they won't necessarily have the right sized lightmap
         // due to the inability to remove or resize lightmaps at a position.
@@ -340,6 +688,14 @@ impl GPURenderer {
         let num_planes = planes.len();
         let num_mesh_instances = mesh_instances.len();
         let num_objs = num_spheres + num_planes + num_mesh_instances;
+        let counts = render_mod::ty::ObjectCounts {
+            spheres:        num_spheres as u32,
+            planes:         num_planes as u32,
+            mesh_instances: num_mesh_instances as u32,
+            lights:         lights.len() as u32,
+        };
+        // the same struct from structs.glsl, generated for each shader
+        let radiosity_counts = bytemuck::cast(counts);
         if num_objs > num_lightmaps {
             let delta = num_objs - num_lightmaps;
             for i in 0..delta {
@@ -364,10 +720,16 @@ impl GPURenderer {
 
             let dispatch_size = DispatchSize::Custom(LM_WIDTH, LM_HEIGHT, num_objs as u32); //TODO: correct dispatch size
 
+            let stage = |stage| {
+                Some(radiosity_mod::ty::Constants {
+                    stage,
+                    counts: radiosity_counts,
+                })
+            };
             builder
-                .add_shader_execution(0, dispatch_size, Some(radiosity_mod::ty::Constants { stage: 0 }))
-                .add_shader_execution(0, dispatch_size, Some(radiosity_mod::ty::Constants { stage: 1 }))
-                .add_shader_execution(0, dispatch_size, Some(radiosity_mod::ty::Constants { stage: 2 }));
+                .add_shader_execution(0, dispatch_size, stage(0))
+                .add_shader_execution(0, dispatch_size, stage(1))
+                .add_shader_execution(0, dispatch_size, stage(2));
             //.add_shader_execution(0, dispatch_size, Some(radiosity_mod::ty::Constants { stage: 1 }))
             //.add_shader_execution(0, dispatch_size, Some(radiosity_mod::ty::Constants { stage: 2 }));
         }
@@ -378,41 +740,196 @@ impl GPURenderer {
                 camera_position,
                 camera_rotation,
                 camera_zdepth,
+                render_mode: match self.render_mode {
+                    RenderMode::Lightmap => RENDER_LIGHTMAP,
+                    RenderMode::PathTraced => RENDER_PATH_TRACED,
+                },
+                sample_count: self.sample_count,
+                counts,
             }),
         );
 
         builder.submit();
+
+        if self.render_mode == RenderMode::PathTraced {
+            self.sample_count += 1;
+        }
+    }
+
+    fn resize(&mut self, width: u32, height: u32) {
+        if !self.backend.borrow_mut().resize(width, height) {
+            return;
+        }
+
+        // the surface may not have taken the size asked for
+        let [width, height] = self.backend.borrow().dimensions();
+        self.accumulation.clear();
+        self.accumulation.push_image(width, height);
+        self.sample_count = 0;
+    }
+
+    fn render_mode(&self) -> RenderMode { self.render_mode }
+    fn set_render_mode(&mut self, mode: RenderMode) {
+        if mode != self.render_mode {
+            self.render_mode = mode;
+            self.sample_count = 0;
+        }
+    }
+
+    fn sample_count(&self) -> u32 {
+        match self.render_mode {
+            RenderMode::Lightmap => 1,
+            RenderMode::PathTraced => self.sample_count,
+        }
+    }
+}
+
+impl From<&super::lights::Light> for Light {
+    fn from(l: &super::lights::Light) -> Self {
+        Self {
+            position:   l.position.to_array(),
+            light_type: l.light_type as u32,
+            direction:  l.direction.to_array(),
+            intensity:  l.intensity,
+            colour:     l.colour.to_array(),
+            radius:     l.radius,
+            tangent:    l.tangent.to_array(),
+            cos_inner:  l.cos_inner,
+            bitangent:  l.bitangent.to_array(),
+            cos_outer:  l.cos_outer,
+        }
     }
 }
 
-impl From<&MaterialComponent> for render_mod::ty::Material {
-    fn from(m: &MaterialComponent) -> Self {
+impl From<&Material> for render_mod::ty::Material {
+    fn from(m: &Material) -> Self {
         Self {
             tex_id: m.tex_id,
             tex_scale: m.tex_scale.to_array(),
-            ambient: m.ambient,
-            diffuse: m.diffuse,
-            specular: m.specular,
-            shininess: m.shininess,
-            reflectivity: m.reflectivity,
-            emissive: m.emissive,
+            base_colour: m.base_colour.to_array(),
+            metallic: m.metallic,
+            emissive: m.emissive.to_array(),
+            roughness: m.roughness,
+            ior: m.ior,
+            transmission: m.transmission,
+            absorption: m.absorption.to_array(),
+
+            normal_tex_id: m.normal_tex_id.unwrap_or(NO_TEXTURE),
+            metallic_roughness_tex_id: m.metallic_roughness_tex_id.unwrap_or(NO_TEXTURE),
+            emissive_tex_id: m.emissive_tex_id.unwrap_or(NO_TEXTURE),
+            occlusion_tex_id: m.occlusion_tex_id.unwrap_or(NO_TEXTURE),
+            alpha_cutoff: m.alpha_cutoff.unwrap_or(0.0),
 
             ..Default::default()
         }
     }
 }
 
+/// The texture's texels as bytes, and the format they're in. sRGB textures are turned linear as they're sampled
+fn texture_upload(texture: &Texture) -> (Vec<u8>, Format) {
+    match &texture.data {
+        TextureData::Srgb8(data) => (data.clone(), Format::R8G8B8A8_SRGB),
+        TextureData::Unorm8(data) => (data.clone(), Format::R8G8B8A8_UNORM),
+        TextureData::Half(data) => (bytemuck::cast_slice(data).to_vec(), Format::R16G16B16A16_SFLOAT),
+    }
+}
+
+impl From<&TextureSampler> for SamplerCreateInfo {
+    fn from(s: &TextureSampler) -> Self {
+        let filter = |f| match f {
+            TextureFilter::Nearest => Filter::Nearest,
+            TextureFilter::Linear => Filter::Linear,
+        };
+        let address_mode = |a| match a {
+            TextureAddressMode::Repeat => SamplerAddressMode::Repeat,
+            TextureAddressMode::MirroredRepeat => SamplerAddressMode::MirroredRepeat,
+            TextureAddressMode::ClampToEdge => SamplerAddressMode::ClampToEdge,
+        };
+
+        Self {
+            mag_filter: filter(s.mag_filter),
+            min_filter: filter(s.min_filter),
+            mipmap_mode: match s.mipmap_filter {
+                Some(TextureFilter::Linear) => SamplerMipmapMode::Linear,
+                _ => SamplerMipmapMode::Nearest,
+            },
+            address_mode: [
+                address_mode(s.address_u),
+                address_mode(s.address_v),
+                SamplerAddressMode::Repeat,
+            ],
+            // without mipmapping, stay on the full size level
+            lod: if s.mipmap_filter.is_some() {
+                0.0..=LOD_CLAMP_NONE
+            } else {
+                0.0..=0.0
+            },
+            ..Default::default()
+        }
+    }
+}
+
+impl From<&SkyBoxComponent> for render_mod::ty::Sky {
+    fn from(s: &SkyBoxComponent) -> Self {
+        let sky = Self {
+            intensity: s.intensity,
+            rotation: s.rotation.to_radians(),
+            ..Default::default()
+        };
+
+        match &s.sky {
+            Sky::Colour(colour) => Self {
+                sky_type: SKY_COLOUR,
+                colour: colour.to_array(),
+                ..sky
+            },
+            Sky::Equirect { texture } => Self {
+                sky_type: SKY_EQUIRECT,
+                tex_ids: [texture.id(); 6],
+                ..sky
+            },
+            Sky::Cubemap { textures } => Self {
+                sky_type: SKY_CUBEMAP,
+                tex_ids: textures.each_ref().map(Handle::id),
+                ..sky
+            },
+            Sky::Procedural {
+                sun_direction,
+                turbidity,
+            } => Self {
+                sky_type: SKY_PROCEDURAL,
+                sun_direction: normalize_sun_direction(*sun_direction).to_array(),
+                turbidity: *turbidity,
+                ..sky
+            },
+        }
+    }
+}
+
 impl From<&super::Vertex> for render_mod::ty::Vertex {
     fn from(v: &super::Vertex) -> Self {
         Self {
             position: v.position.to_array(),
             normal: v.normal.to_array(),
             uv: v.uv.to_array(),
+            colour: v.colour.to_array(),
+            tangent: v.tangent.to_array(),
             ..Default::default()
         }
     }
 }
 
+impl From<&super::BvhNode> for BvhNode {
+    fn from(n: &super::BvhNode) -> Self {
+        Self {
+            aabb_min:   n.aabb.min.to_array(),
+            left_first: n.left_first,
+            aabb_max:   n.aabb.max.to_array(),
+            count:      n.count,
+        }
+    }
+}
+
 //TODO: get backend to deal with this (at runtime?)
 #[allow(clippy::needless_question_mark)]
 mod render_mod {
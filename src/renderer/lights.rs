@@ -0,0 +1,121 @@
+// The scene's lights, shared by both renderers. Mirrors the Light struct in structs.glsl
+//
+// Each kind of light has its own component, they're gathered into one list here. The GPU renderer converts it to
+// the shader's structs to upload.
+
+use super::{
+    AreaLightComponent, AreaLightShape, DirectionalLightComponent, PlaneRenderComponent, PointLightComponent,
+    SpotLightComponent, TransformComponent,
+};
+use crate::{scene::Scene, Vec3};
+
+// Same as the light types in defines.glsl
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u32)]
+pub(super) enum LightType {
+    Point,
+    Directional,
+    Spot,
+    Rect,
+    Sphere,
+}
+
+/// Every type of light. `direction` is the way directional and spot lights shine, and the normal of rect lights.
+/// `tangent` and `bitangent` are the full edges of rect lights.
+#[derive(Clone, PartialEq)]
+pub(super) struct Light {
+    pub(super) light_type: LightType,
+    pub(super) position:   Vec3,
+    pub(super) direction:  Vec3,
+    pub(super) colour:     Vec3,
+    pub(super) intensity:  f32,
+    pub(super) radius:     f32,
+    pub(super) tangent:    Vec3,
+    pub(super) bitangent:  Vec3,
+    pub(super) cos_inner:  f32,
+    pub(super) cos_outer:  f32,
+}
+
+impl Default for Light {
+    fn default() -> Self {
+        Self {
+            light_type: LightType::Point,
+            position:   Vec3::ZERO,
+            direction:  Vec3::ZERO,
+            colour:     Vec3::ONE,
+            intensity:  0.0,
+            radius:     0.0,
+            tangent:    Vec3::ZERO,
+            bitangent:  Vec3::ZERO,
+            cos_inner:  0.0,
+            cos_outer:  0.0,
+        }
+    }
+}
+
+/// Every kind of light component in the scene, as one list
+pub(super) fn gather_lights(scene: &mut Scene) -> Vec<Light> {
+    let mut lights = vec![];
+
+    for (_, (t, p)) in scene.query_mut::<(&TransformComponent, &PointLightComponent)>() {
+        lights.push(Light {
+            position: t.position,
+            colour: p.colour,
+            intensity: p.intensity,
+            ..Default::default()
+        });
+    }
+
+    for (_, d) in scene.query_mut::<&DirectionalLightComponent>() {
+        lights.push(Light {
+            light_type: LightType::Directional,
+            direction: d.direction.normalize(),
+            colour: d.colour,
+            intensity: d.intensity,
+            ..Default::default()
+        });
+    }
+
+    for (_, (t, s)) in scene.query_mut::<(&TransformComponent, &SpotLightComponent)>() {
+        let (cos_inner, cos_outer) = s.cone_cosines();
+        lights.push(Light {
+            light_type: LightType::Spot,
+            position: t.position,
+            direction: (t.rotation * s.direction).normalize(),
+            colour: s.colour,
+            intensity: s.intensity,
+            cos_inner,
+            cos_outer,
+            ..Default::default()
+        });
+    }
+
+    for (_, (t, a)) in scene.query_mut::<(&TransformComponent, &AreaLightComponent)>() {
+        let light = Light {
+            position: t.position,
+            colour: a.colour,
+            intensity: a.intensity,
+            ..Default::default()
+        };
+
+        lights.push(match a.shape {
+            AreaLightShape::Rect { normal, width, height } => {
+                let rect = PlaneRenderComponent::new(normal, width, height).to_world(t);
+                Light {
+                    light_type: LightType::Rect,
+                    direction: rect.normal,
+                    tangent: rect.tangent * rect.width,
+                    bitangent: rect.bitangent * rect.height,
+                    ..light
+                }
+            }
+            AreaLightShape::Sphere { radius } => Light {
+                light_type: LightType::Sphere,
+                radius: radius * t.scale.max_element(),
+                ..light
+            },
+        });
+    }
+
+    lights
+}
@@ -1,77 +1,542 @@
-use std::iter::zip;
+use std::{
+    error::Error,
+    fmt, io,
+    path::{Path, PathBuf},
+};
 
-use log::debug;
+use log::{debug, warn};
 use tobj::{load_obj, GPU_LOAD_OPTIONS};
 
-use crate::{vec2, vec3, Vec2, Vec3};
+use super::{
+    ply::load_ply, stl::load_stl, Aabb, Bvh, Handle, LegacyMaterial, Material, Renderer, Texture, TextureError,
+};
+use crate::{vec2, vec3, Vec2, Vec3, Vec4};
+
+/// Faces meeting at a sharper angle than this, in degrees, get separate normals where they meet when normals are
+/// generated. 0 would make every face flat, and 180 would smooth everything
+pub const CREASE_ANGLE: f32 = 60.0;
 
 #[derive(Debug)]
 pub struct Mesh {
     pub vertices:  Vec<Vertex>,
+    /// Ordered so that each BVH leaf refers to a contiguous range of triangles
     pub triangles: Vec<Triangle>,
+    pub bvh:       Bvh,
+    /// The materials the triangles use, which stay loaded for as long as the mesh
+    pub materials: Vec<Handle<Material>>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct Vertex {
     pub position: Vec3,
     pub normal:   Vec3,
     pub uv:       Vec2,
+    /// Tints the texture, white unless the mesh file has colours
+    pub colour:   Vec3,
+    /// Points along +u, w is the sign of the bitangent (+v) for mirrored uvs. Always generated
+    pub tangent:  Vec4,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct Triangle {
     pub v1_idx: u32,
     pub v2_idx: u32,
     pub v3_idx: u32,
+    /// The material the mesh file gives it, unless the mesh instance has its own
+    pub mat_id: Option<u32>,
+}
+
+/// Which vertex attributes came from the mesh file, `Mesh::new` generates the rest
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VertexAttributes {
+    pub normals: bool,
+    pub uvs:     bool,
+}
+
+/// A material from a mesh file, with the paths of its textures as they haven't been loaded yet
+#[derive(Debug, Clone)]
+pub struct MeshMaterial {
+    pub material:         Material,
+    pub texture:          Option<String>,
+    /// From map_Bump
+    pub normal_texture:   Option<String>,
+    /// From map_Ke
+    pub emissive_texture: Option<String>,
+}
+
+impl Vertex {
+    /// A vertex with only a position, for loaders to fill in the rest of
+    pub fn new(position: Vec3) -> Self {
+        Self {
+            position,
+            normal: Vec3::ZERO,
+            uv: Vec2::ZERO,
+            colour: Vec3::ONE,
+            tangent: Vec4::ZERO,
+        }
+    }
 }
 
 impl Mesh {
-    pub fn from_path(path: &str) -> Self {
-        let (models, materials_res) =
-            load_obj(path, &GPU_LOAD_OPTIONS).unwrap_or_else(|_| panic!("Failed to load models from {}", path));
-        let materials = materials_res.unwrap_or_else(|_| panic!("Failed to load materials for {}", path));
-
-        debug!("Loaded {} models from {}", models.len(), path);
-        let model = models.get(0).unwrap();
-        debug!("Using model {} for {}", model.name, path);
-
-        let positions = &model.mesh.positions;
-        let normals = &model.mesh.normals;
-        let uvs = &model.mesh.texcoords;
-        let indices = &model.mesh.indices;
-
-        let mut vertices = Vec::with_capacity(positions.len() / 3);
-        let mut triangles = Vec::with_capacity(indices.len() / 3);
-
-        for (pos_slice, (normal_slice, uv_slice)) in zip(
-            positions.chunks_exact(3),
-            zip(normals.chunks_exact(3), uvs.chunks_exact(2)),
-        ) {
-            // Hint to compiler the sice of each slice
-            let pos_slice = &pos_slice[0..3];
-            let normal_slice = &normal_slice[0..3];
-            let uv_slice = &uv_slice[0..2];
-
-            let position = vec3(pos_slice[0], pos_slice[1], pos_slice[2]);
-            let normal = vec3(normal_slice[0], normal_slice[1], normal_slice[2]);
-            let uv = vec2(uv_slice[0], uv_slice[1]);
-
-            vertices.push(Vertex { position, normal, uv });
+    /// Loads a mesh file, the format is chosen from the extension: OBJ (the default), PLY or STL.
+    /// Each material in the file is given to `add_material`, which adds it for the triangles that use it
+    pub fn from_path(
+        path: &str, add_material: impl FnMut(MeshMaterial) -> Handle<Material>,
+    ) -> Result<Self, MeshError> {
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+
+        // PLY and STL files have no materials
+        let (vertices, triangles, attributes) = match extension.as_str() {
+            "ply" => load_ply(path)?,
+            "stl" => load_stl(path)?,
+            _ => return Self::from_obj(path, add_material),
+        };
+
+        debug!(
+            "Loaded {} vertices and {} triangles from {}",
+            vertices.len(),
+            triangles.len(),
+            path
+        );
+        Self::new(vertices, triangles, attributes)
+    }
+
+    /// Loads every model in an OBJ file as one mesh. Each material in its MTL file is given to `add_material`,
+    /// which adds it for the triangles that use it
+    fn from_obj(path: &str, mut add_material: impl FnMut(MeshMaterial) -> Handle<Material>) -> Result<Self, MeshError> {
+        let (models, materials_res) = load_obj(path, &GPU_LOAD_OPTIONS).map_err(|e| MeshError::Parse(e.to_string()))?;
+        let materials = materials_res.unwrap_or_else(|e| {
+            warn!("Failed to load materials for {}: {}", path, e);
+            vec![]
+        });
+
+        debug!(
+            "Loaded {} models and {} materials from {}",
+            models.len(),
+            materials.len(),
+            path
+        );
+
+        let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+        let mesh_materials = materials
+            .iter()
+            .map(|m| add_material(MeshMaterial::from_mtl(m, dir)))
+            .collect::<Vec<_>>();
+
+        let mut vertices = vec![];
+        let mut triangles = vec![];
+        // only kept if every model has them
+        let mut attributes = VertexAttributes {
+            normals: true,
+            uvs:     true,
+        };
+
+        for model in &models {
+            let positions = &model.mesh.positions;
+            let normals = &model.mesh.normals;
+            let uvs = &model.mesh.texcoords;
+            let indices = &model.mesh.indices;
+
+            // the models' indices each start from 0
+            let start_vertex_idx = vertices.len() as u32;
+            let mat_id = model
+                .mesh
+                .material_id
+                .and_then(|i| mesh_materials.get(i))
+                .map(Handle::id);
+
+            vertices.extend(positions.chunks_exact(3).map(|p| Vertex::new(vec3(p[0], p[1], p[2]))));
+            let model_vertices = &mut vertices[start_vertex_idx as usize..];
+
+            // tobj leaves out normals and uvs that aren't in the file, rather than filling them in
+            if normals.len() == positions.len() {
+                for (vertex, n) in model_vertices.iter_mut().zip(normals.chunks_exact(3)) {
+                    vertex.normal = vec3(n[0], n[1], n[2]);
+                }
+            } else {
+                attributes.normals = false;
+            }
+            if uvs.len() / 2 == positions.len() / 3 {
+                for (vertex, uv) in model_vertices.iter_mut().zip(uvs.chunks_exact(2)) {
+                    vertex.uv = vec2(uv[0], uv[1]);
+                }
+            } else {
+                attributes.uvs = false;
+            }
+
+            triangles.reserve(indices.len() / 3);
+            for idx_slice in indices.chunks_exact(3) {
+                let idx_slice = &idx_slice[0..3];
+
+                let v1_idx = start_vertex_idx + idx_slice[0];
+                let v2_idx = start_vertex_idx + idx_slice[1];
+                let v3_idx = start_vertex_idx + idx_slice[2];
+
+                triangles.push(Triangle {
+                    v1_idx,
+                    v2_idx,
+                    v3_idx,
+                    mat_id,
+                })
+            }
+        }
+
+        Ok(Self {
+            materials: mesh_materials,
+            ..Self::new(vertices, triangles, attributes)?
+        })
+    }
+
+    /// Checks the mesh is usable, generates the attributes the file didn't have along with tangents,
+    /// then builds the BVH over the triangles and reorders them to match it
+    pub fn new(
+        mut vertices: Vec<Vertex>, mut triangles: Vec<Triangle>, mut attributes: VertexAttributes,
+    ) -> Result<Self, MeshError> {
+        Self::validate(&vertices, &triangles)?;
+
+        if attributes.normals {
+            for vertex in &mut vertices {
+                vertex.normal = vertex.normal.normalize_or_zero();
+            }
+            if vertices.iter().any(|v| v.normal == Vec3::ZERO) {
+                warn!("Mesh has invalid normals, generating them instead");
+                attributes.normals = false;
+            }
+        }
+
+        if !attributes.normals {
+            Self::generate_normals(&mut vertices, &mut triangles, CREASE_ANGLE);
+        }
+        if !attributes.uvs {
+            Self::planar_uvs(&mut vertices);
+        }
+        Self::generate_tangents(&mut vertices, &triangles);
+
+        let bounds = triangles
+            .iter()
+            .map(|t| {
+                Aabb::from_points(&[
+                    vertices[t.v1_idx as usize].position,
+                    vertices[t.v2_idx as usize].position,
+                    vertices[t.v3_idx as usize].position,
+                ])
+            })
+            .collect::<Vec<_>>();
+
+        let (bvh, order) = Bvh::build(&bounds);
+        let triangles = order.iter().map(|&i| triangles[i as usize]).collect();
+        debug!("Built BVH with {} nodes", bvh.len());
+
+        Ok(Self {
+            vertices,
+            triangles,
+            bvh,
+            materials: vec![],
+        })
+    }
+
+    fn validate(vertices: &[Vertex], triangles: &[Triangle]) -> Result<(), MeshError> {
+        if triangles.is_empty() {
+            return Err(MeshError::Empty);
+        }
+
+        if let Some(vertex_idx) = vertices.iter().position(|v| !v.position.is_finite()) {
+            return Err(MeshError::InvalidPosition { vertex_idx });
+        }
+
+        for (triangle_idx, t) in triangles.iter().enumerate() {
+            for vertex_idx in [t.v1_idx, t.v2_idx, t.v3_idx] {
+                if vertex_idx as usize >= vertices.len() {
+                    return Err(MeshError::IndexOutOfBounds {
+                        triangle_idx,
+                        vertex_idx,
+                        len_vertices: vertices.len(),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sets each corner's normal to the average of the faces around its vertex, weighted by their area,
+    /// leaving out faces at more than `crease_angle` degrees to it. Vertices are split where the corners around them
+    /// end up with different normals
+    fn generate_normals(vertices: &mut Vec<Vertex>, triangles: &mut [Triangle], crease_angle: f32) {
+        // the cross product's length is twice the area
+        let face_normals = triangles
+            .iter()
+            .map(|t| {
+                let [v1, v2, v3] = [t.v1_idx, t.v2_idx, t.v3_idx].map(|i| vertices[i as usize].position);
+                (v2 - v1).cross(v3 - v1)
+            })
+            .collect::<Vec<_>>();
+
+        let mut vertex_faces = vec![vec![]; vertices.len()];
+        for (face_idx, t) in triangles.iter().enumerate() {
+            for vertex_idx in [t.v1_idx, t.v2_idx, t.v3_idx] {
+                vertex_faces[vertex_idx as usize].push(face_idx);
+            }
+        }
+
+        let min_cos = crease_angle.to_radians().cos();
+        // the normals each vertex has been given so far, and the vertex that has it
+        let mut vertex_normals = vec![vec![]; vertices.len()];
+
+        for (face_idx, t) in triangles.iter_mut().enumerate() {
+            let face_normal = face_normals[face_idx].normalize_or_zero();
+
+            for vertex_idx in [&mut t.v1_idx, &mut t.v2_idx, &mut t.v3_idx] {
+                let faces = &vertex_faces[*vertex_idx as usize];
+                let normal = faces
+                    .iter()
+                    .map(|&f| face_normals[f])
+                    // degenerate faces have no direction of their own, so take all of them
+                    .filter(|n| face_normal == Vec3::ZERO || n.normalize_or_zero().dot(face_normal) >= min_cos)
+                    .fold(Vec3::ZERO, |total, n| total + n)
+                    .normalize_or_zero();
+
+                let normals: &mut Vec<(Vec3, u32)> = &mut vertex_normals[*vertex_idx as usize];
+                if let Some(&(_, split_idx)) = normals.iter().find(|(n, _)| n.dot(normal) > 0.9999) {
+                    *vertex_idx = split_idx;
+                } else if normals.is_empty() {
+                    vertices[*vertex_idx as usize].normal = normal;
+                    normals.push((normal, *vertex_idx));
+                } else {
+                    let split_idx = vertices.len() as u32;
+                    vertices.push(Vertex {
+                        normal,
+                        ..vertices[*vertex_idx as usize]
+                    });
+                    normals.push((normal, split_idx));
+                    *vertex_idx = split_idx;
+                }
+            }
         }
+    }
+
+    /// Projects the vertices onto the largest side of their bounding box, as uvs for mesh files that don't have them
+    fn planar_uvs(vertices: &mut [Vertex]) {
+        let bounds = vertices.iter().fold(Aabb::EMPTY, |aabb, v| aabb.grow(v.position));
+        let size = (bounds.max - bounds.min).max(Vec3::splat(f32::EPSILON));
+
+        // leave out the smallest axis
+        let (u_axis, v_axis) = if size.x <= size.y && size.x <= size.z {
+            (2, 1)
+        } else if size.y <= size.z {
+            (0, 2)
+        } else {
+            (0, 1)
+        };
+
+        for vertex in vertices {
+            let p = (vertex.position - bounds.min) / size;
+            vertex.uv = vec2(p[u_axis], p[v_axis]);
+        }
+    }
 
-        for idx_slice in indices.chunks_exact(3) {
-            let idx_slice = &idx_slice[0..3];
+    /// Averages the directions u and v increase in across the faces around each vertex,
+    /// then makes the tangent perpendicular to the normal
+    fn generate_tangents(vertices: &mut [Vertex], triangles: &[Triangle]) {
+        let mut tangents = vec![Vec3::ZERO; vertices.len()];
+        let mut bitangents = vec![Vec3::ZERO; vertices.len()];
 
-            let v1_idx = idx_slice[0];
-            let v2_idx = idx_slice[1];
-            let v3_idx = idx_slice[2];
+        for t in triangles {
+            let [v1, v2, v3] = [t.v1_idx, t.v2_idx, t.v3_idx].map(|i| &vertices[i as usize]);
+            let (edge1, edge2) = (v2.position - v1.position, v3.position - v1.position);
+            let (duv1, duv2) = (v2.uv - v1.uv, v3.uv - v1.uv);
 
-            triangles.push(Triangle { v1_idx, v2_idx, v3_idx })
+            let det = duv1.x * duv2.y - duv2.x * duv1.y;
+            if det.abs() < f32::EPSILON {
+                continue;
+            }
+            // not divided by det's magnitude, so bigger faces count for more
+            let tangent = (edge1 * duv2.y - edge2 * duv1.y) * det.signum();
+            let bitangent = (edge2 * duv1.x - edge1 * duv2.x) * det.signum();
+
+            for i in [t.v1_idx, t.v2_idx, t.v3_idx] {
+                tangents[i as usize] += tangent;
+                bitangents[i as usize] += bitangent;
+            }
         }
 
-        Self { vertices, triangles }
+        for (vertex, (tangent, bitangent)) in vertices.iter_mut().zip(tangents.into_iter().zip(bitangents)) {
+            let n = vertex.normal;
+            let mut t = (tangent - n * n.dot(tangent)).normalize_or_zero();
+            if t == Vec3::ZERO {
+                // no uvs to follow, so any direction along the surface will do
+                t = n.any_orthonormal_vector();
+            }
+            let w = if n.cross(t).dot(bitangent) < 0.0 { -1.0 } else { 1.0 };
+            vertex.tangent = t.extend(w);
+        }
     }
 
     pub fn len_vertices(&self) -> u32 { self.vertices.len() as u32 }
     pub fn len_triangles(&self) -> u32 { self.triangles.len() as u32 }
+    pub fn len_bvh_nodes(&self) -> u32 { self.bvh.len() }
+}
+
+impl MeshMaterial {
+    /// Maps the Phong style parameters of an MTL material onto the metallic/roughness model.
+    /// `dir` is the directory the MTL file is in, which its texture paths are relative to
+    fn from_mtl(mtl: &tobj::Material, dir: &Path) -> Self {
+        let texture = find_texture(&mtl.diffuse_texture, dir);
+        let normal_texture = find_texture(&mtl.normal_texture, dir);
+        let emissive_texture = mtl.unknown_param.get("map_Ke").and_then(|name| find_texture(name, dir));
+
+        let diffuse = Vec3::from(mtl.diffuse);
+        let specular = Vec3::from(mtl.specular);
+
+        // illumination models 3 and up have ray traced reflections, the closest MTL gets to a metal
+        let reflectivity = if mtl.illumination_model.unwrap_or(0) >= 3 {
+            specular.max_element()
+        } else {
+            0.0
+        };
+        let legacy = LegacyMaterial {
+            shininess: mtl.shininess,
+            reflectivity,
+            ..Default::default()
+        };
+        let pbr = legacy.to_pbr(0, Vec2::ONE);
+
+        // the textures take the place of the diffuse and emissive colours
+        let base_colour = if texture.is_some() { Vec3::ONE } else { diffuse };
+        let emissive = parse_colour(mtl.unknown_param.get("Ke")).unwrap_or(if emissive_texture.is_some() {
+            Vec3::ONE
+        } else {
+            Vec3::ZERO
+        });
+
+        let material = Material {
+            base_colour: base_colour.lerp(specular, pbr.metallic),
+            // no specular highlight at all
+            roughness: if specular == Vec3::ZERO { 1.0 } else { pbr.roughness },
+            emissive,
+            ior: if mtl.optical_density > 1.0 {
+                mtl.optical_density
+            } else {
+                pbr.ior
+            },
+            // dissolve is how opaque it is
+            transmission: 1.0 - mtl.dissolve.clamp(0.0, 1.0),
+            ..pbr
+        };
+
+        Self {
+            material,
+            texture,
+            normal_texture,
+            emissive_texture,
+        }
+    }
+
+    /// Loads the textures with the renderer and adds the material to it, which keeps them loaded. Textures that
+    /// can't be loaded are left out, like MTL files that can't be, so the mesh can still be drawn
+    pub fn load(self, renderer: &mut impl Renderer) -> Handle<Material> {
+        let skip_failed = |texture: Result<Handle<Texture>, TextureError>| {
+            texture
+                .map_err(|e| warn!("Leaving out a texture of a mesh material: {}", e))
+                .ok()
+        };
+
+        let texture = match self
+            .texture
+            .map(|path| renderer.get_texture_by_path(&path))
+            .and_then(skip_failed)
+        {
+            Some(texture) => texture,
+            None => renderer.get_texture_by_colour(Vec3::ONE),
+        };
+        let normal_texture = self
+            .normal_texture
+            .and_then(|path| skip_failed(renderer.get_data_texture_by_path(&path)));
+        let emissive_texture = self
+            .emissive_texture
+            .and_then(|path| skip_failed(renderer.get_texture_by_path(&path)));
+
+        renderer.get_material(Material {
+            tex_id: texture.id(),
+            normal_tex_id: normal_texture.as_ref().map(Handle::id),
+            emissive_tex_id: emissive_texture.as_ref().map(Handle::id),
+            ..self.material
+        })
+    }
+}
+
+/// MTL files should name textures relative to themselves, but some are relative to the working directory instead
+fn find_texture(name: &str, dir: &Path) -> Option<String> {
+    if name.is_empty() {
+        return None;
+    }
+
+    let name = name.replace('\\', "/");
+    let path = [dir.join(&name), PathBuf::from(&name)]
+        .into_iter()
+        .find(|p| p.is_file());
+    if path.is_none() {
+        warn!("Couldn't find texture \"{}\"", name);
+    }
+
+    path.map(|p| p.to_string_lossy().into_owned())
+}
+
+/// A colour written as three numbers
+fn parse_colour(value: Option<&String>) -> Option<Vec3> {
+    let components = value?
+        .split_whitespace()
+        .map(|c| c.parse::<f32>().ok())
+        .collect::<Option<Vec<_>>>()?;
+
+    match components[..] {
+        [r, g, b] => Some(vec3(r, g, b)),
+        _ => None,
+    }
+}
+
+/// Error loading a mesh file, or a mesh that can't be rendered
+#[derive(Debug)]
+pub enum MeshError {
+    Io(io::Error),
+    Parse(String),
+    Empty,
+    InvalidPosition {
+        vertex_idx: usize,
+    },
+    IndexOutOfBounds {
+        triangle_idx: usize,
+        vertex_idx:   u32,
+        len_vertices: usize,
+    },
+}
+
+impl fmt::Display for MeshError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "couldn't read mesh file: {}", e),
+            Self::Parse(e) => write!(f, "couldn't parse mesh file: {}", e),
+            Self::Empty => write!(f, "mesh has no triangles"),
+            Self::InvalidPosition { vertex_idx } => write!(f, "vertex {} has an invalid position", vertex_idx),
+            Self::IndexOutOfBounds {
+                triangle_idx,
+                vertex_idx,
+                len_vertices,
+            } => write!(
+                f,
+                "triangle {} refers to vertex {}, but there are only {} vertices",
+                triangle_idx, vertex_idx, len_vertices
+            ),
+        }
+    }
+}
+
+impl Error for MeshError {}
+
+impl From<io::Error> for MeshError {
+    fn from(e: io::Error) -> Self { Self::Io(e) }
 }
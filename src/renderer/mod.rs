@@ -1,11 +1,26 @@
+mod assets;
+mod bvh;
 mod components;
+mod cpu_renderer;
+mod frame;
 mod gpu_renderer;
+mod lights;
 mod mesh;
+mod ply;
+#[allow(clippy::module_inception)]
+mod renderer;
+mod sky;
+mod stl;
 mod texture;
 mod utils;
 
+pub use assets::*;
+pub use bvh::*;
 pub use components::*;
+pub use cpu_renderer::*;
+pub use frame::*;
 pub use gpu_renderer::*;
 pub use mesh::*;
+pub use renderer::*;
 pub use texture::*;
 pub use utils::*;
@@ -0,0 +1,450 @@
+// Loading PLY meshes (ASCII or binary), as made by 3D scanners
+//
+// Only the vertex and face elements are used. Vertices need x, y and z, and can have normals (nx, ny, nz),
+// uvs (u/v, s/t or texture_u/texture_v) and colours (red, green, blue). Faces are polygons, split into fans.
+
+use std::{fs, str::SplitAsciiWhitespace};
+
+use super::{srgb_to_linear, MeshError, Triangle, Vertex, VertexAttributes};
+use crate::{vec2, vec3, Vec2, Vec3};
+
+pub(super) fn load_ply(path: &str) -> Result<(Vec<Vertex>, Vec<Triangle>, VertexAttributes), MeshError> {
+    parse(&fs::read(path)?)
+}
+
+fn parse(bytes: &[u8]) -> Result<(Vec<Vertex>, Vec<Triangle>, VertexAttributes), MeshError> {
+    let (header, body) = Header::parse(bytes)?;
+
+    let mut body = Body {
+        encoding: header.encoding,
+        data:     body,
+        tokens:   match header.encoding {
+            Encoding::Ascii => std::str::from_utf8(body).map_err(invalid)?.split_ascii_whitespace(),
+            _ => "".split_ascii_whitespace(),
+        },
+    };
+
+    // element counts come from the header, so they're only trusted as far as each one needing a byte of the file
+    let max_count = body.data.len();
+    let mut vertices = vec![];
+    let mut triangles = vec![];
+    let mut has_normals = false;
+    let mut has_uvs = false;
+
+    // elements have to be read in order, even the ones that aren't used
+    for element in &header.elements {
+        match element.name.as_str() {
+            "vertex" => {
+                let find = |names: &[&str]| element.properties.iter().position(|p| names.contains(&p.name.as_str()));
+                let find_all = |names: [&[&str]; 3]| Some([find(names[0])?, find(names[1])?, find(names[2])?]);
+
+                let position_idx =
+                    find_all([&["x"], &["y"], &["z"]]).ok_or_else(|| invalid("vertices have no position"))?;
+                let normal_idx = find_all([&["nx"], &["ny"], &["nz"]]);
+                let uv_idx = find(&["u", "s", "texture_u"]).zip(find(&["v", "t", "texture_v"]));
+                let colour_idx = find_all([&["red"], &["green"], &["blue"]]);
+                // integer colours go up to the type's max
+                let colour_scale = colour_idx.map_or(1.0, |[r, _, _]| match element.properties[r].kind {
+                    PropertyKind::Scalar(ty) => ty.colour_max(),
+                    PropertyKind::List { .. } => 1.0,
+                });
+
+                has_normals = normal_idx.is_some();
+                has_uvs = uv_idx.is_some();
+
+                vertices.reserve(element.count.min(max_count));
+                let mut values = vec![0.0; element.properties.len()];
+                for _ in 0..element.count {
+                    for (value, property) in values.iter_mut().zip(&element.properties) {
+                        *value = match property.kind {
+                            PropertyKind::Scalar(ty) => body.read(ty)?,
+                            PropertyKind::List { .. } => {
+                                body.read_list(property.kind)?;
+                                0.0
+                            }
+                        };
+                    }
+
+                    let get = |[x, y, z]: [usize; 3]| vec3(values[x] as f32, values[y] as f32, values[z] as f32);
+                    vertices.push(Vertex {
+                        normal: normal_idx.map_or(Vec3::ZERO, get),
+                        uv: uv_idx.map_or(Vec2::ZERO, |(u, v)| vec2(values[u] as f32, values[v] as f32)),
+                        colour: colour_idx.map_or(Vec3::ONE, |idx| {
+                            (get(idx) / colour_scale as f32).to_array().map(srgb_to_linear).into()
+                        }),
+                        ..Vertex::new(get(position_idx))
+                    });
+                }
+            }
+            "face" => {
+                let indices_idx = element
+                    .properties
+                    .iter()
+                    .position(|p| p.name == "vertex_indices" || p.name == "vertex_index")
+                    .ok_or_else(|| invalid("faces have no vertex indices"))?;
+
+                triangles.reserve(element.count.min(max_count));
+                for _ in 0..element.count {
+                    for (i, property) in element.properties.iter().enumerate() {
+                        match property.kind {
+                            PropertyKind::Scalar(ty) => {
+                                body.read(ty)?;
+                            }
+                            PropertyKind::List { .. } => {
+                                let indices = body.read_list(property.kind)?;
+                                if i == indices_idx {
+                                    add_polygon(&indices, vertices.len(), &mut triangles)?;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            _ => {
+                for _ in 0..element.count {
+                    for property in &element.properties {
+                        match property.kind {
+                            PropertyKind::Scalar(ty) => {
+                                body.read(ty)?;
+                            }
+                            PropertyKind::List { .. } => {
+                                body.read_list(property.kind)?;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    let attributes = VertexAttributes {
+        normals: has_normals,
+        uvs:     has_uvs,
+    };
+    Ok((vertices, triangles, attributes))
+}
+
+/// Splits a polygon into a fan of triangles
+fn add_polygon(indices: &[f64], len_vertices: usize, triangles: &mut Vec<Triangle>) -> Result<(), MeshError> {
+    if let Some(&idx) = indices.iter().find(|&&i| i < 0.0 || i as usize >= len_vertices) {
+        return Err(invalid(format!(
+            "face refers to vertex {} out of {}",
+            idx, len_vertices
+        )));
+    }
+
+    for i in 2..indices.len() {
+        triangles.push(Triangle {
+            v1_idx: indices[0] as u32,
+            v2_idx: indices[i - 1] as u32,
+            v3_idx: indices[i] as u32,
+            mat_id: None,
+        });
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
+enum Encoding {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Clone, Copy)]
+enum ScalarType {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+#[derive(Clone, Copy)]
+enum PropertyKind {
+    Scalar(ScalarType),
+    List { count: ScalarType, item: ScalarType },
+}
+
+struct Property {
+    name: String,
+    kind: PropertyKind,
+}
+
+struct Element {
+    name:       String,
+    count:      usize,
+    properties: Vec<Property>,
+}
+
+struct Header {
+    encoding: Encoding,
+    elements: Vec<Element>,
+}
+
+impl Header {
+    /// Returns the header, and the rest of the file after it
+    fn parse(bytes: &[u8]) -> Result<(Self, &[u8]), MeshError> {
+        const END: &[u8] = b"end_header";
+        let end = bytes
+            .windows(END.len())
+            .position(|w| w == END)
+            .ok_or_else(|| invalid("no end_header"))?;
+        // the body starts on the line after end_header
+        let body_start = bytes[end..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(bytes.len(), |i| end + i + 1);
+
+        let text = std::str::from_utf8(&bytes[..end]).map_err(invalid)?;
+        let mut lines = text.lines().map(|l| l.split_ascii_whitespace().collect::<Vec<_>>());
+
+        if !matches!(lines.next().as_deref(), Some(["ply"])) {
+            return Err(invalid("not a PLY file"));
+        }
+
+        let mut encoding = None;
+        let mut elements = Vec::<Element>::new();
+
+        for line in lines {
+            match line[..] {
+                ["format", format, _version] => {
+                    encoding = Some(match format {
+                        "ascii" => Encoding::Ascii,
+                        "binary_little_endian" => Encoding::BinaryLittleEndian,
+                        "binary_big_endian" => Encoding::BinaryBigEndian,
+                        _ => return Err(invalid(format!("unknown format {}", format))),
+                    });
+                }
+                ["element", name, count] => elements.push(Element {
+                    name:       name.to_owned(),
+                    count:      count.parse().map_err(invalid)?,
+                    properties: vec![],
+                }),
+                ["property", "list", count, item, name] => {
+                    let kind = PropertyKind::List {
+                        count: ScalarType::parse(count)?,
+                        item:  ScalarType::parse(item)?,
+                    };
+                    add_property(&mut elements, name, kind)?;
+                }
+                ["property", ty, name] => {
+                    add_property(&mut elements, name, PropertyKind::Scalar(ScalarType::parse(ty)?))?
+                }
+                _ => {} // comments, obj_info and blank lines
+            }
+        }
+
+        let encoding = encoding.ok_or_else(|| invalid("no format"))?;
+        Ok((Self { encoding, elements }, &bytes[body_start..]))
+    }
+}
+
+fn add_property(elements: &mut [Element], name: &str, kind: PropertyKind) -> Result<(), MeshError> {
+    let element = elements
+        .last_mut()
+        .ok_or_else(|| invalid("property before any element"))?;
+    element.properties.push(Property {
+        name: name.to_owned(),
+        kind,
+    });
+    Ok(())
+}
+
+impl ScalarType {
+    fn parse(name: &str) -> Result<Self, MeshError> {
+        Ok(match name {
+            "char" | "int8" => Self::I8,
+            "uchar" | "uint8" => Self::U8,
+            "short" | "int16" => Self::I16,
+            "ushort" | "uint16" => Self::U16,
+            "int" | "int32" => Self::I32,
+            "uint" | "uint32" => Self::U32,
+            "float" | "float32" => Self::F32,
+            "double" | "float64" => Self::F64,
+            _ => return Err(invalid(format!("unknown property type {}", name))),
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Self::I8 | Self::U8 => 1,
+            Self::I16 | Self::U16 => 2,
+            Self::I32 | Self::U32 | Self::F32 => 4,
+            Self::F64 => 8,
+        }
+    }
+
+    /// The value of a fully bright colour channel
+    fn colour_max(self) -> f64 {
+        match self {
+            Self::U8 => u8::MAX as f64,
+            Self::U16 => u16::MAX as f64,
+            _ => 1.0,
+        }
+    }
+}
+
+/// Reads values from the part of the file after the header. Everything is read as f64, which holds any of the types
+struct Body<'a> {
+    encoding: Encoding,
+    data:     &'a [u8],
+    tokens:   SplitAsciiWhitespace<'a>,
+}
+
+impl<'a> Body<'a> {
+    fn read(&mut self, ty: ScalarType) -> Result<f64, MeshError> {
+        let little_endian = match self.encoding {
+            Encoding::Ascii => {
+                let token = self.tokens.next().ok_or_else(|| invalid("unexpected end of file"))?;
+                return token.parse().map_err(invalid);
+            }
+            Encoding::BinaryLittleEndian => true,
+            Encoding::BinaryBigEndian => false,
+        };
+
+        let size = ty.size();
+        if self.data.len() < size {
+            return Err(invalid("unexpected end of file"));
+        }
+        let (bytes, rest) = self.data.split_at(size);
+        self.data = rest;
+
+        let mut b = [0; 8];
+        b[..size].copy_from_slice(bytes);
+        if !little_endian {
+            b[..size].reverse();
+        }
+
+        Ok(match ty {
+            ScalarType::I8 => b[0] as i8 as f64,
+            ScalarType::U8 => b[0] as f64,
+            ScalarType::I16 => i16::from_le_bytes([b[0], b[1]]) as f64,
+            ScalarType::U16 => u16::from_le_bytes([b[0], b[1]]) as f64,
+            ScalarType::I32 => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            ScalarType::U32 => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            ScalarType::F32 => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            ScalarType::F64 => f64::from_le_bytes(b),
+        })
+    }
+
+    fn read_list(&mut self, kind: PropertyKind) -> Result<Vec<f64>, MeshError> {
+        match kind {
+            PropertyKind::List { count, item } => {
+                let count = self.read(count)? as usize;
+                (0..count).map(|_| self.read(item)).collect()
+            }
+            PropertyKind::Scalar(ty) => Ok(vec![self.read(ty)?]),
+        }
+    }
+}
+
+fn invalid(e: impl ToString) -> MeshError { MeshError::Parse(e.to_string()) }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A unit square facing +z as one quad, with normals, uvs, colours and an element that isn't used
+    const HEADER: &str = "ply
+format {} 1.0
+comment made by hand
+element vertex 4
+property float x
+property float y
+property float z
+property float nx
+property float ny
+property float nz
+property float s
+property float t
+property uchar red
+property uchar green
+property uchar blue
+element face 1
+property list uchar int vertex_indices
+element edge 1
+property int vertex1
+property int vertex2
+end_header
+";
+
+    const POSITIONS: [[f32; 3]; 4] = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]];
+
+    fn header(format: &str) -> Vec<u8> { HEADER.replace("{}", format).into_bytes() }
+
+    fn ascii() -> Vec<u8> {
+        let mut bytes = header("ascii");
+        for [x, y, z] in POSITIONS {
+            bytes.extend(format!("{} {} {} 0 0 1 {} {} 255 0 0\n", x, y, z, x, y).bytes());
+        }
+        bytes.extend(b"4 0 1 2 3\n0 1\n");
+        bytes
+    }
+
+    fn binary(format: &str, to_bytes: fn(f32) -> [u8; 4], int_to_bytes: fn(i32) -> [u8; 4]) -> Vec<u8> {
+        let mut bytes = header(format);
+        for [x, y, z] in POSITIONS {
+            bytes.extend([x, y, z, 0.0, 0.0, 1.0, x, y].into_iter().flat_map(to_bytes));
+            bytes.extend([255, 0, 0]);
+        }
+        bytes.push(4);
+        bytes.extend([0, 1, 2, 3, 0, 1].into_iter().flat_map(int_to_bytes));
+        bytes
+    }
+
+    fn check_square((vertices, triangles, attributes): (Vec<Vertex>, Vec<Triangle>, VertexAttributes)) {
+        assert!(attributes.normals && attributes.uvs);
+        assert_eq!(vertices.len(), 4);
+        for (vertex, position) in vertices.iter().zip(POSITIONS) {
+            assert_eq!(vertex.position, Vec3::from(position));
+            assert_eq!(vertex.normal, Vec3::Z);
+            assert_eq!(vertex.uv, vec2(position[0], position[1]));
+            assert_eq!(vertex.colour, Vec3::X);
+        }
+
+        // split into a fan around the first corner
+        let indices = triangles
+            .iter()
+            .map(|t| [t.v1_idx, t.v2_idx, t.v3_idx])
+            .collect::<Vec<_>>();
+        assert_eq!(indices, vec![[0, 1, 2], [0, 2, 3]]);
+    }
+
+    #[test]
+    fn ascii_square() { check_square(parse(&ascii()).unwrap()) }
+
+    #[test]
+    fn binary_little_endian_square() {
+        check_square(parse(&binary("binary_little_endian", f32::to_le_bytes, i32::to_le_bytes)).unwrap())
+    }
+
+    #[test]
+    fn binary_big_endian_square() {
+        check_square(parse(&binary("binary_big_endian", f32::to_be_bytes, i32::to_be_bytes)).unwrap())
+    }
+
+    #[test]
+    fn errors() {
+        let is_parse_error = |bytes: &[u8]| matches!(parse(bytes), Err(MeshError::Parse(_)));
+
+        // a count far past what the file holds runs out of data instead of allocating for it
+        let huge_count = String::from_utf8(ascii())
+            .unwrap()
+            .replace("element vertex 4", &format!("element vertex {}", usize::MAX));
+        assert!(is_parse_error(huge_count.as_bytes()));
+
+        let out_of_bounds = String::from_utf8(ascii()).unwrap().replace("4 0 1 2 3", "4 0 1 2 4");
+        assert!(is_parse_error(out_of_bounds.as_bytes()));
+
+        let mut truncated = binary("binary_little_endian", f32::to_le_bytes, i32::to_le_bytes);
+        truncated.truncate(truncated.len() - 1);
+        assert!(is_parse_error(&truncated));
+
+        assert!(is_parse_error(b"ply\nformat ascii 1.0\n"));
+        assert!(is_parse_error(b"obj\nformat ascii 1.0\nend_header\n"));
+    }
+}
@@ -0,0 +1,75 @@
+use crate::{scene::Scene, Vec3};
+
+use super::{Frame, Handle, Material, Mesh, MeshError, Texture, TextureError, TextureSampler};
+
+/// The interface every renderer implements, so the engine can drive any of them.
+///
+/// Renderers hand out handles for textures, meshes and materials, which keep them loaded until `unload_unused` is
+/// called after every handle to them has been dropped. Materials refer to textures by the handle's id, and keep them
+/// loaded for as long as the renderer has the material, as meshes do with the materials from their files.
+/// Currently implemented by `GPURenderer` (compute shader based) and `CpuRenderer` (rayon based).
+pub trait Renderer {
+    /// Loads a texture, or gives another handle to it if it's already loaded
+    fn get_texture_by_path(&mut self, path: &str) -> Result<Handle<Texture>, TextureError>;
+    /// Loads a texture that holds data rather than colours, like a normal map, so isn't converted from sRGB
+    fn get_data_texture_by_path(&mut self, path: &str) -> Result<Handle<Texture>, TextureError>;
+    fn get_texture_by_colour(&mut self, colour: Vec3) -> Handle<Texture>;
+    fn get_mesh_by_path(&mut self, path: &str) -> Result<Handle<Mesh>, MeshError>;
+    /// Adds a material to the renderer's material table, or gives another handle to an identical one if there is one
+    fn get_material(&mut self, material: Material) -> Handle<Material>;
+
+    /// Adds a texture that was made in memory, e.g. one embedded in a glTF file. Always adds a new texture,
+    /// and `name` is only what `get_texture_path` gives back, so it must not be a path that can be loaded
+    fn add_texture(&mut self, name: &str, texture: Texture) -> Handle<Texture>;
+    /// Adds a mesh that was made in memory, with the same rules for `name` as `add_texture`
+    fn add_mesh(&mut self, name: &str, mesh: Mesh) -> Handle<Mesh>;
+
+    /// Swaps a texture for another in place, so every material using it shows the new one. Keeps the texture's
+    /// sampler and path, so it's also how a texture is reloaded after its file changes
+    fn replace_texture(&mut self, texture: &Handle<Texture>, replacement: Texture);
+    /// Swaps a mesh for another in place, for every object that draws it
+    fn replace_mesh(&mut self, mesh: &Handle<Mesh>, replacement: Mesh);
+    /// Frees the textures, meshes and materials nothing has a handle to any more, like those only used by a scene
+    /// that has been dropped. Their ids are then reused by the next ones loaded
+    fn unload_unused(&mut self);
+
+    /// The path a texture was loaded from, solid colours have a path made by `Texture::colour_path`
+    fn get_texture_path(&self, tex_id: u32) -> Option<&str>;
+    fn get_mesh_path(&self, mesh_id: u32) -> Option<&str>;
+    fn get_material_data(&self, mat_id: u32) -> Option<&Material>;
+
+    /// Changes a material, for every object that uses it. Cheaper than giving the objects a new material,
+    /// as only the material table has to be updated
+    fn set_material(&mut self, mat_id: u32, material: Material);
+
+    fn get_texture_sampler(&self, tex_id: u32) -> Option<TextureSampler>;
+    /// Changes how a texture is filtered and repeated, for every material that uses it
+    fn set_texture_sampler(&mut self, tex_id: u32, sampler: TextureSampler);
+
+    /// Draws the scene from its first camera
+    fn draw(&mut self, scene: &mut Scene);
+
+    /// Changes the resolution of the frames being drawn
+    fn resize(&mut self, width: u32, height: u32);
+
+    /// Reads back the last drawn frame. Blocks until it has finished rendering.
+    fn read_frame(&self) -> Frame;
+
+    fn render_mode(&self) -> RenderMode;
+    fn set_render_mode(&mut self, mode: RenderMode);
+
+    /// How many path traced samples per pixel the current frame is averaged over.
+    /// Starts again from zero whenever the camera or scene changes, and is always 1 for `RenderMode::Lightmap`
+    fn sample_count(&self) -> u32;
+}
+
+/// How a renderer lights the scene
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RenderMode {
+    /// Ray traced reflections and refractions, with light bouncing between surfaces taken from precomputed
+    /// radiosity lightmaps
+    #[default]
+    Lightmap,
+    /// Monte Carlo path tracing, averaging another sample per pixel every frame while nothing changes
+    PathTraced,
+}
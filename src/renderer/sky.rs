@@ -0,0 +1,273 @@
+// Looking up and importance sampling the sky, shared by both renderers. Mirrors sky.glsl
+//
+// Skies are sampled with a piecewise constant distribution on a latitude/longitude grid, built on the CPU from the
+// sky's brightness. The GPU renderer uploads its cdfs as they are.
+
+use std::f32::consts::{PI, TAU};
+
+use super::{Sky, SkyBoxComponent, TextureStore};
+use crate::{vec2, vec3, Mat3, Vec2, Vec3};
+
+// the Preetham model gives luminance in kcd/m^2, this brings a clear sky to about the brightness of the scene's lights
+const SKY_LUMINANCE_SCALE: f32 = 0.1;
+
+// Same as SKY_CDF_WIDTH and SKY_CDF_HEIGHT in defines.glsl
+pub(super) const SKY_CDF_WIDTH: usize = 128;
+pub(super) const SKY_CDF_HEIGHT: usize = 64;
+// each cell of the distribution is the average of this many samples squared, so small bright spots aren't missed
+const SKY_CDF_SUPERSAMPLING: usize = 2;
+// a little of the distribution is spread evenly, so every direction can be picked
+const SKY_CDF_UNIFORM: f32 = 0.01;
+
+const LUMINANCE: Vec3 = vec3(0.2126, 0.7152, 0.0722);
+
+/// The light coming from the sky in a direction, `textures` are the renderer's
+pub(super) fn sample_sky(sky: &SkyBoxComponent, textures: &TextureStore, direction: Vec3) -> Vec3 {
+    // turning the sky one way is the same as turning the direction the other
+    let d = Mat3::from_rotation_y(-sky.rotation.to_radians()) * direction;
+
+    let radiance = match &sky.sky {
+        Sky::Colour(colour) => *colour,
+        Sky::Equirect { texture } => textures[texture.id()].sample(direction_to_uv(d)),
+        Sky::Cubemap { textures: faces } => {
+            let (face, uv) = cubemap_uv(d);
+            let texture = &textures[faces[face].id()];
+
+            // sampling repeats, so keep away from the edges to stop the other side of the face bleeding in
+            let half_texel = 0.5 / vec2(texture.width as f32, texture.height as f32);
+            texture.sample(uv.clamp(half_texel, 1.0 - half_texel))
+        }
+        // not rotated, so it stays lined up with the sun's light
+        Sky::Procedural {
+            sun_direction,
+            turbidity,
+        } => procedural_sky(direction, -normalize_sun_direction(*sun_direction), *turbidity),
+    };
+
+    radiance * sky.intensity
+}
+
+/// The way the sunlight of a procedural sky travels, straight down for a zero direction, which has none
+pub(super) fn normalize_sun_direction(sun_direction: Vec3) -> Vec3 {
+    sun_direction.try_normalize().unwrap_or(Vec3::NEG_Y)
+}
+
+/// Where a direction is on a latitude/longitude map, with the middle facing +z
+fn direction_to_uv(direction: Vec3) -> Vec2 {
+    vec2(
+        0.5 + (direction.x.atan2(direction.z) / TAU),
+        direction.y.clamp(-1.0, 1.0).acos() / PI,
+    )
+}
+
+fn uv_to_direction(uv: Vec2) -> Vec3 {
+    let phi = (uv.x - 0.5) * TAU;
+    let (sin_theta, cos_theta) = (uv.y * PI).sin_cos();
+    vec3(sin_theta * phi.sin(), cos_theta, sin_theta * phi.cos())
+}
+
+/// Picks directions in the sky, with brighter ones picked more often
+pub(super) struct SkyDistribution {
+    /// The cdf over the rows of the grid, then the cdf within each row, the same as the GPU's sky_cdf buffer.
+    /// Each cdf starts at 0 and ends at 1
+    pub(super) cdf: Vec<f32>,
+}
+
+impl SkyDistribution {
+    pub(super) fn new(sky: &SkyBoxComponent, textures: &TextureStore) -> Self {
+        let (width, height, n) = (SKY_CDF_WIDTH, SKY_CDF_HEIGHT, SKY_CDF_SUPERSAMPLING);
+        let samples_size = vec2((width * n) as f32, (height * n) as f32);
+
+        let mut weights = Vec::with_capacity(width * height);
+        for row in 0..height {
+            // rows nearer the poles cover less of the sky
+            let sin_theta = (((row as f32 + 0.5) / height as f32) * PI).sin();
+
+            for col in 0..width {
+                let luminance = (0..n * n)
+                    .map(|i| {
+                        let sample = vec2(((col * n) + (i % n)) as f32, ((row * n) + (i / n)) as f32);
+                        let direction = uv_to_direction((sample + 0.5) / samples_size);
+                        sample_sky(sky, textures, direction).dot(LUMINANCE)
+                    })
+                    .sum::<f32>();
+                weights.push((luminance / (n * n) as f32).max(0.0) * sin_theta);
+            }
+        }
+
+        let mean = weights.iter().sum::<f32>() / weights.len() as f32;
+        for weight in &mut weights {
+            *weight += mean * SKY_CDF_UNIFORM;
+        }
+
+        let mut cdf = Vec::with_capacity((height + 1) + (height * (width + 1)));
+        let row_sums = weights
+            .chunks_exact(width)
+            .map(|row| row.iter().sum())
+            .collect::<Vec<f32>>();
+        push_cdf(&mut cdf, &row_sums);
+        for row in weights.chunks_exact(width) {
+            push_cdf(&mut cdf, row);
+        }
+
+        Self { cdf }
+    }
+
+    /// Picks a direction with xi (0.0..1.0), giving it and its pdf over solid angle
+    pub(super) fn sample(&self, xi: Vec2) -> (Vec3, f32) {
+        let (row, dv) = self.sample_cdf(0, SKY_CDF_HEIGHT, xi.y);
+        let (col, du) = self.sample_cdf(row_start(row), SKY_CDF_WIDTH, xi.x);
+
+        let uv = vec2(
+            (col as f32 + du) / SKY_CDF_WIDTH as f32,
+            (row as f32 + dv) / SKY_CDF_HEIGHT as f32,
+        );
+        (uv_to_direction(uv), self.cell_pdf(row, col, uv.y))
+    }
+
+    /// The pdf over solid angle of picking a direction
+    pub(super) fn pdf(&self, direction: Vec3) -> f32 {
+        let uv = direction_to_uv(direction);
+        let row = ((uv.y * SKY_CDF_HEIGHT as f32) as usize).min(SKY_CDF_HEIGHT - 1);
+        let col = ((uv.x * SKY_CDF_WIDTH as f32) as usize).min(SKY_CDF_WIDTH - 1);
+        self.cell_pdf(row, col, uv.y)
+    }
+
+    fn cell_pdf(&self, row: usize, col: usize, v: f32) -> f32 {
+        let sin_theta = (v * PI).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+
+        let p_row = self.cdf[row + 1] - self.cdf[row];
+        let c = row_start(row) + col;
+        let p_col = self.cdf[c + 1] - self.cdf[c];
+
+        // from the uv square to the sphere
+        p_row * p_col * (SKY_CDF_WIDTH * SKY_CDF_HEIGHT) as f32 / (2.0 * PI * PI * sin_theta)
+    }
+
+    /// Which of the count intervals of the cdf at start x falls in, and how far through it
+    fn sample_cdf(&self, start: usize, count: usize, x: f32) -> (usize, f32) {
+        let cdf = &self.cdf[start..=start + count];
+
+        // the last value that's no more than x, skipping over empty intervals
+        let (mut lo, mut hi) = (0, count);
+        while lo + 1 < hi {
+            let mid = (lo + hi) / 2;
+            if cdf[mid] <= x {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+
+        let width = cdf[lo + 1] - cdf[lo];
+        let t = if width > 0.0 {
+            ((x - cdf[lo]) / width).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        (lo, t)
+    }
+}
+
+/// Where the cdf of a row starts, after the cdf over the rows
+fn row_start(row: usize) -> usize { (SKY_CDF_HEIGHT + 1) + (row * (SKY_CDF_WIDTH + 1)) }
+
+/// Appends the cdf of the weights, an even one if they're all zero
+fn push_cdf(cdf: &mut Vec<f32>, weights: &[f32]) {
+    let total = weights.iter().sum::<f32>();
+
+    cdf.push(0.0);
+    let mut sum = 0.0;
+    for (i, weight) in weights.iter().enumerate() {
+        sum += weight;
+        cdf.push(if total > 0.0 {
+            sum / total
+        } else {
+            (i + 1) as f32 / weights.len() as f32
+        });
+    }
+    // rounding can leave it just short
+    *cdf.last_mut().unwrap() = 1.0;
+}
+
+// Perez et al.'s distribution of sky luminance and chromaticity (as Yxy), by the angle from the zenith and the angle
+// from the sun
+fn perez(cos_theta: f32, gamma: f32, cos_gamma: f32, coeffs: &[Vec3; 5]) -> Vec3 {
+    let [a, b, c, d, e] = *coeffs;
+    (1.0 + (a * (b / cos_theta).exp())) * (1.0 + (c * (d * gamma).exp()) + (e * cos_gamma * cos_gamma))
+}
+
+// The clear sky from "A Practical Analytic Model for Daylight" (Preetham et al.), without the sun itself.
+// to_sun must be normalised
+fn procedural_sky(direction: Vec3, to_sun: Vec3, turbidity: f32) -> Vec3 {
+    let t = turbidity;
+
+    // below the horizon is the same as the horizon, and so is a sun that's set
+    let cos_theta = direction.y.max(0.01);
+    let cos_gamma = direction.dot(to_sun).clamp(-1.0, 1.0);
+    let gamma = cos_gamma.acos();
+    let theta_s = to_sun.y.clamp(0.0, 1.0).acos();
+
+    // Perez coefficients for Y, x and y
+    let coeffs = [
+        (vec3(0.1787, -0.0193, -0.0167) * t) + vec3(-1.4630, -0.2592, -0.2608),
+        (vec3(-0.3554, -0.0665, -0.0950) * t) + vec3(0.4275, 0.0008, 0.0092),
+        (vec3(-0.0227, -0.0004, -0.0079) * t) + vec3(5.3251, 0.2125, 0.2102),
+        (vec3(0.1206, -0.0641, -0.0441) * t) + vec3(-2.5771, -0.8989, -1.6537),
+        (vec3(-0.0670, -0.0033, -0.0109) * t) + vec3(0.3703, 0.0452, 0.0529),
+    ];
+
+    // the sky at the zenith
+    let chi = ((4.0 / 9.0) - (t / 120.0)) * (PI - (2.0 * theta_s));
+    let zenith_y = (((4.0453 * t) - 4.9710) * chi.tan()) - (0.2155 * t) + 2.4192;
+    let thetas = vec3(theta_s * theta_s * theta_s, theta_s * theta_s, theta_s);
+    let zenith_x = (t * t * vec3(0.00166, -0.00375, 0.00209).dot(thetas))
+        + (t * (vec3(-0.02903, 0.06377, -0.03202).dot(thetas) + 0.00394))
+        + vec3(0.11693, -0.21196, 0.06052).dot(thetas)
+        + 0.25886;
+    let zenith_y_chroma = (t * t * vec3(0.00275, -0.00610, 0.00317).dot(thetas))
+        + (t * (vec3(-0.04214, 0.08970, -0.04153).dot(thetas) + 0.00516))
+        + vec3(0.15346, -0.26756, 0.06670).dot(thetas)
+        + 0.26688;
+
+    let yxy = vec3(zenith_y, zenith_x, zenith_y_chroma) * perez(cos_theta, gamma, cos_gamma, &coeffs)
+        / perez(1.0, theta_s, theta_s.cos(), &coeffs);
+
+    // Yxy to XYZ, then to linear sRGB
+    let luminance = yxy.x * SKY_LUMINANCE_SCALE;
+    let xyz = vec3(yxy.y, yxy.z, 1.0 - yxy.y - yxy.z) * (luminance / yxy.z);
+    let xyz_to_rgb = Mat3::from_cols(
+        vec3(3.2406, -0.9689, 0.0557),
+        vec3(-1.5372, 1.8758, -0.2040),
+        vec3(-0.4986, 0.0415, 1.0570),
+    );
+    (xyz_to_rgb * xyz).max(Vec3::ZERO)
+}
+
+// Which face of a cubemap a direction points at, and where on it, like OpenGL cubemaps
+fn cubemap_uv(direction: Vec3) -> (usize, Vec2) {
+    let a = direction.abs();
+
+    let (face, major, st) = if a.x >= a.y && a.x >= a.z {
+        if direction.x > 0.0 {
+            (0, a.x, vec2(-direction.z, -direction.y))
+        } else {
+            (1, a.x, vec2(direction.z, -direction.y))
+        }
+    } else if a.y >= a.z {
+        if direction.y > 0.0 {
+            (2, a.y, vec2(direction.x, direction.z))
+        } else {
+            (3, a.y, vec2(direction.x, -direction.z))
+        }
+    } else if direction.z > 0.0 {
+        (4, a.z, vec2(direction.x, -direction.y))
+    } else {
+        (5, a.z, vec2(-direction.x, -direction.y))
+    };
+
+    (face, 0.5 * ((st / major) + 1.0))
+}
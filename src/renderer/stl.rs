@@ -0,0 +1,233 @@
+// Loading STL meshes (ASCII or binary), as exported by CAD tools
+//
+// STL only has triangles with a normal each, so corners at the same position are welded into shared vertices, and the
+// normals are generated from them, keeping the edges sharper than `CREASE_ANGLE`.
+
+use std::{collections::HashMap, fs, str::SplitAsciiWhitespace};
+
+use super::{MeshError, Triangle, Vertex, VertexAttributes};
+use crate::{vec3, Vec3};
+
+pub(super) fn load_stl(path: &str) -> Result<(Vec<Vertex>, Vec<Triangle>, VertexAttributes), MeshError> {
+    parse(&fs::read(path)?)
+}
+
+fn parse(bytes: &[u8]) -> Result<(Vec<Vertex>, Vec<Triangle>, VertexAttributes), MeshError> {
+    let facets = if is_binary(bytes) {
+        read_binary(bytes)?
+    } else {
+        read_ascii(std::str::from_utf8(bytes).map_err(invalid)?)?
+    };
+
+    let mut vertices = vec![];
+    let mut triangles = Vec::with_capacity(facets.len());
+    // positions by their bits, with -0.0 made 0.0 so both weld
+    let mut welded = HashMap::new();
+
+    for Facet { normal, mut positions } in facets {
+        // the winding decides which way generated normals face, so it's made to agree with the file's normal.
+        // Some exporters leave those as zero, which leaves the winding as it is
+        let winding = (positions[1] - positions[0]).cross(positions[2] - positions[0]);
+        if winding.dot(normal) < 0.0 {
+            positions.swap(1, 2);
+        }
+
+        let [v1_idx, v2_idx, v3_idx] = positions.map(|position| {
+            let key = (position + Vec3::ZERO).to_array().map(f32::to_bits);
+            *welded.entry(key).or_insert_with(|| {
+                vertices.push(Vertex::new(position));
+                vertices.len() as u32 - 1
+            })
+        });
+        triangles.push(Triangle {
+            v1_idx,
+            v2_idx,
+            v3_idx,
+            mat_id: None,
+        });
+    }
+
+    Ok((vertices, triangles, VertexAttributes::default()))
+}
+
+struct Facet {
+    normal:    Vec3,
+    positions: [Vec3; 3],
+}
+
+/// Binary files are an 80 byte header, a triangle count and 50 bytes per triangle. Some of them start with "solid"
+/// like ASCII files do, so the size is checked as well
+fn is_binary(bytes: &[u8]) -> bool {
+    if bytes.len() < 84 {
+        return false;
+    }
+    let count = u32::from_le_bytes([bytes[80], bytes[81], bytes[82], bytes[83]]) as usize;
+    bytes.len() == 84 + count * 50 || !bytes.starts_with(b"solid")
+}
+
+fn read_binary(bytes: &[u8]) -> Result<Vec<Facet>, MeshError> {
+    let count = u32::from_le_bytes([bytes[80], bytes[81], bytes[82], bytes[83]]) as usize;
+    if bytes.len() < 84 + count * 50 {
+        return Err(invalid(format!("expected {} triangles", count)));
+    }
+
+    let read_vec3 = |b: &[u8]| {
+        let f = |i: usize| f32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]);
+        vec3(f(0), f(4), f(8))
+    };
+
+    // normal, 3 positions, then 2 bytes of attributes
+    Ok(bytes[84..84 + count * 50]
+        .chunks_exact(50)
+        .map(|t| Facet {
+            normal:    read_vec3(&t[0..12]),
+            positions: [read_vec3(&t[12..24]), read_vec3(&t[24..36]), read_vec3(&t[36..48])],
+        })
+        .collect())
+}
+
+/// Only the normal and vertex lines matter, "outer loop" and the rest are skipped
+fn read_ascii(text: &str) -> Result<Vec<Facet>, MeshError> {
+    fn read_vec3(tokens: &mut SplitAsciiWhitespace) -> Result<Vec3, MeshError> {
+        let mut f = || -> Result<f32, MeshError> {
+            tokens
+                .next()
+                .ok_or_else(|| invalid("unexpected end of file"))?
+                .parse()
+                .map_err(invalid)
+        };
+        Ok(vec3(f()?, f()?, f()?))
+    }
+
+    let mut tokens = text.split_ascii_whitespace();
+    let mut facets = vec![];
+    let mut normal = Vec3::ZERO;
+    let mut positions = vec![];
+
+    while let Some(token) = tokens.next() {
+        match token {
+            "normal" => normal = read_vec3(&mut tokens)?,
+            "vertex" => positions.push(read_vec3(&mut tokens)?),
+            "endfacet" => {
+                if positions.len() != 3 {
+                    return Err(invalid(format!("facet has {} vertices", positions.len())));
+                }
+                facets.push(Facet {
+                    normal,
+                    positions: [positions[0], positions[1], positions[2]],
+                });
+                positions.clear();
+            }
+            _ => {}
+        }
+    }
+
+    Ok(facets)
+}
+
+fn invalid(e: impl ToString) -> MeshError { MeshError::Parse(e.to_string()) }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::Mesh;
+
+    /// A unit square in the xy plane facing +z, as two facets
+    const SQUARE: [[Vec3; 3]; 2] = [
+        [Vec3::ZERO, Vec3::X, Vec3::new(1.0, 1.0, 0.0)],
+        [Vec3::ZERO, Vec3::new(1.0, 1.0, 0.0), Vec3::Y],
+    ];
+
+    fn ascii(facets: &[[Vec3; 3]], normal: Vec3) -> Vec<u8> {
+        let mut text = "solid square\n".to_owned();
+        for positions in facets {
+            text += &format!("facet normal {} {} {}\nouter loop\n", normal.x, normal.y, normal.z);
+            for p in positions {
+                text += &format!("vertex {} {} {}\n", p.x, p.y, p.z);
+            }
+            text += "endloop\nendfacet\n";
+        }
+        (text + "endsolid square\n").into_bytes()
+    }
+
+    fn binary(facets: &[[Vec3; 3]], normal: Vec3) -> Vec<u8> {
+        // starts with "solid" like some exporters do, which the size gives away
+        let mut bytes = b"solid square".to_vec();
+        bytes.resize(80, 0);
+        bytes.extend((facets.len() as u32).to_le_bytes());
+        for positions in facets {
+            for v in std::iter::once(&normal).chain(positions) {
+                bytes.extend(v.to_array().into_iter().flat_map(f32::to_le_bytes));
+            }
+            bytes.extend([0, 0]);
+        }
+        bytes
+    }
+
+    fn winding_normal(vertices: &[Vertex], t: &Triangle) -> Vec3 {
+        let [v1, v2, v3] = [t.v1_idx, t.v2_idx, t.v3_idx].map(|i| vertices[i as usize].position);
+        (v2 - v1).cross(v3 - v1).normalize()
+    }
+
+    #[test]
+    fn ascii_welds_vertices() {
+        let (vertices, triangles, attributes) = parse(&ascii(&SQUARE, Vec3::Z)).unwrap();
+        assert_eq!(vertices.len(), 4);
+        assert_eq!(triangles.len(), 2);
+        assert!(!attributes.normals && !attributes.uvs);
+        for t in &triangles {
+            assert_eq!(winding_normal(&vertices, t), Vec3::Z);
+        }
+    }
+
+    #[test]
+    fn binary_matches_ascii() {
+        let (vertices, triangles, _) = parse(&binary(&SQUARE, Vec3::Z)).unwrap();
+        let (ascii_vertices, ascii_triangles, _) = parse(&ascii(&SQUARE, Vec3::Z)).unwrap();
+        assert_eq!(
+            vertices.iter().map(|v| v.position).collect::<Vec<_>>(),
+            ascii_vertices.iter().map(|v| v.position).collect::<Vec<_>>()
+        );
+        for (t, ascii_t) in triangles.iter().zip(&ascii_triangles) {
+            assert_eq!(
+                [t.v1_idx, t.v2_idx, t.v3_idx],
+                [ascii_t.v1_idx, ascii_t.v2_idx, ascii_t.v3_idx]
+            );
+        }
+    }
+
+    #[test]
+    fn winding_follows_file_normals() {
+        let (vertices, triangles, _) = parse(&ascii(&SQUARE, -Vec3::Z)).unwrap();
+        for t in &triangles {
+            assert_eq!(winding_normal(&vertices, t), -Vec3::Z);
+        }
+
+        // zero normals leave it as it is
+        let (vertices, triangles, _) = parse(&binary(&SQUARE, Vec3::ZERO)).unwrap();
+        for t in &triangles {
+            assert_eq!(winding_normal(&vertices, t), Vec3::Z);
+        }
+    }
+
+    #[test]
+    fn welded_faces_are_smooth() {
+        let (vertices, triangles, attributes) = parse(&binary(&SQUARE, Vec3::Z)).unwrap();
+        let mesh = Mesh::new(vertices, triangles, attributes).unwrap();
+        assert_eq!(mesh.vertices.len(), 4);
+        assert!(mesh.vertices.iter().all(|v| v.normal == Vec3::Z));
+    }
+
+    #[test]
+    fn errors() {
+        let mut truncated = binary(&SQUARE, Vec3::Z);
+        // without "solid" at the start, so it can only be binary
+        truncated[..5].copy_from_slice(b"model");
+        truncated[80..84].copy_from_slice(&3u32.to_le_bytes());
+        assert!(matches!(parse(&truncated), Err(MeshError::Parse(_))));
+
+        let missing_vertex =
+            b"solid x\nfacet normal 0 0 1\nouter loop\nvertex 0 0 0\nvertex 1 0 0\nendloop\nendfacet\n";
+        assert!(matches!(parse(missing_vertex), Err(MeshError::Parse(_))));
+    }
+}
@@ -1,35 +1,385 @@
-use crate::{renderer::srgb_to_linear, Vec3};
+use std::{error::Error, fmt, sync::OnceLock};
+
+use half::f16;
+use image::{DynamicImage, ImageError};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    renderer::{linear_to_srgb, srgb_to_linear},
+    vec2, vec3, vec4, Vec2, Vec3, Vec4,
+};
+
+/// Solid colour textures are identified by a made up path, as they don't come from a file
+const COLOUR_PATH_PREFIX: &str = "colour/";
 
 pub struct Texture {
-    pub(super) width:  u32,
-    pub(super) height: u32,
-    pub(super) data:   Vec<f32>,
+    pub(super) width:   u32,
+    pub(super) height:  u32,
+    pub(super) data:    TextureData,
+    pub(super) sampler: TextureSampler,
+    /// Smaller copies of the texture, each half the size of the last down to 1x1. Empty until `generate_mips`
+    mips:               Vec<Mip>,
 }
 
-impl Texture {
-    pub fn from_path(path: &str) -> Self {
-        let raw_image = image::open(path).unwrap().into_rgb8();
-        let (width, height) = (raw_image.width(), raw_image.height());
-        let mut data = Vec::with_capacity((raw_image.width() * raw_image.height()) as usize);
+struct Mip {
+    width:  u32,
+    height: u32,
+    data:   TextureData,
+}
+
+/// The RGBA texels of a texture, in the smallest format that holds them. Alpha is always linear
+#[derive(Clone)]
+pub enum TextureData {
+    /// 8 bit sRGB colours, for images that are no brighter than 1
+    Srgb8(Vec<u8>),
+    /// 8 bit data that isn't converted from sRGB, like normal maps
+    Unorm8(Vec<u8>),
+    /// For HDR images, which can be brighter than 1
+    Half(Vec<f16>),
+}
+
+/// How a texture is filtered, and what's outside of 0.0..1.0 in uv space
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TextureSampler {
+    /// Between texels when the texture is close up
+    pub mag_filter:    TextureFilter,
+    /// Between texels when the texture is far away
+    pub min_filter:    TextureFilter,
+    /// Between mip levels, `None` to always use the full size texture
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mipmap_filter: Option<TextureFilter>,
+    pub address_u:     TextureAddressMode,
+    pub address_v:     TextureAddressMode,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TextureFilter {
+    Nearest,
+    Linear,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TextureAddressMode {
+    Repeat,
+    MirroredRepeat,
+    ClampToEdge,
+}
+
+impl Default for TextureSampler {
+    /// Trilinear filtering, repeating in both directions
+    fn default() -> Self {
+        Self {
+            mag_filter:    TextureFilter::Linear,
+            min_filter:    TextureFilter::Linear,
+            mipmap_filter: Some(TextureFilter::Linear),
+            address_u:     TextureAddressMode::Repeat,
+            address_v:     TextureAddressMode::Repeat,
+        }
+    }
+}
 
-        for pixel in raw_image.pixels() {
-            let [r, g, b] = pixel.0;
-            //data.extend_from_slice(&rgb!(r, g, b).to_array());
-            data.push(srgb_to_linear(r as f32 / 255.0));
-            data.push(srgb_to_linear(g as f32 / 255.0));
-            data.push(srgb_to_linear(b as f32 / 255.0));
-            data.push(1.0);
+impl Texture {
+    /// Floating point images, like .hdr and .exr files, are already linear and can be brighter than 1, so they're
+    /// kept as half floats. Anything else is 8 bit sRGB. Alpha is kept, and is 1 for images without it
+    pub fn from_path(path: &str) -> Result<Self, TextureError> {
+        let image = open(path)?;
+        let (width, height) = (image.width(), image.height());
+        if let DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) = image {
+            // anything brighter than a half float can hold would become infinite
+            let max = f16::MAX.to_f32();
+            let data = image
+                .into_rgba32f()
+                .into_raw()
+                .into_iter()
+                .map(|x| f16::from_f32(x.min(max)))
+                .collect();
+            return Ok(Self::from_data(width, height, TextureData::Half(data)));
         }
-        Self { data, width, height }
+
+        Ok(Self::from_data(width, height, TextureData::Srgb8(image.into_rgba8().into_raw())))
+    }
+
+    /// For textures that hold data rather than colours, like normal maps, so aren't converted from sRGB
+    pub fn from_data_path(path: &str) -> Result<Self, TextureError> {
+        let image = open(path)?;
+        let (width, height) = (image.width(), image.height());
+        Ok(Self::from_data(width, height, TextureData::Unorm8(image.into_rgba8().into_raw())))
+    }
+
+    /// From 8 bit sRGB pixels with 1 to 4 channels. One channel is grey, two is grey and alpha.
+    pub fn from_srgb8(width: u32, height: u32, channels: usize, pixels: &[u8]) -> Self {
+        Self::from_data(width, height, TextureData::Srgb8(to_rgba8(channels, pixels)))
+    }
+
+    /// Like `from_srgb8`, for data textures
+    pub fn from_linear8(width: u32, height: u32, channels: usize, pixels: &[u8]) -> Self {
+        Self::from_data(width, height, TextureData::Unorm8(to_rgba8(channels, pixels)))
     }
 
     pub fn from_colour_srgb(col: Vec3) -> Self {
-        let data = vec![col.x, col.y, col.z, 1.0];
+        let data = [col.x, col.y, col.z, 1.0].map(f16::from_f32).to_vec();
+        Self::from_data(1, 1, TextureData::Half(data))
+    }
 
+    fn from_data(width: u32, height: u32, data: TextureData) -> Self {
         Self {
+            width,
+            height,
             data,
-            width: 1,
-            height: 1,
+            sampler: TextureSampler::default(),
+            mips: vec![],
+        }
+    }
+
+    pub fn with_sampler(self, sampler: TextureSampler) -> Self { Self { sampler, ..self } }
+
+    /// How many mip levels the texture has once they're all made, including the full size one
+    pub fn mip_levels(&self) -> u32 { 32 - self.width.max(self.height).leading_zeros() }
+
+    /// Makes the smaller copies of the texture used when it's far away, by averaging each 2x2 block of the level
+    /// above. The GPU makes its own as textures are uploaded, so only the CPU renderer needs these
+    pub fn generate_mips(&mut self) {
+        self.mips.clear();
+
+        let (mut width, mut height) = (self.width, self.height);
+        for _ in 1..self.mip_levels() {
+            let above = self.mips.last().map_or(&self.data, |m| &m.data);
+            let (above_width, above_height) = (width, height);
+            width = (width / 2).max(1);
+            height = (height / 2).max(1);
+
+            // averaged in linear space, so sRGB levels don't get darker
+            let texels = (0..height).flat_map(|y| {
+                (0..width).map(move |x| {
+                    // levels with an odd size drop their last row or column
+                    [(0, 0), (1, 0), (0, 1), (1, 1)]
+                        .map(|(dx, dy)| {
+                            let tx = ((x * 2) + dx).min(above_width - 1);
+                            let ty = ((y * 2) + dy).min(above_height - 1);
+                            above.texel(((ty * above_width) + tx) as usize)
+                        })
+                        .into_iter()
+                        .fold(Vec4::ZERO, |sum, t| sum + t)
+                        / 4.0
+                })
+            });
+            let data = above.with_texels(texels);
+
+            self.mips.push(Mip { width, height, data });
+        }
+    }
+
+    /// Samples the full size texture, with its sampler's magnification filter and edges
+    pub fn sample(&self, uv: Vec2) -> Vec3 { self.sample_lod(uv, 0.0) }
+
+    /// How opaque the full size texture is at uv, for alpha tested cutouts
+    pub fn sample_alpha(&self, uv: Vec2) -> f32 { self.sample_rgba(uv, 0.0).w }
+
+    /// Samples the texture blurred over footprint, the width of the area a ray sees in uv space, like
+    /// sample_footprint in colour.glsl
+    pub fn sample_footprint(&self, uv: Vec2, footprint: f32) -> Vec3 {
+        let lod = (footprint * self.width.max(self.height) as f32).max(1e-8).log2();
+        self.sample_lod(uv, lod)
+    }
+
+    /// Samples the texture at a mip level, which can be between levels, the same as the GPU sampler does
+    pub fn sample_lod(&self, uv: Vec2, lod: f32) -> Vec3 { self.sample_rgba(uv, lod).truncate() }
+
+    fn sample_rgba(&self, uv: Vec2, lod: f32) -> Vec4 {
+        let s = &self.sampler;
+        if lod <= 0.0 {
+            return self.sample_level(0, uv, s.mag_filter);
+        }
+
+        let max_level = self.mips.len() as f32;
+        match s.mipmap_filter {
+            None => self.sample_level(0, uv, s.min_filter),
+            Some(TextureFilter::Nearest) => {
+                let level = ((lod + 0.5).ceil() - 1.0).min(max_level);
+                self.sample_level(level as usize, uv, s.min_filter)
+            }
+            Some(TextureFilter::Linear) => {
+                let lod = lod.min(max_level);
+                let level = lod.floor();
+                let finer = self.sample_level(level as usize, uv, s.min_filter);
+                let coarser = self.sample_level((level + 1.0).min(max_level) as usize, uv, s.min_filter);
+                finer.lerp(coarser, lod - level)
+            }
+        }
+    }
+
+    fn sample_level(&self, level: usize, uv: Vec2, filter: TextureFilter) -> Vec4 {
+        let (width, height, data) = match level {
+            0 => (self.width, self.height, &self.data),
+            _ => {
+                let mip = &self.mips[level - 1];
+                (mip.width, mip.height, &mip.data)
+            }
+        };
+
+        let texel = |x: f32, y: f32| {
+            let x = self.sampler.address_u.apply(x as i64, width);
+            let y = self.sampler.address_v.apply(y as i64, height);
+            data.texel(y * width as usize + x)
+        };
+
+        let p = uv * vec2(width as f32, height as f32);
+        match filter {
+            TextureFilter::Nearest => texel(p.x.floor(), p.y.floor()),
+            TextureFilter::Linear => {
+                // texel centres are at half texel offsets
+                let (x, y) = (p.x - 0.5, p.y - 0.5);
+                let (x0, y0) = (x.floor(), y.floor());
+                let (fx, fy) = (x - x0, y - y0);
+
+                let top = texel(x0, y0).lerp(texel(x0 + 1.0, y0), fx);
+                let bottom = texel(x0, y0 + 1.0).lerp(texel(x0 + 1.0, y0 + 1.0), fx);
+                top.lerp(bottom, fy)
+            }
+        }
+    }
+
+    /// The path used to identify a solid colour texture
+    pub fn colour_path(col: Vec3) -> String {
+        format!(
+            "{}{},{},{}",
+            COLOUR_PATH_PREFIX,
+            (col.x * 1024.0).round() as u16,
+            (col.y * 1024.0).round() as u16,
+            (col.z * 1024.0).round() as u16
+        )
+    }
+
+    /// The colour of a solid colour texture, if this path identifies one
+    pub fn colour_from_path(path: &str) -> Option<Vec3> {
+        let components = path
+            .strip_prefix(COLOUR_PATH_PREFIX)?
+            .split(',')
+            .map(|c| c.parse::<u16>().ok().map(|c| c as f32 / 1024.0))
+            .collect::<Option<Vec<_>>>()?;
+
+        match components[..] {
+            [r, g, b] => Some(vec3(r, g, b)),
+            _ => None,
+        }
+    }
+}
+
+impl TextureAddressMode {
+    /// Which texel a possibly out of range coordinate reads, on a row or column of size texels
+    fn apply(self, i: i64, size: u32) -> usize {
+        let size = size as i64;
+        let i = match self {
+            Self::Repeat => i.rem_euclid(size),
+            Self::MirroredRepeat => {
+                let i = i.rem_euclid(size * 2);
+                if i < size {
+                    i
+                } else {
+                    (size * 2) - 1 - i
+                }
+            }
+            Self::ClampToEdge => i.clamp(0, size - 1),
+        };
+        i as usize
+    }
+}
+
+impl TextureData {
+    /// The linear RGBA of the texel at idx
+    fn texel(&self, idx: usize) -> Vec4 {
+        let i = idx * 4;
+        match self {
+            Self::Srgb8(data) => {
+                let to_linear = srgb8_to_linear();
+                vec4(
+                    to_linear[data[i] as usize],
+                    to_linear[data[i + 1] as usize],
+                    to_linear[data[i + 2] as usize],
+                    data[i + 3] as f32 / 255.0,
+                )
+            }
+            Self::Unorm8(data) => {
+                vec4(
+                    data[i] as f32,
+                    data[i + 1] as f32,
+                    data[i + 2] as f32,
+                    data[i + 3] as f32,
+                ) / 255.0
+            }
+            Self::Half(data) => vec4(
+                data[i].to_f32(),
+                data[i + 1].to_f32(),
+                data[i + 2].to_f32(),
+                data[i + 3].to_f32(),
+            ),
+        }
+    }
+
+    /// Data in the same format as this, holding other linear RGBA texels
+    fn with_texels(&self, texels: impl Iterator<Item = Vec4>) -> Self {
+        let to_unorm8 = |x: f32| (x.clamp(0.0, 1.0) * 255.0).round() as u8;
+        match self {
+            Self::Srgb8(_) => Self::Srgb8(
+                texels
+                    .flat_map(|t| {
+                        let [r, g, b, a] = t.to_array();
+                        [r, g, b]
+                            .map(|c| to_unorm8(linear_to_srgb(c)))
+                            .into_iter()
+                            .chain([to_unorm8(a)])
+                    })
+                    .collect(),
+            ),
+            Self::Unorm8(_) => Self::Unorm8(texels.flat_map(|t| t.to_array().map(to_unorm8)).collect()),
+            Self::Half(_) => Self::Half(texels.flat_map(|t| t.to_array().map(f16::from_f32)).collect()),
         }
     }
 }
+
+fn open(path: &str) -> Result<DynamicImage, TextureError> {
+    image::open(path).map_err(|error| TextureError {
+        path: path.to_owned(),
+        error,
+    })
+}
+
+/// Pixels with 1 to 4 channels as RGBA. One channel is grey, two is grey and alpha
+fn to_rgba8(channels: usize, pixels: &[u8]) -> Vec<u8> {
+    pixels
+        .chunks_exact(channels)
+        .flat_map(|pixel| match *pixel {
+            [l] => [l, l, l, u8::MAX],
+            [l, a] => [l, l, l, a],
+            [r, g, b] => [r, g, b, u8::MAX],
+            [r, g, b, a, ..] => [r, g, b, a],
+            [] => unreachable!(),
+        })
+        .collect()
+}
+
+/// The linear value of each 8 bit sRGB value, so sampling doesn't work out a power for every texel
+fn srgb8_to_linear() -> &'static [f32; 256] {
+    static TABLE: OnceLock<[f32; 256]> = OnceLock::new();
+    TABLE.get_or_init(|| std::array::from_fn(|i| srgb_to_linear(i as f32 / 255.0)))
+}
+
+/// Error loading a texture from an image file
+#[derive(Debug)]
+pub struct TextureError {
+    pub path:  String,
+    pub error: ImageError,
+}
+
+impl fmt::Display for TextureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "couldn't open image \"{}\": {}", self.path, self.error)
+    }
+}
+
+impl Error for TextureError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> { Some(&self.error) }
+}
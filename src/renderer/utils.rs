@@ -6,6 +6,14 @@ pub fn srgb_to_linear(x: f32) -> f32 {
     }
 }
 
+pub fn linear_to_srgb(x: f32) -> f32 {
+    if x <= 0.0031308 {
+        x * 12.92
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
+
 #[macro_export]
 macro_rules! rgb {
     [$r:expr, $g:expr, $b:expr] => {
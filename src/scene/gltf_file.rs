@@ -0,0 +1,402 @@
+// Importing glTF 2.0 scenes (.gltf or .glb)
+//
+// glTF is right handed, looking down -z, while the engine is left handed, looking down +z,
+// so everything is mirrored in z on the way in. Triangles are wound the other way to keep them facing out.
+
+use std::{collections::HashMap, f32::consts::PI};
+
+use gltf::{
+    camera::Projection,
+    image::Format,
+    khr_lights_punctual::Kind,
+    material::AlphaMode,
+    mesh::Mode,
+    texture::{MagFilter, MinFilter, WrappingMode},
+    Document, Node,
+};
+use log::{debug, warn};
+
+use super::{Scene, SceneFileError};
+use crate::{
+    renderer::{
+        CameraComponent, DirectionalLightComponent, Handle, Material, Mesh, MeshError, MeshRenderComponent,
+        PointLightComponent, Renderer, SpotLightComponent, Texture, TextureAddressMode, TextureFilter, TextureSampler,
+        TransformComponent, Triangle, Vertex, VertexAttributes,
+    },
+    vec3, Mat4, Vec2, Vec3,
+};
+
+/// Loads the default scene of a glTF file, or its first one. Its meshes, materials and textures are added to the
+/// renderer, and each node with a mesh, camera or light becomes an entity.
+///
+/// Only perspective cameras are supported, and they can't roll. Materials use the metallic/roughness factors, and
+/// the base colour, normal, metallic/roughness, emissive and occlusion textures. Masked materials are cut out,
+/// while blended ones are drawn opaque.
+pub fn load_gltf(path: &str, renderer: &mut impl Renderer) -> Result<Scene, SceneFileError> {
+    let (document, buffers, images) = gltf::import(path).map_err(|e| SceneFileError::Parse(e.to_string()))?;
+
+    let gltf_scene = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+        .ok_or_else(|| SceneFileError::Parse("no scenes in file".to_owned()))?;
+
+    let mut importer = Importer {
+        path,
+        renderer,
+        buffers: &buffers,
+        images: &images,
+        scene: Scene::empty(),
+        textures: HashMap::new(),
+        meshes: HashMap::new(),
+        materials: vec![],
+        has_camera: false,
+    };
+    importer.add_materials(&document);
+
+    for node in gltf_scene.nodes() {
+        importer.add_node(&node, Mat4::IDENTITY)?;
+    }
+
+    if !importer.has_camera {
+        warn!("No camera in {}, adding one at the origin", path);
+        let mut builder = hecs::EntityBuilder::new();
+        builder.add(TransformComponent::default());
+        builder.add(CameraComponent {
+            pitch: 0.0,
+            yaw:   0.0,
+            fov:   60.0,
+        });
+        importer.scene.create_entity(builder.build());
+    }
+
+    Ok(importer.scene)
+}
+
+struct Importer<'a, R: Renderer> {
+    path:       &'a str,
+    renderer:   &'a mut R,
+    buffers:    &'a [gltf::buffer::Data],
+    images:     &'a [gltf::image::Data],
+    scene:      Scene,
+    /// Renderer textures by texture index and whether it's a colour, as several materials can use one texture
+    textures:   HashMap<(usize, bool), Handle<Texture>>,
+    /// Renderer meshes by mesh index, as several nodes can use one mesh
+    meshes:     HashMap<usize, Handle<Mesh>>,
+    /// Renderer materials by material index
+    materials:  Vec<Handle<Material>>,
+    has_camera: bool,
+}
+
+impl<'a, R: Renderer> Importer<'a, R> {
+    fn add_materials(&mut self, document: &Document) {
+        // for materials without a base colour texture, held until they've been added
+        let white = self.renderer.get_texture_by_colour(Vec3::ONE);
+
+        for material in document.materials() {
+            let pbr = material.pbr_metallic_roughness();
+
+            let tex_id = pbr
+                .base_color_texture()
+                .and_then(|info| self.get_texture(&info.texture(), true))
+                .unwrap_or(white.id());
+            let [r, g, b, a] = pbr.base_color_factor();
+            // only the texture's alpha is tested, so move the factor's over to the cutoff. A factor of zero cuts out
+            // everything unless the cutoff is zero too, which a tiny factor does as well without dividing by zero
+            let alpha_cutoff = match material.alpha_mode() {
+                AlphaMode::Mask => Some(material.alpha_cutoff().unwrap_or(0.5) / a.max(f32::EPSILON)),
+                AlphaMode::Opaque | AlphaMode::Blend => None,
+            };
+
+            let normal_tex_id = material
+                .normal_texture()
+                .and_then(|info| self.get_texture(&info.texture(), false));
+            let metallic_roughness_tex_id = pbr
+                .metallic_roughness_texture()
+                .and_then(|info| self.get_texture(&info.texture(), false));
+            let emissive_tex_id = material
+                .emissive_texture()
+                .and_then(|info| self.get_texture(&info.texture(), true));
+            let occlusion_tex_id = material
+                .occlusion_texture()
+                .and_then(|info| self.get_texture(&info.texture(), false));
+
+            let material = Material {
+                tex_id,
+                tex_scale: Vec2::ONE,
+                base_colour: vec3(r, g, b),
+                metallic: pbr.metallic_factor(),
+                roughness: pbr.roughness_factor(),
+                emissive: material.emissive_factor().into(),
+                ior: material.ior().unwrap_or(1.5),
+                transmission: material.transmission().map_or(0.0, |t| t.transmission_factor()),
+                absorption: Vec3::ZERO,
+                normal_tex_id,
+                metallic_roughness_tex_id,
+                emissive_tex_id,
+                occlusion_tex_id,
+                alpha_cutoff,
+            };
+            let material = self.renderer.get_material(material);
+            self.materials.push(material);
+        }
+    }
+
+    /// Colour images are sRGB, while normal, metallic/roughness and occlusion images are linear data
+    fn get_texture(&mut self, gltf_texture: &gltf::Texture, is_colour: bool) -> Option<u32> {
+        if let Some(texture) = self.textures.get(&(gltf_texture.index(), is_colour)) {
+            return Some(texture.id());
+        }
+
+        let image_idx = gltf_texture.source().index();
+        let image = &self.images[image_idx];
+        let channels = match image.format {
+            Format::R8 => 1,
+            Format::R8G8 => 2,
+            Format::R8G8B8 => 3,
+            Format::R8G8B8A8 => 4,
+            format => {
+                warn!(
+                    "Skipping image {} in {}, as it has unsupported format {:?}",
+                    image_idx, self.path, format
+                );
+                return None;
+            }
+        };
+
+        let texture = if is_colour {
+            Texture::from_srgb8(image.width, image.height, channels, &image.pixels)
+        } else {
+            Texture::from_linear8(image.width, image.height, channels, &image.pixels)
+        };
+        // textures are images with a sampler, so one image can be added more than once with different samplers
+        let texture = texture.with_sampler(texture_sampler(&gltf_texture.sampler()));
+        let texture = self
+            .renderer
+            .add_texture(&format!("{}#texture{}", self.path, gltf_texture.index()), texture);
+        let tex_id = texture.id();
+        self.textures.insert((gltf_texture.index(), is_colour), texture);
+        Some(tex_id)
+    }
+
+    /// Adds an entity for the node if it has anything the engine can use, then does the same for its children
+    fn add_node(&mut self, node: &Node, parent: Mat4) -> Result<(), MeshError> {
+        let world = parent * Mat4::from_cols_array_2d(&node.transform().matrix());
+
+        // mirroring on both sides keeps the transform a proper rotation
+        let mirror = Mat4::from_scale(vec3(1.0, 1.0, -1.0));
+        let (scale, rotation, position) = (mirror * world * mirror).to_scale_rotation_translation();
+        let transform = TransformComponent {
+            position,
+            rotation,
+            scale,
+        };
+
+        let mut builder = hecs::EntityBuilder::new();
+        let mut has_components = false;
+
+        if let Some(mesh) = node.mesh() {
+            if let Some(mesh) = self.get_mesh(&mesh)? {
+                builder.add(MeshRenderComponent { mesh });
+                has_components = true;
+            }
+        }
+
+        // cameras and lights point down -z, which is +z once mirrored
+        let forward = rotation * Vec3::Z;
+
+        if let Some(camera) = node.camera() {
+            match camera.projection() {
+                Projection::Perspective(perspective) => {
+                    builder.add(CameraComponent {
+                        pitch: (-forward.y).asin(),
+                        yaw:   forward.x.atan2(forward.z),
+                        fov:   perspective.yfov().to_degrees(),
+                    });
+                    has_components = true;
+                    self.has_camera = true;
+                }
+                Projection::Orthographic(_) => {
+                    warn!("Skipping orthographic camera {} in {}", camera.index(), self.path)
+                }
+            }
+        }
+
+        if let Some(light) = node.light() {
+            let colour = light.color().into();
+            // glTF gives intensities in candela or lux, while the renderer's lights are scaled by π
+            let intensity = light.intensity() / PI;
+
+            match light.kind() {
+                Kind::Point => {
+                    builder.add(PointLightComponent { colour, intensity });
+                }
+                Kind::Spot {
+                    inner_cone_angle,
+                    outer_cone_angle,
+                } => {
+                    builder.add(SpotLightComponent {
+                        colour,
+                        intensity,
+                        direction: Vec3::Z,
+                        inner_angle: inner_cone_angle.to_degrees(),
+                        outer_angle: outer_cone_angle.to_degrees(),
+                    });
+                }
+                Kind::Directional => {
+                    builder.add(DirectionalLightComponent {
+                        colour,
+                        intensity,
+                        direction: forward,
+                    });
+                }
+            }
+            has_components = true;
+        }
+
+        if has_components {
+            builder.add(transform);
+            self.scene.create_entity(builder.build());
+        }
+
+        for child in node.children() {
+            self.add_node(&child, world)?;
+        }
+        Ok(())
+    }
+
+    /// Merges the triangles of every primitive in the mesh, each keeping its own material
+    fn get_mesh(&mut self, mesh: &gltf::Mesh) -> Result<Option<Handle<Mesh>>, MeshError> {
+        if let Some(handle) = self.meshes.get(&mesh.index()) {
+            return Ok(Some(handle.clone()));
+        }
+
+        let mut vertices = vec![];
+        let mut triangles = vec![];
+        let mut materials = vec![];
+        // only kept if every primitive has them
+        let mut attributes = VertexAttributes {
+            normals: true,
+            uvs:     true,
+        };
+        let buffers = self.buffers;
+
+        for primitive in mesh.primitives() {
+            if primitive.mode() != Mode::Triangles {
+                warn!(
+                    "Skipping primitive {} of mesh {} in {}, as it isn't made of triangles",
+                    primitive.index(),
+                    mesh.index(),
+                    self.path
+                );
+                continue;
+            }
+
+            let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+            let positions = match reader.read_positions() {
+                Some(positions) => positions,
+                None => continue,
+            };
+
+            let start_vertex_idx = vertices.len() as u32;
+            let material = primitive.material().index().map(|i| &self.materials[i]);
+            let mat_id = material.map(Handle::id);
+            if let Some(material) = material.filter(|m| !materials.contains(*m)) {
+                materials.push(material.clone());
+            }
+
+            vertices.extend(positions.map(|[x, y, z]| Vertex::new(vec3(x, y, -z))));
+            let primitive_vertices = &mut vertices[start_vertex_idx as usize..];
+
+            match reader.read_normals() {
+                Some(normals) => {
+                    for (vertex, [x, y, z]) in primitive_vertices.iter_mut().zip(normals) {
+                        vertex.normal = vec3(x, y, -z);
+                    }
+                }
+                None => attributes.normals = false,
+            }
+            match reader.read_tex_coords(0) {
+                Some(uvs) => {
+                    for (vertex, uv) in primitive_vertices.iter_mut().zip(uvs.into_f32()) {
+                        vertex.uv = uv.into();
+                    }
+                }
+                None => attributes.uvs = false,
+            }
+            if let Some(colours) = reader.read_colors(0) {
+                for (vertex, colour) in primitive_vertices.iter_mut().zip(colours.into_rgb_f32()) {
+                    vertex.colour = colour.into();
+                }
+            }
+
+            let indices = match reader.read_indices() {
+                Some(indices) => indices.into_u32().collect::<Vec<_>>(),
+                None => (0..primitive_vertices.len() as u32).collect(),
+            };
+            triangles.extend(indices.chunks_exact(3).map(|idx_slice| Triangle {
+                v1_idx: start_vertex_idx + idx_slice[0],
+                v2_idx: start_vertex_idx + idx_slice[2],
+                v3_idx: start_vertex_idx + idx_slice[1],
+                mat_id,
+            }));
+        }
+
+        if triangles.is_empty() {
+            warn!(
+                "Skipping mesh {} in {}, as it has no triangles",
+                mesh.index(),
+                self.path
+            );
+            return Ok(None);
+        }
+
+        debug!(
+            "Loaded mesh {} from {} with {} vertices and {} triangles",
+            mesh.index(),
+            self.path,
+            vertices.len(),
+            triangles.len()
+        );
+
+        let handle = self.renderer.add_mesh(
+            &format!("{}#mesh{}", self.path, mesh.index()),
+            Mesh {
+                materials,
+                ..Mesh::new(vertices, triangles, attributes)?
+            },
+        );
+        self.meshes.insert(mesh.index(), handle.clone());
+        Ok(Some(handle))
+    }
+}
+
+/// Filters the file leaves out are up to the renderer, so they keep the defaults
+fn texture_sampler(sampler: &gltf::texture::Sampler) -> TextureSampler {
+    let default = TextureSampler::default();
+    let address_mode = |wrap| match wrap {
+        WrappingMode::ClampToEdge => TextureAddressMode::ClampToEdge,
+        WrappingMode::MirroredRepeat => TextureAddressMode::MirroredRepeat,
+        WrappingMode::Repeat => TextureAddressMode::Repeat,
+    };
+    let (min_filter, mipmap_filter) = match sampler.min_filter() {
+        Some(MinFilter::Nearest) => (TextureFilter::Nearest, None),
+        Some(MinFilter::Linear) => (TextureFilter::Linear, None),
+        Some(MinFilter::NearestMipmapNearest) => (TextureFilter::Nearest, Some(TextureFilter::Nearest)),
+        Some(MinFilter::LinearMipmapNearest) => (TextureFilter::Linear, Some(TextureFilter::Nearest)),
+        Some(MinFilter::NearestMipmapLinear) => (TextureFilter::Nearest, Some(TextureFilter::Linear)),
+        Some(MinFilter::LinearMipmapLinear) => (TextureFilter::Linear, Some(TextureFilter::Linear)),
+        None => (default.min_filter, default.mipmap_filter),
+    };
+
+    TextureSampler {
+        mag_filter: match sampler.mag_filter() {
+            Some(MagFilter::Nearest) => TextureFilter::Nearest,
+            Some(MagFilter::Linear) => TextureFilter::Linear,
+            None => default.mag_filter,
+        },
+        min_filter,
+        mipmap_filter,
+        address_u: address_mode(sampler.wrap_s()),
+        address_v: address_mode(sampler.wrap_t()),
+    }
+}
@@ -1,8 +1,12 @@
 mod entity;
+mod gltf_file;
 mod query;
 #[allow(clippy::module_inception)]
 mod scene;
+mod scene_file;
 
 pub use entity::*;
+pub use gltf_file::*;
 pub use query::*;
 pub use scene::*;
+pub use scene_file::*;
@@ -0,0 +1,728 @@
+// Reading and writing scenes as text files (RON, TOML or JSON)
+//
+// Renderer components refer to textures, meshes and materials by handles and ids handed out by the renderer,
+// so the file format has its own descriptions which refer to them by path, or describe them in full instead.
+
+use std::{error::Error, fmt, fs, io, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use super::Scene;
+use crate::{
+    renderer::{
+        AreaLightComponent, CameraComponent, DirectionalLightComponent, Handle, LegacyMaterial, Material,
+        MaterialComponent, MeshError, MeshRenderComponent, PlaneRenderComponent, PointLightComponent, Renderer, Sky,
+        SkyBoxComponent, SphereRenderComponent, SpotLightComponent, Texture, TextureError, TextureSampler,
+        TransformComponent,
+    },
+    Vec2, Vec3,
+};
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct SceneFile {
+    pub entities: Vec<EntityDesc>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct EntityDesc {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transform:         Option<TransformComponent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sphere:            Option<SphereRenderComponent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub plane:             Option<PlaneDesc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mesh:              Option<MeshDesc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub material:          Option<MaterialDesc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub point_light:       Option<PointLightComponent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub directional_light: Option<DirectionalLightComponent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spot_light:        Option<SpotLightComponent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub area_light:        Option<AreaLightComponent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub camera:            Option<CameraComponent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sky_box:           Option<SkyBoxDesc>,
+}
+
+/// The tangent and bitangent of a plane are derived from the normal, so only the normal is stored
+#[derive(Serialize, Deserialize)]
+pub struct PlaneDesc {
+    pub normal: Vec3,
+    pub width:  f32,
+    pub height: f32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MeshDesc {
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct SkyBoxDesc {
+    pub sky:       SkyDesc,
+    pub rotation:  f32,
+    pub intensity: f32,
+}
+
+/// Like `Sky`, with textures given by path
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkyDesc {
+    Colour(Vec3),
+    Equirect(String),
+    Cubemap([String; 6]),
+    Procedural { sun_direction: Vec3, turbidity: f32 },
+}
+
+impl Default for SkyBoxDesc {
+    fn default() -> Self {
+        Self {
+            sky:       SkyDesc::Colour(Vec3::splat(0.7)),
+            rotation:  0.0,
+            intensity: 1.0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TextureDesc {
+    Path(String),
+    Colour(Vec3),
+}
+
+/// Older scene files have the Phong material parameters, which are converted when any of them are present
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct MaterialDesc {
+    pub tex_scale:    Vec2,
+    pub base_colour:  Vec3,
+    pub metallic:     f32,
+    pub roughness:    f32,
+    pub emissive:     EmissiveDesc,
+    pub ior:          f32,
+    pub transmission: f32,
+    pub absorption:   Vec3,
+    // ambient and specular have no equivalent, so they are ignored
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ambient:      Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diffuse:      Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub specular:     Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shininess:    Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reflectivity: Option<f32>,
+
+    // paths of the optional maps, see `Material`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub normal_map:             Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metallic_roughness_map: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub emissive_map:           Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub occlusion_map:          Option<String>,
+    /// Rays go through where the texture's alpha is below this, see `Material`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alpha_cutoff:           Option<f32>,
+
+    /// How all of the material's textures are filtered and repeated. Textures are shared by path, so this changes
+    /// it for every material that uses the same ones
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sampler: Option<TextureSampler>,
+    pub texture: TextureDesc,
+}
+
+/// Emission is a colour, but older scene files have a single strength
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum EmissiveDesc {
+    Colour(Vec3),
+    Legacy(f32),
+}
+
+impl Default for MaterialDesc {
+    fn default() -> Self {
+        let basic = Material::basic();
+        Self {
+            tex_scale:    basic.tex_scale,
+            base_colour:  basic.base_colour,
+            metallic:     basic.metallic,
+            roughness:    basic.roughness,
+            emissive:     EmissiveDesc::Colour(basic.emissive),
+            ior:          basic.ior,
+            transmission: basic.transmission,
+            absorption:   basic.absorption,
+            ambient:      None,
+            diffuse:      None,
+            specular:     None,
+            shininess:    None,
+            reflectivity: None,
+
+            normal_map:             None,
+            metallic_roughness_map: None,
+            emissive_map:           None,
+            occlusion_map:          None,
+            alpha_cutoff:           None,
+
+            sampler: None,
+            texture: TextureDesc::Colour(Vec3::ONE),
+        }
+    }
+}
+
+impl SceneFile {
+    /// Reads a scene file, the format is chosen from the extension
+    pub fn from_path(path: &str) -> Result<Self, SceneFileError> {
+        Self::parse(&fs::read_to_string(path)?, SceneFormat::from_path(path)?)
+    }
+
+    /// Writes a scene file, the format is chosen from the extension
+    pub fn save(&self, path: &str) -> Result<(), SceneFileError> {
+        let contents = self.serialize(SceneFormat::from_path(path)?)?;
+        fs::write(path, contents)?;
+        Ok(())
+    }
+
+    fn parse(contents: &str, format: SceneFormat) -> Result<Self, SceneFileError> {
+        match format {
+            SceneFormat::Ron => ron::from_str(contents).map_err(|e| SceneFileError::Parse(e.to_string())),
+            // see `serialize`
+            SceneFormat::Toml => toml::from_str::<toml::Value>(contents)
+                .map_err(|e| e.to_string())
+                .and_then(|value| serde_json::to_value(value).map_err(|e| e.to_string()))
+                .and_then(|value| serde_json::from_value(value).map_err(|e| e.to_string()))
+                .map_err(SceneFileError::Parse),
+            SceneFormat::Json => serde_json::from_str(contents).map_err(|e| SceneFileError::Parse(e.to_string())),
+        }
+    }
+
+    fn serialize(&self, format: SceneFormat) -> Result<String, SceneFileError> {
+        match format {
+            SceneFormat::Ron => ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+                .map_err(|e| SceneFileError::Serialize(e.to_string())),
+            // toml can only read and write enum variants that hold a value, like `TextureDesc::Colour`, as inline
+            // tables, so they go through JSON's values instead, which turn them into ordinary tables either way
+            SceneFormat::Toml => serde_json::to_value(self)
+                .map_err(|e| e.to_string())
+                .and_then(|value| toml::Value::try_from(value).map_err(|e| e.to_string()))
+                .and_then(|value| toml::to_string_pretty(&value).map_err(|e| e.to_string()))
+                .map_err(SceneFileError::Serialize),
+            SceneFormat::Json => {
+                serde_json::to_string_pretty(self).map_err(|e| SceneFileError::Serialize(e.to_string()))
+            }
+        }
+    }
+
+    /// Describes every entity in the scene, using the renderer to turn texture and mesh ids back into paths,
+    /// and material ids back into materials
+    pub fn from_scene(scene: &Scene, renderer: &impl Renderer) -> Self {
+        let entities = scene
+            .query::<(
+                Option<&TransformComponent>,
+                Option<&SphereRenderComponent>,
+                Option<&PlaneRenderComponent>,
+                Option<&MeshRenderComponent>,
+                Option<&MaterialComponent>,
+                Option<&PointLightComponent>,
+                Option<&DirectionalLightComponent>,
+                Option<&SpotLightComponent>,
+                Option<&AreaLightComponent>,
+                Option<&CameraComponent>,
+                Option<&SkyBoxComponent>,
+            )>()
+            .iter()
+            .map(
+                |(
+                    _,
+                    (
+                        transform,
+                        sphere,
+                        plane,
+                        mesh,
+                        material,
+                        point_light,
+                        directional_light,
+                        spot_light,
+                        area_light,
+                        camera,
+                        sky_box,
+                    ),
+                )| EntityDesc {
+                    transform:         transform.copied(),
+                    sphere:            sphere.copied(),
+                    plane:             plane.map(|p| PlaneDesc {
+                        normal: p.normal,
+                        width:  p.width,
+                        height: p.height,
+                    }),
+                    mesh:              mesh.map(|m| MeshDesc {
+                        path: renderer.get_mesh_path(m.mesh.id()).unwrap_or_default().to_owned(),
+                    }),
+                    material:          material.map(|m| MaterialDesc::from_component(m, renderer)),
+                    point_light:       point_light.copied(),
+                    directional_light: directional_light.copied(),
+                    spot_light:        spot_light.copied(),
+                    area_light:        area_light.copied(),
+                    camera:            camera.copied(),
+                    sky_box:           sky_box.map(|s| SkyBoxDesc::from_component(s, renderer)),
+                },
+            )
+            .collect();
+
+        Self { entities }
+    }
+
+    /// Creates a scene from the descriptions, loading any textures and meshes they refer to
+    pub fn into_scene(self, renderer: &mut impl Renderer) -> Result<Scene, SceneFileError> {
+        let mut scene = Scene::empty();
+
+        for entity in self.entities {
+            let mut builder = hecs::EntityBuilder::new();
+
+            if let Some(transform) = entity.transform {
+                builder.add(transform);
+            }
+            if let Some(sphere) = entity.sphere {
+                builder.add(sphere);
+            }
+            if let Some(plane) = entity.plane {
+                builder.add(PlaneRenderComponent::new(plane.normal, plane.width, plane.height));
+            }
+            if let Some(mesh) = entity.mesh {
+                builder.add(MeshRenderComponent {
+                    mesh: renderer.get_mesh_by_path(&mesh.path)?,
+                });
+            }
+            if let Some(material) = entity.material {
+                builder.add(material.into_component(renderer)?);
+            }
+            if let Some(point_light) = entity.point_light {
+                builder.add(point_light);
+            }
+            if let Some(directional_light) = entity.directional_light {
+                builder.add(directional_light);
+            }
+            if let Some(spot_light) = entity.spot_light {
+                if spot_light.inner_angle > spot_light.outer_angle {
+                    return Err(SceneFileError::Parse(format!(
+                        "spot light's inner angle {} is wider than its outer angle {}",
+                        spot_light.inner_angle, spot_light.outer_angle
+                    )));
+                }
+                builder.add(spot_light);
+            }
+            if let Some(area_light) = entity.area_light {
+                builder.add(area_light);
+            }
+            if let Some(camera) = entity.camera {
+                builder.add(camera);
+            }
+            if let Some(sky_box) = entity.sky_box {
+                builder.add(sky_box.into_component(renderer)?);
+            }
+
+            scene.create_entity(builder.build());
+        }
+
+        // the renderers draw from the first camera with a transform, so there has to be one
+        let has_camera = scene
+            .query::<(&TransformComponent, &CameraComponent)>()
+            .iter()
+            .next()
+            .is_some();
+        if !has_camera {
+            return Err(SceneFileError::NoCamera);
+        }
+
+        Ok(scene)
+    }
+}
+
+impl SkyBoxDesc {
+    fn from_component(s: &SkyBoxComponent, renderer: &impl Renderer) -> Self {
+        let path = |texture: &Handle<Texture>| renderer.get_texture_path(texture.id()).unwrap_or_default().to_owned();
+        let sky = match &s.sky {
+            Sky::Colour(colour) => SkyDesc::Colour(*colour),
+            Sky::Equirect { texture } => SkyDesc::Equirect(path(texture)),
+            Sky::Cubemap { textures } => SkyDesc::Cubemap(textures.each_ref().map(path)),
+            Sky::Procedural {
+                sun_direction,
+                turbidity,
+            } => SkyDesc::Procedural {
+                sun_direction: *sun_direction,
+                turbidity:     *turbidity,
+            },
+        };
+
+        Self {
+            sky,
+            rotation: s.rotation,
+            intensity: s.intensity,
+        }
+    }
+
+    fn into_component(self, renderer: &mut impl Renderer) -> Result<SkyBoxComponent, SceneFileError> {
+        let sky = match self.sky {
+            SkyDesc::Colour(colour) => Sky::Colour(colour),
+            SkyDesc::Equirect(path) => Sky::Equirect {
+                texture: renderer.get_texture_by_path(&path)?,
+            },
+            SkyDesc::Cubemap(paths) => {
+                let [a, b, c, d, e, f] = paths.map(|path| renderer.get_texture_by_path(&path));
+                Sky::Cubemap {
+                    textures: [a?, b?, c?, d?, e?, f?],
+                }
+            }
+            SkyDesc::Procedural {
+                sun_direction,
+                turbidity,
+            } => Sky::Procedural {
+                sun_direction,
+                turbidity,
+            },
+        };
+
+        Ok(SkyBoxComponent {
+            sky,
+            rotation: self.rotation,
+            intensity: self.intensity,
+        })
+    }
+}
+
+impl MaterialDesc {
+    fn from_component(m: &MaterialComponent, renderer: &impl Renderer) -> Self {
+        let m = renderer.get_material_data(m.material.id()).copied().unwrap_or_default();
+        let path = renderer.get_texture_path(m.tex_id).unwrap_or_default();
+        let texture = match Texture::colour_from_path(path) {
+            Some(colour) => TextureDesc::Colour(colour),
+            None => TextureDesc::Path(path.to_owned()),
+        };
+        let map_path = |tex_id: Option<u32>| tex_id.and_then(|id| renderer.get_texture_path(id)).map(str::to_owned);
+        let sampler = renderer
+            .get_texture_sampler(m.tex_id)
+            .filter(|s| *s != TextureSampler::default());
+
+        Self {
+            tex_scale: m.tex_scale,
+            base_colour: m.base_colour,
+            metallic: m.metallic,
+            roughness: m.roughness,
+            emissive: EmissiveDesc::Colour(m.emissive),
+            ior: m.ior,
+            transmission: m.transmission,
+            absorption: m.absorption,
+            normal_map: map_path(m.normal_tex_id),
+            metallic_roughness_map: map_path(m.metallic_roughness_tex_id),
+            emissive_map: map_path(m.emissive_tex_id),
+            occlusion_map: map_path(m.occlusion_tex_id),
+            alpha_cutoff: m.alpha_cutoff,
+            sampler,
+            texture,
+            ..Default::default()
+        }
+    }
+
+    /// Loads the textures and adds the material, holding on to the textures until the material does
+    fn into_component(self, renderer: &mut impl Renderer) -> Result<MaterialComponent, SceneFileError> {
+        let texture = match &self.texture {
+            TextureDesc::Path(path) => renderer.get_texture_by_path(path)?,
+            TextureDesc::Colour(colour) => renderer.get_texture_by_colour(*colour),
+        };
+        let normal_map = self
+            .normal_map
+            .as_ref()
+            .map(|p| renderer.get_data_texture_by_path(p))
+            .transpose()?;
+        let metallic_roughness_map = self
+            .metallic_roughness_map
+            .as_ref()
+            .map(|p| renderer.get_data_texture_by_path(p))
+            .transpose()?;
+        let emissive_map = self
+            .emissive_map
+            .as_ref()
+            .map(|p| renderer.get_texture_by_path(p))
+            .transpose()?;
+        let occlusion_map = self
+            .occlusion_map
+            .as_ref()
+            .map(|p| renderer.get_data_texture_by_path(p))
+            .transpose()?;
+        let tex_id = texture.id();
+
+        let emissive = match self.emissive {
+            EmissiveDesc::Colour(colour) => colour,
+            EmissiveDesc::Legacy(strength) => Vec3::splat(strength),
+        };
+
+        let material = if self.is_legacy() {
+            let basic = LegacyMaterial::default();
+            let legacy = LegacyMaterial {
+                diffuse:      self.diffuse.unwrap_or(basic.diffuse),
+                shininess:    self.shininess.unwrap_or(basic.shininess),
+                reflectivity: self.reflectivity.unwrap_or(basic.reflectivity),
+                emissive:     0.0,
+            };
+
+            Material {
+                emissive,
+                ..legacy.to_pbr(tex_id, self.tex_scale)
+            }
+        } else {
+            Material {
+                tex_id,
+                tex_scale: self.tex_scale,
+                base_colour: self.base_colour,
+                metallic: self.metallic,
+                roughness: self.roughness,
+                emissive,
+                ior: self.ior,
+                transmission: self.transmission,
+                absorption: self.absorption,
+                ..Material::basic()
+            }
+        };
+
+        let material = Material {
+            normal_tex_id: normal_map.as_ref().map(Handle::id),
+            metallic_roughness_tex_id: metallic_roughness_map.as_ref().map(Handle::id),
+            emissive_tex_id: emissive_map.as_ref().map(Handle::id),
+            occlusion_tex_id: occlusion_map.as_ref().map(Handle::id),
+            alpha_cutoff: self.alpha_cutoff,
+            ..material
+        };
+
+        if let Some(sampler) = self.sampler {
+            for tex_id in material.texture_ids() {
+                renderer.set_texture_sampler(tex_id, sampler);
+            }
+        }
+        Ok(MaterialComponent {
+            material: renderer.get_material(material),
+        })
+    }
+
+    fn is_legacy(&self) -> bool {
+        self.ambient.is_some()
+            || self.diffuse.is_some()
+            || self.specular.is_some()
+            || self.shininess.is_some()
+            || self.reflectivity.is_some()
+            || matches!(self.emissive, EmissiveDesc::Legacy(_))
+    }
+}
+
+#[derive(Clone, Copy)]
+enum SceneFormat {
+    Ron,
+    Toml,
+    Json,
+}
+
+impl SceneFormat {
+    fn from_path(path: &str) -> Result<Self, SceneFileError> {
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+
+        match extension.as_str() {
+            "ron" => Ok(Self::Ron),
+            "toml" => Ok(Self::Toml),
+            "json" => Ok(Self::Json),
+            _ => Err(SceneFileError::UnsupportedFormat(extension)),
+        }
+    }
+}
+
+/// Error reading or writing a scene file
+#[derive(Debug)]
+pub enum SceneFileError {
+    Io(io::Error),
+    Parse(String),
+    Serialize(String),
+    UnsupportedFormat(String),
+    Mesh(MeshError),
+    Texture(TextureError),
+    NoCamera,
+}
+
+impl fmt::Display for SceneFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "couldn't access scene file: {}", e),
+            Self::Parse(e) => write!(f, "couldn't parse scene file: {}", e),
+            Self::Serialize(e) => write!(f, "couldn't serialize scene: {}", e),
+            Self::UnsupportedFormat(ext) => write!(f, "unsupported scene file extension \"{}\"", ext),
+            Self::Mesh(e) => write!(f, "couldn't load mesh: {}", e),
+            Self::Texture(e) => write!(f, "couldn't load texture: {}", e),
+            Self::NoCamera => write!(f, "scene has no camera, add an entity with a transform and a camera"),
+        }
+    }
+}
+
+impl Error for SceneFileError {}
+
+impl From<io::Error> for SceneFileError {
+    fn from(e: io::Error) -> Self { Self::Io(e) }
+}
+
+impl From<MeshError> for SceneFileError {
+    fn from(e: MeshError) -> Self { Self::Mesh(e) }
+}
+
+impl From<TextureError> for SceneFileError {
+    fn from(e: TextureError) -> Self { Self::Texture(e) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{renderer::CpuRenderer, vec3};
+
+    /// A tinted glass material, a procedural sky and a camera
+    const SCENE: &str = r#"{
+        "entities": [{
+            "material": {
+                "base_colour": [0.9, 0.8, 0.7],
+                "metallic": 0.25,
+                "roughness": 0.1,
+                "emissive": [1.0, 0.5, 0.0],
+                "ior": 1.33,
+                "transmission": 1.0,
+                "absorption": [0.4, 0.1, 0.05],
+                "texture": { "colour": [1.0, 0.0, 0.0] }
+            }
+        }, {
+            "sky_box": {
+                "sky": { "procedural": { "sun_direction": [0.0, 1.0, 0.0], "turbidity": 3.0 } },
+                "rotation": 0.5
+            }
+        }, {
+            "transform": { "position": [0.0, 1.5, -6.5] },
+            "camera": { "pitch": 0.0, "yaw": 0.0, "fov": 90.0 }
+        }]
+    }"#;
+
+    /// The material of the first entity that has one
+    fn material(scene: &Scene, renderer: &impl Renderer) -> Material {
+        let mut query = scene.query::<&MaterialComponent>();
+        let (_, m) = query.iter().next().expect("scene has a material");
+        *renderer.get_material_data(m.material.id()).unwrap()
+    }
+
+    fn material_desc(json: &str) -> MaterialDesc { serde_json::from_str(json).unwrap() }
+
+    #[test]
+    fn is_legacy() {
+        assert!(!material_desc("{}").is_legacy());
+        assert!(!material_desc(r#"{ "emissive": [1.0, 1.0, 1.0], "roughness": 0.2 }"#).is_legacy());
+
+        for legacy in [
+            r#"{ "ambient": 0.1 }"#,
+            r#"{ "diffuse": 0.5 }"#,
+            r#"{ "specular": 0.5 }"#,
+            r#"{ "shininess": 8.0 }"#,
+            r#"{ "reflectivity": 0.5 }"#,
+            r#"{ "emissive": 1.0 }"#,
+        ] {
+            assert!(material_desc(legacy).is_legacy(), "{}", legacy);
+        }
+    }
+
+    #[test]
+    fn legacy_materials_are_converted() {
+        // the old Phong parameters, with a single emissive strength
+        let legacy = r#"{
+            "entities": [{
+                "material": {
+                    "diffuse": 0.5,
+                    "specular": 0.3,
+                    "shininess": 16.0,
+                    "reflectivity": 0.2,
+                    "emissive": 2.0,
+                    "texture": { "colour": [1.0, 0.0, 0.0] }
+                }
+            }, {
+                "transform": {},
+                "camera": { "pitch": 0.0, "yaw": 0.0, "fov": 90.0 }
+            }]
+        }"#;
+        let mut renderer = CpuRenderer::new(1, 1);
+        let scene = serde_json::from_str::<SceneFile>(legacy)
+            .unwrap()
+            .into_scene(&mut renderer)
+            .unwrap();
+        let m = material(&scene, &renderer);
+
+        // reflectivity becomes metallic, and takes the base colour from the diffuse towards white. Shininess 16 is a
+        // Beckmann alpha of sqrt(2 / 18) = 1/3, so a roughness of sqrt(1/3) * (1 - 0.2)
+        assert_eq!(m.tex_id, renderer.get_texture_by_colour(vec3(1.0, 0.0, 0.0)).id());
+        assert!(m.base_colour.abs_diff_eq(Vec3::splat(0.6), 1e-5), "{}", m.base_colour);
+        assert!((m.metallic - 0.2).abs() < 1e-5, "{}", m.metallic);
+        assert!((m.roughness - 0.46188).abs() < 1e-5, "{}", m.roughness);
+        assert_eq!(m.emissive, Vec3::splat(2.0));
+        assert_eq!(m.ior, 1.5);
+        assert_eq!(m.transmission, 0.0);
+
+        // and it's saved in the new fields
+        let file = SceneFile::from_scene(&scene, &renderer);
+        assert!(!file.entities[0].material.as_ref().unwrap().is_legacy());
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut renderer = CpuRenderer::new(1, 1);
+        let scene = serde_json::from_str::<SceneFile>(SCENE)
+            .unwrap()
+            .into_scene(&mut renderer)
+            .unwrap();
+        let loaded = material(&scene, &renderer);
+
+        let file = SceneFile::from_scene(&scene, &renderer);
+        let desc = file.entities[0].material.as_ref().unwrap();
+        assert!(matches!(desc.texture, TextureDesc::Colour(colour) if colour == vec3(1.0, 0.0, 0.0)));
+
+        // saved in every format, it loads back as the same scene
+        let files = [SceneFormat::Ron, SceneFormat::Toml, SceneFormat::Json].map(|format| {
+            let contents = file.serialize(format).unwrap();
+            SceneFile::parse(&contents, format).unwrap()
+        });
+        for file in files {
+            let scene = file.into_scene(&mut renderer).unwrap();
+            assert_eq!(material(&scene, &renderer), loaded);
+
+            let mut query = scene.query::<&SkyBoxComponent>();
+            let (_, sky_box) = query.iter().next().expect("scene has a sky box");
+            assert!(matches!(
+                sky_box.sky,
+                Sky::Procedural { sun_direction, turbidity } if sun_direction == Vec3::Y && turbidity == 3.0
+            ));
+            assert_eq!(sky_box.rotation, 0.5);
+
+            let mut query = scene.query::<(&TransformComponent, &CameraComponent)>();
+            let (_, (transform, camera)) = query.iter().next().expect("scene has a camera");
+            assert_eq!(transform.position, vec3(0.0, 1.5, -6.5));
+            assert_eq!(camera.fov, 90.0);
+        }
+    }
+
+    #[test]
+    fn needs_a_camera() {
+        let mut renderer = CpuRenderer::new(1, 1);
+        let file = serde_json::from_str::<SceneFile>(r#"{ "entities": [{ "sphere": { "radius": 1.0 } }] }"#).unwrap();
+        assert!(matches!(file.into_scene(&mut renderer), Err(SceneFileError::NoCamera)));
+    }
+}
@@ -1,13 +1,14 @@
-use std::sync::Arc;
+use std::{error::Error, fmt, sync::Arc};
 
 use vulkano::{
-    command_buffer::BlitImageInfo,
+    buffer::{BufferUsage, CpuAccessibleBuffer},
+    command_buffer::{AutoCommandBufferBuilder, BlitImageInfo, CommandBufferUsage, CopyImageToBufferInfo},
     device::{
         physical::{PhysicalDevice, PhysicalDeviceType},
         Device, DeviceCreateInfo, DeviceExtensions, Features, Queue, QueueCreateInfo,
     },
     format::Format,
-    image::{view::ImageView, AttachmentImage, ImageAccess, ImageUsage, SwapchainImage},
+    image::{view::ImageView, AttachmentImage, ImageUsage, SwapchainImage},
     instance::{
         debug::{
             DebugUtilsMessageSeverity, DebugUtilsMessageType, DebugUtilsMessenger, DebugUtilsMessengerCreateInfo,
@@ -18,7 +19,7 @@ use vulkano::{
     pipeline::ComputePipeline,
     swapchain::{
         acquire_next_image, AcquireError, ColorSpace, PresentMode, Surface, SurfaceCapabilities, SurfaceInfo,
-        Swapchain, SwapchainCreateInfo,
+        Swapchain, SwapchainCreateInfo, SwapchainCreationError,
     },
     sync::{now, FlushError, GpuFuture, Sharing},
 };
@@ -47,7 +48,7 @@ pub struct VkBackend {
     pub device:                Arc<Device>,
     pub physical_device_index: usize,
     pub debug_callback:        Option<DebugUtilsMessenger>,
-    pub surface:               Arc<Surface<Window>>,
+    pub surface:               Option<Arc<Surface<Window>>>, // None when running headless
 
     pub queues:         Vec<Arc<Queue>>,
     pub graphics_queue: Arc<Queue>,
@@ -61,11 +62,17 @@ pub struct VkBackend {
 
     pub compute_context: Option<ComputeContext>,
 
+    pub(super) dimensions:   [u32; 2],
+    /// A size the swapchain couldn't be recreated at yet, like while the window is minimised
+    pub(super) pending_size: Option<[u32; 2]>,
     pub(super) frame_number: usize,
 }
 
 impl VkBackend {
-    pub fn new(event_loop: &EventLoop<()>, title: &str, width: u32, height: u32) -> Self {
+    /// `device_index` picks a specific physical device (as listed in the log), otherwise the best suitable one is used
+    pub fn new(
+        event_loop: &EventLoop<()>, title: &str, width: u32, height: u32, device_index: Option<usize>,
+    ) -> Result<Self, DeviceError> {
         // find out what extensions vulkano_win/winit requires
 
         let required_extensions = Self::get_required_instance_extensions(vulkano_win::required_extensions());
@@ -74,18 +81,19 @@ impl VkBackend {
 
         let surface = Self::create_surface(&instance, event_loop, title, width, height);
 
-        let device_extensions = Self::get_required_device_extensions();
+        let device_extensions = Self::get_required_device_extensions(true);
 
-        let physical_device_index = Self::pick_physical_device(&instance, device_extensions, &surface);
+        let physical_device_index =
+            Self::pick_physical_device(&instance, device_extensions, Some(&surface), device_index)?;
         let (device, queues) = Self::create_device(&instance, physical_device_index, device_extensions);
-        let (graphics_queue, present_queue, compute_queue, transfer_queue) = Self::get_queues(&queues, &surface);
+        let (graphics_queue, present_queue, compute_queue, transfer_queue) = Self::get_queues(&queues, Some(&surface));
 
         let mut this = Self {
             instance,
             device,
             physical_device_index,
             queues,
-            surface,
+            surface: Some(surface),
             debug_callback,
             graphics_queue,
             present_queue,
@@ -97,10 +105,49 @@ impl VkBackend {
             attachment_views: vec![],
 
             compute_context: None,
+            dimensions: [width, height],
+            pending_size: None,
             frame_number: 0,
         };
         this.create_swap_chain(width, height);
-        this
+        Ok(this)
+    }
+
+    /// Creates a backend with no window, surface or swapchain.
+    /// Frames are rendered into the frame image only, and can be fetched with `read_frame`.
+    pub fn new_headless(width: u32, height: u32, device_index: Option<usize>) -> Result<Self, DeviceError> {
+        // no window, so we don't need any of the surface extensions
+        let required_extensions = Self::get_required_instance_extensions(InstanceExtensions::none());
+        let instance = Self::create_instance(required_extensions);
+        let debug_callback = Self::setup_debug_callback(&instance);
+
+        let device_extensions = Self::get_required_device_extensions(false);
+
+        let physical_device_index = Self::pick_physical_device(&instance, device_extensions, None, device_index)?;
+        let (device, queues) = Self::create_device(&instance, physical_device_index, device_extensions);
+        let (graphics_queue, present_queue, compute_queue, transfer_queue) = Self::get_queues(&queues, None);
+
+        Ok(Self {
+            instance,
+            device,
+            physical_device_index,
+            queues,
+            surface: None,
+            debug_callback,
+            graphics_queue,
+            present_queue,
+            compute_queue,
+            transfer_queue,
+
+            swap_chain: None,
+            swap_chain_images: vec![],
+            attachment_views: vec![],
+
+            compute_context: None,
+            dimensions: [width, height],
+            pending_size: None,
+            frame_number: 0,
+        })
     }
 
     // ----------------------------------------------------------------------------------------------------------------------
@@ -116,10 +163,10 @@ impl VkBackend {
         .union(&window_extensions)
     }
 
-    /// Desired extensions for our device
-    const fn get_required_device_extensions() -> DeviceExtensions {
+    /// Desired extensions for our device. The swapchain extension is only needed if we present to a surface
+    const fn get_required_device_extensions(swapchain: bool) -> DeviceExtensions {
         DeviceExtensions {
-            khr_swapchain: true,
+            khr_swapchain: swapchain,
             ..DeviceExtensions::none()
         }
     }
@@ -136,7 +183,9 @@ impl VkBackend {
     }
 
     /// Decides if a given physical device has the right extensions and queues for us
-    fn is_device_suitable<W>(p: &PhysicalDevice, device_extensions: DeviceExtensions, surface: &Surface<W>) -> bool {
+    fn is_device_suitable(
+        p: &PhysicalDevice, device_extensions: DeviceExtensions, surface: Option<&Surface<Window>>,
+    ) -> bool {
         p.supported_extensions().is_superset_of(&device_extensions)
         &&
         // look for the right queue families 
@@ -144,7 +193,8 @@ impl VkBackend {
         // we look for at least one family that can do these
         p.queue_families().any(|q| q.supports_graphics()) &&
         p.queue_families().any(|q| q.supports_compute()) &&
-        p.queue_families().any(|q| q.supports_surface(surface).unwrap_or(false))
+        // a headless device never presents, so doesn't need a present queue
+        surface.is_none_or(|surface| p.queue_families().any(|q| q.supports_surface(surface).unwrap_or(false)))
     }
 
     /// Picks a colour format,and a colour space to use.
@@ -191,16 +241,20 @@ impl VkBackend {
 
     /// Gets the queues that we want from a list of queues, that was provided by the device.
     fn get_queues(
-        queues: &[Arc<Queue>], surface: &Surface<Window>,
+        queues: &[Arc<Queue>], surface: Option<&Surface<Window>>,
     ) -> (Arc<Queue>, Arc<Queue>, Arc<Queue>, Arc<Queue>) {
         let graphics_queue = queues
             .iter()
             .find(|q| q.family().supports_graphics())
             .expect("Cannot find graphics queue");
-        let present_queue = queues
-            .iter()
-            .find(|q| q.family().supports_surface(surface).unwrap_or(false))
-            .expect("Cannot find present queue");
+        // without a surface nothing is presented, so just use the graphics queue
+        let present_queue = match surface {
+            Some(surface) => queues
+                .iter()
+                .find(|q| q.family().supports_surface(surface).unwrap_or(false))
+                .expect("Cannot find present queue"),
+            None => graphics_queue,
+        };
         let compute_queue = queues
             .iter()
             .find(|q| q.family().supports_compute())
@@ -303,10 +357,26 @@ impl VkBackend {
             .expect("Couldn't build surface")
     }
 
-    /// Picks out a physical device that has the lowest score (best performing device), and is deemed "suitable"
-    fn pick_physical_device<W>(
-        instance: &Arc<Instance>, device_extensions: DeviceExtensions, surface: &Surface<W>,
-    ) -> usize {
+    /// Picks out a physical device that has the lowest score (best performing device), and is deemed "suitable".
+    /// If a device index is given, that device is used instead as long as it is suitable.
+    fn pick_physical_device(
+        instance: &Arc<Instance>, device_extensions: DeviceExtensions, surface: Option<&Surface<Window>>,
+        device_index: Option<usize>,
+    ) -> Result<usize, DeviceError> {
+        // list every device, so the user knows which index to ask for
+        let names = PhysicalDevice::enumerate(instance)
+            .map(|p| {
+                format!(
+                    "{} (type: {:?})",
+                    p.properties().device_name,
+                    p.properties().device_type
+                )
+            })
+            .collect::<Vec<_>>();
+        for (idx, name) in names.iter().enumerate() {
+            info!("Found device {}: {}", idx, name);
+        }
+
         let mut sorted_devices = PhysicalDevice::enumerate(instance).enumerate().collect::<Vec<_>>();
         sorted_devices.sort_by_key(|(_, p)| {
             // We assign a lower score to device types that are likely to be faster/better.
@@ -319,23 +389,37 @@ impl VkBackend {
             }
         });
 
-        if !sorted_devices[0]
-            .1
-            .supported_extensions()
-            .is_superset_of(&device_extensions)
-        {
-            let missing = device_extensions.difference(sorted_devices[0].1.supported_extensions());
+        let Some((_, ideal_device)) = sorted_devices.first() else {
+            return Err(DeviceError::NoneSuitable);
+        };
+        if !ideal_device.supported_extensions().is_superset_of(&device_extensions) {
+            let missing = device_extensions.difference(ideal_device.supported_extensions());
             warn!(
                 "Ideal device is missing extensions: {:?}\nTry updating your graphics drivers",
                 missing
             );
         }
 
-        // find the first device deemed "suitable"
-        let (physical_device_index, physical_device) = sorted_devices
-            .iter()
-            .find(|(_, device)| Self::is_device_suitable(device, device_extensions, surface))
-            .expect("failed to find a suitable GPU!");
+        let (physical_device_index, physical_device) = match device_index {
+            Some(device_index) => {
+                let Some(device) = sorted_devices.iter().find(|(idx, _)| *idx == device_index) else {
+                    return Err(DeviceError::NotFound {
+                        index:     device_index,
+                        available: names,
+                    });
+                };
+
+                if !Self::is_device_suitable(&device.1, device_extensions, surface) {
+                    return Err(DeviceError::Unsuitable(device_index));
+                }
+                device
+            }
+            // find the first device deemed "suitable"
+            None => sorted_devices
+                .iter()
+                .find(|(_, device)| Self::is_device_suitable(device, device_extensions, surface))
+                .ok_or(DeviceError::NoneSuitable)?,
+        };
 
         // debug info
         info!(
@@ -345,7 +429,7 @@ impl VkBackend {
             physical_device.properties().api_version,
         );
 
-        *physical_device_index
+        Ok(*physical_device_index)
     }
 
     /// From a physical device, create a logical device, that is our method of talking to the physical device
@@ -376,18 +460,22 @@ impl VkBackend {
     /// Creates a swap chain, which we will render to
     pub fn create_swap_chain(&mut self, width: u32, height: u32) {
         let physical_device = PhysicalDevice::from_index(&self.instance, self.physical_device_index).unwrap();
+        let surface = self
+            .surface
+            .as_ref()
+            .expect("Cannot create a swap chain without a surface");
 
         // Find out the capabilities of the device given this surface
         let capabilities = physical_device
-            .surface_capabilities(&self.surface, SurfaceInfo::default())
+            .surface_capabilities(surface, SurfaceInfo::default())
             .expect("failed to get surface capabilities");
 
         let available_formats = physical_device
-            .surface_formats(&self.surface, SurfaceInfo::default())
+            .surface_formats(surface, SurfaceInfo::default())
             .expect("Cannot get surface formats");
 
         let available_present_modes = physical_device
-            .surface_present_modes(&self.surface)
+            .surface_present_modes(surface)
             .expect("Cannot get surface present modes")
             .collect();
 
@@ -418,7 +506,7 @@ impl VkBackend {
 
         let (swap_chain, images) = Swapchain::new(
             self.device.clone(),
-            self.surface.clone(),
+            surface.clone(),
             SwapchainCreateInfo {
                 min_image_count: image_count,
                 image_format: Some(surface_format.0),
@@ -434,6 +522,7 @@ impl VkBackend {
         )
         .expect("failed to create swap chain!");
 
+        self.dimensions = extent;
         self.swap_chain = Some(swap_chain);
         self.swap_chain_images = images;
         // get image views to write to from swapchain
@@ -464,22 +553,10 @@ impl VkBackend {
             pipelines.push(pipeline);
         }
 
-        let dimensions = self.swap_chain_images[0].dimensions().width_height();
-
         // The Frame Data for each frame in flight
         let create_frame_data = |_| -> ComputeFrameData {
             // the framebuffer
-            let frame_image = AttachmentImage::with_usage(
-                self.device.clone(),
-                dimensions,
-                Format::R32G32B32A32_SFLOAT,
-                ImageUsage {
-                    storage: true,
-                    transfer_src: true,
-                    ..ImageUsage::none()
-                },
-            )
-            .unwrap();
+            let frame_image = self.create_frame_image();
 
             let previous_frame_end = Some(now(self.device.clone()).boxed());
 
@@ -498,12 +575,93 @@ impl VkBackend {
         })
     }
 
+    /// The image the compute shaders render into, which gets blitted to the swapchain
+    fn create_frame_image(&self) -> Arc<AttachmentImage> {
+        AttachmentImage::with_usage(
+            self.device.clone(),
+            self.dimensions,
+            Format::R32G32B32A32_SFLOAT,
+            ImageUsage {
+                storage: true,
+                transfer_src: true,
+                ..ImageUsage::none()
+            },
+        )
+        .unwrap()
+    }
+
+    /// Changes the size of the frames being rendered, recreating the swapchain if there is one, at the nearest size
+    /// the surface supports. Returns false if it can't be resized yet, like while the window is minimised, in which
+    /// case the size is kept in `pending_size` to try again with, and nothing should be drawn until it works
+    pub fn resize(&mut self, width: u32, height: u32) -> bool {
+        self.pending_size = Some([width, height]);
+        if width == 0 || height == 0 {
+            return false;
+        }
+
+        let mut extent = [width, height];
+        if let Some(swap_chain) = &self.swap_chain {
+            let physical_device = PhysicalDevice::from_index(&self.instance, self.physical_device_index).unwrap();
+            let capabilities = physical_device
+                .surface_capabilities(swap_chain.surface(), SurfaceInfo::default())
+                .expect("failed to get surface capabilities");
+            extent = Self::choose_swap_extent(&capabilities, width, height);
+            if extent.contains(&0) {
+                return false;
+            }
+
+            let (swap_chain, images) = match swap_chain.recreate(SwapchainCreateInfo {
+                image_extent: extent,
+                ..swap_chain.create_info()
+            }) {
+                Ok(recreated) => recreated,
+                // the window can change size again before the swapchain catches up
+                Err(
+                    e @ (SwapchainCreationError::ImageExtentNotSupported { .. }
+                    | SwapchainCreationError::ImageExtentZeroLengthDimensions),
+                ) => {
+                    debug!("Can't resize the swap chain yet: {}", e);
+                    return false;
+                }
+                Err(e) => panic!("failed to recreate swap chain: {}", e),
+            };
+
+            self.swap_chain = Some(swap_chain);
+            self.swap_chain_images = images;
+            self.attachment_views = self
+                .swap_chain_images
+                .iter()
+                .map(|image| ImageView::new_default(image.clone()).unwrap())
+                .collect::<Vec<_>>();
+        }
+        self.dimensions = extent;
+        self.pending_size = None;
+
+        // frames still in flight keep their old image alive until they finish
+        if let Some(mut context) = self.compute_context.take() {
+            for frame in &mut context.frame_data {
+                frame.frame_image = self.create_frame_image();
+            }
+            self.compute_context = Some(context);
+        }
+        true
+    }
+
+    /// The size the last `resize` couldn't change to, if it failed
+    pub fn pending_size(&self) -> Option<[u32; 2]> { self.pending_size }
+
     pub fn gen_buffer<T: BufferType>(&self, len: u64) -> Arc<Buffer<T>> {
         Arc::new(Buffer::new(self.device.clone(), self.frames_in_flight() + 1, len))
     }
 
     pub fn frames_in_flight(&self) -> usize { FRAMES_IN_FLIGHT }
 
+    /// The width and height of the frame being rendered
+    pub fn dimensions(&self) -> [u32; 2] { self.dimensions }
+
+    /// The window we are presenting to, if we aren't headless
+    pub fn window(&self) -> Option<&Window> { self.surface.as_ref().map(|surface| surface.window()) }
+
     pub(super) fn frame_image(&self) -> Arc<AttachmentImage> {
         let context = self.compute_context.as_ref().expect("Compute pipeline was not created");
 
@@ -513,6 +671,11 @@ impl VkBackend {
     pub fn compute_begin_submit(&mut self) -> ComputeSubmitBuilder { ComputeSubmitBuilder::new(self) }
 
     pub(super) fn compute_submit(&mut self, submit_builder: ComputeSubmitBuilder) {
+        if self.swap_chain.is_none() {
+            self.compute_submit_headless(submit_builder);
+            return;
+        }
+
         let context = self.compute_context.as_mut().expect("Compute pipeline was not created");
         let swap_chain = self.swap_chain.as_ref().expect("No swapchain");
         let frame = &mut context.frame_data[self.frame_number];
@@ -594,4 +757,118 @@ impl VkBackend {
         self.frame_number += 1;
         self.frame_number %= FRAMES_IN_FLIGHT;
     }
+
+    /// Submits the compute shaders without presenting, the result is left in the frame image
+    fn compute_submit_headless(&mut self, submit_builder: ComputeSubmitBuilder) {
+        let context = self.compute_context.as_mut().expect("Compute pipeline was not created");
+        let frame = &mut context.frame_data[self.frame_number];
+
+        frame.previous_frame_end.as_mut().unwrap().cleanup_finished();
+
+        // wait for the previous frame to end
+        let mut future = frame.previous_frame_end.take().unwrap();
+
+        // schedule execution of all command buffer
+        for builder in submit_builder.command_builders {
+            let command_buffer = builder.build().unwrap();
+            future = future
+                .then_execute(self.compute_queue.clone(), command_buffer)
+                .unwrap()
+                .boxed();
+        }
+
+        match future.then_signal_fence_and_flush() {
+            Ok(future) => {
+                frame.previous_frame_end = Some(future.boxed());
+            }
+            Err(e) => {
+                error!("Failed to flush future: {:?}", e);
+                frame.previous_frame_end = Some(vulkano::sync::now(self.device.clone()).boxed());
+            }
+        }
+
+        self.frame_number += 1;
+        self.frame_number %= FRAMES_IN_FLIGHT;
+    }
+
+    /// Copies the last submitted frame back to the CPU. Blocks until the GPU has finished rendering it.
+    /// Returns tightly packed RGBA pixels, in rows from top to bottom.
+    pub fn read_frame(&mut self) -> Vec<f32> {
+        let context = self.compute_context.as_mut().expect("Compute pipeline was not created");
+        // frame_number has already moved on to the next frame
+        let frame_number = self.frame_number.checked_sub(1).unwrap_or(FRAMES_IN_FLIGHT - 1);
+        let frame = &mut context.frame_data[frame_number];
+
+        let [width, height] = self.dimensions;
+        let buffer = CpuAccessibleBuffer::from_iter(
+            self.device.clone(),
+            BufferUsage {
+                transfer_dst: true,
+                ..BufferUsage::none()
+            },
+            false,
+            (0..width * height * 4).map(|_| 0.0f32),
+        )
+        .unwrap();
+
+        let mut builder = AutoCommandBufferBuilder::primary(
+            self.device.clone(),
+            self.compute_queue.family(),
+            CommandBufferUsage::OneTimeSubmit,
+        )
+        .unwrap();
+        builder
+            .copy_image_to_buffer(CopyImageToBufferInfo::image_buffer(
+                frame.frame_image.clone(),
+                buffer.clone(),
+            ))
+            .unwrap();
+        let command_buffer = builder.build().unwrap();
+
+        // the copy has to happen after the frame has finished rendering
+        let future = frame
+            .previous_frame_end
+            .take()
+            .unwrap()
+            .then_execute(self.compute_queue.clone(), command_buffer)
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .expect("Failed to flush frame readback");
+        future.wait(None).expect("Failed to wait for frame readback");
+        frame.previous_frame_end = Some(now(self.device.clone()).boxed());
+
+        let pixels = buffer.read().unwrap();
+        pixels.to_vec()
+    }
+}
+
+/// Error picking the physical device to render on
+#[derive(Debug)]
+pub enum DeviceError {
+    /// There's no device with the requested index, `available` lists the devices there are
+    NotFound {
+        index:     usize,
+        available: Vec<String>,
+    },
+    /// The requested device is missing extensions or queues we need
+    Unsuitable(usize),
+    NoneSuitable,
+}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound { index, available } => {
+                write!(f, "no device with index {}, the available devices are:", index)?;
+                for (idx, name) in available.iter().enumerate() {
+                    write!(f, "\n  {}: {}", idx, name)?;
+                }
+                Ok(())
+            }
+            Self::Unsuitable(index) => write!(f, "device {} is not suitable", index),
+            Self::NoneSuitable => write!(f, "couldn't find a suitable GPU"),
+        }
+    }
 }
+
+impl Error for DeviceError {}
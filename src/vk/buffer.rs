@@ -15,6 +15,9 @@ use super::HasDescriptor;
 
 pub trait BufferType: Send + Sync + Pod {}
 
+// plain arrays of floats, like the sky's cdf
+impl BufferType for f32 {}
+
 const USAGE: BufferUsage = BufferUsage {
     transfer_src: true,
     uniform_buffer: true,
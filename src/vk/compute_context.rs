@@ -2,7 +2,7 @@ use std::sync::Arc;
 
 use vulkano::{
     command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage, PrimaryAutoCommandBuffer},
-    image::{AttachmentImage, ImageAccess},
+    image::AttachmentImage,
     pipeline::{ComputePipeline, Pipeline, PipelineBindPoint},
     sync::GpuFuture,
 };
@@ -45,7 +45,7 @@ impl<'a> ComputeSubmitBuilder<'a> {
         let shader = &context.shaders[shader_idx];
         let pipeline = &context.pipelines[shader_idx];
 
-        let dimensions = self.backend.swap_chain_images[0].dimensions().width_height();
+        let dimensions = self.backend.dimensions();
         let layouts = pipeline.layout().set_layouts();
         let mut vk_sets = Vec::with_capacity(shader.sets.len());
 
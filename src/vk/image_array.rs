@@ -47,6 +47,9 @@ impl ImageArray {
         }
     }
 
+    /// Removes every image, so they can be pushed again at a different size
+    pub fn clear(&self) { self.images.write().unwrap().clear(); }
+
     //pub fn set_texture(&mut self, id: u32, ...)
 }
 
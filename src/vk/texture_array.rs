@@ -6,27 +6,38 @@ use std::{
 use log::debug;
 use vulkano::{
     descriptor_set::WriteDescriptorSet,
-    device::Queue,
+    device::{Device, Queue},
+    format::Format,
     image::{view::ImageView, ImageViewAbstract, ImmutableImage, MipmapsCount},
+    sampler::{Sampler, SamplerCreateInfo},
     sync::GpuFuture,
 };
 
 use super::{HasDescriptor, VkBackend};
 
+/// Textures with a sampler each, bound as an array of combined image samplers
 pub struct TextureArray {
+    device:   Arc<Device>,
     queue:    Arc<Queue>,
-    textures: RwLock<Vec<Arc<ImmutableImage>>>,
+    textures: RwLock<Vec<(Arc<ImmutableImage>, Arc<Sampler>)>>,
 }
 
 impl TextureArray {
     pub fn new(backend: Arc<RefCell<VkBackend>>) -> Self {
+        let backend = backend.borrow();
         Self {
-            queue:    backend.borrow().transfer_queue.clone(),
+            device:   backend.device.clone(),
+            // mips are made with blits, which need a graphics queue
+            queue:    backend.graphics_queue.clone(),
             textures: RwLock::new(vec![]),
         }
     }
 
-    pub fn push_texture(&self, width: u32, height: u32, data: Vec<f32>) {
+    /// Uploads a texture into slot idx, with a full chain of mips that are made from it on the GPU. Replaces the
+    /// texture already there, or adds one when idx is the number of textures. data is the texels as bytes, in format
+    pub fn set_texture(
+        &self, idx: usize, width: u32, height: u32, data: Vec<u8>, format: Format, sampler: SamplerCreateInfo,
+    ) {
         let (image, future) = ImmutableImage::from_iter(
             data,
             vulkano::image::ImageDimensions::Dim2d {
@@ -34,27 +45,41 @@ impl TextureArray {
                 height,
                 array_layers: 1,
             },
-            MipmapsCount::One,
-            vulkano::format::Format::R32G32B32A32_SFLOAT,
+            MipmapsCount::Log2,
+            format,
             self.queue.clone(),
         )
         .unwrap();
         debug!("Uploading a texture to GPU");
         future.flush().unwrap();
-        self.textures.write().unwrap().push(image);
+
+        let sampler = Sampler::new(self.device.clone(), sampler).unwrap();
+        let mut textures = self.textures.write().unwrap();
+        if idx == textures.len() {
+            textures.push((image, sampler));
+        } else {
+            textures[idx] = (image, sampler);
+        }
     }
 
-    //pub fn set_texture(&mut self, id: u32, ...)
+    /// Replaces the sampler of the texture at idx
+    pub fn set_sampler(&self, idx: usize, sampler: SamplerCreateInfo) {
+        let sampler = Sampler::new(self.device.clone(), sampler).unwrap();
+        self.textures.write().unwrap()[idx].1 = sampler;
+    }
 }
 
 impl HasDescriptor for TextureArray {
     fn get_descriptor(&self, binding: u32, _frame_number: usize) -> WriteDescriptorSet {
         let textures_reader = self.textures.read().unwrap();
-        let views = textures_reader
-            .iter()
-            .map(|image| ImageView::new_default(image.clone()).unwrap() as Arc<dyn ImageViewAbstract>);
+        let views = textures_reader.iter().map(|(image, sampler)| {
+            (
+                ImageView::new_default(image.clone()).unwrap() as Arc<dyn ImageViewAbstract>,
+                sampler.clone(),
+            )
+        });
 
-        WriteDescriptorSet::image_view_array(binding, 0, views)
+        WriteDescriptorSet::image_view_sampler_array(binding, 0, views)
     }
 
     fn is_variable(&self) -> bool { true }